@@ -0,0 +1,741 @@
+//!
+//! Address spaces
+//!
+//! Every process and kernel thread runs in an `AddressSpace`, a root page
+//! table of its own which shares the kernel's top-level entries. Address
+//! spaces are told apart in the TLB by an ASID, which is handed out lazily
+//! on `activate` by an `AsidAllocator`.
+//!
+//! ASIDs are never freed one by one. Once all of them are in use the
+//! allocator starts a new generation: every address space has to pick up a
+//! new ASID on its next activation, and every hart flushes its TLB before
+//! it runs with one of the recycled ASIDs
+//!
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::PAGE_SIZE_B;
+use crate::frame_table::{FrameTable, MAX_HARTS};
+use crate::page_table::aging::PageAging;
+use crate::page_table::virt_map::{self, CloneError, PageRegion};
+use crate::page_table::{MapError, PageTable, paging_mode};
+use crate::sync::SpinLock;
+use crate::tlb::{FlushScope, HartSet, TlbFlush};
+
+///
+/// The widest ASID `satp` can hold
+///
+pub const MAX_ASID_BITS: u32 = 16;
+
+const ASID_WORDS: usize = (1 << MAX_ASID_BITS) / 64;
+
+struct AsidState {
+    ///
+    /// Number of ASID bits the harts implement, `0` when they have none
+    ///
+    bits: u32,
+
+    generation: u64,
+
+    ///
+    /// ASIDs handed out in the current generation
+    ///
+    used: [u64; ASID_WORDS],
+}
+
+impl AsidState {
+    fn take_free(&mut self) -> Option<u16> {
+        let count = 1usize << self.bits;
+
+        let asid = (0..count.div_ceil(64))
+            .find(|&word| self.used[word] != u64::MAX)
+            .map(|word| word * 64 + self.used[word].trailing_ones() as usize)
+            .filter(|&asid| asid < count)?;
+
+        self.used[asid / 64] |= 1 << (asid % 64);
+
+        Some(asid as u16)
+    }
+
+    fn rollover(&mut self) {
+        self.generation += 1;
+        self.used = [0; ASID_WORDS];
+
+        // ASID 0 stays with the kernel page table
+        self.used[0] = 1;
+    }
+}
+
+///
+/// The ASID an address space was last given, tagged with its generation
+///
+/// `0` means it never had one
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AsidContext(u64);
+
+impl AsidContext {
+    fn generation(&self) -> u64 {
+        self.0 >> MAX_ASID_BITS
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.0 as u16
+    }
+}
+
+///
+/// What a hart has to do when it switches to an address space
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsidFlush {
+    ///
+    /// The ASID is current and this hart's TLB holds nothing stale for it
+    ///
+    None,
+
+    ///
+    /// The ASID was just handed out, entries of a previous owner may linger
+    ///
+    Asid,
+
+    ///
+    /// ASIDs were recycled, or aren't implemented at all
+    ///
+    All,
+}
+
+///
+/// Hands out ASIDs to address spaces, recycling them by generation
+///
+pub struct AsidAllocator {
+    state: SpinLock<AsidState>,
+
+    ///
+    /// Harts which have not flushed their TLB since the last rollover
+    ///
+    flush_pending: [AtomicBool; MAX_HARTS],
+}
+
+impl AsidAllocator {
+    pub const fn new() -> Self {
+        let mut used = [0; ASID_WORDS];
+        used[0] = 1;
+
+        Self {
+            state: SpinLock::new(AsidState {
+                bits: 0,
+                generation: 1,
+                used,
+            }),
+            flush_pending: [const { AtomicBool::new(false) }; MAX_HARTS],
+        }
+    }
+
+    ///
+    /// Set the number of ASID bits the harts implement, as probed from `satp`
+    ///
+    pub fn set_bits(&self, bits: u32) {
+        assert!(bits <= MAX_ASID_BITS, "satp holds at most 16 ASID bits");
+
+        let mut state = self.state.lock();
+        state.bits = bits;
+        state.rollover();
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.state.lock().bits
+    }
+
+    ///
+    /// Make sure `context` holds an ASID of the current generation before
+    /// it is used on `hart`
+    ///
+    /// Returns the TLB flush `hart` needs before running with it
+    ///
+    pub fn acquire(&self, context: &mut AsidContext, hart: usize) -> AsidFlush {
+        let mut state = self.state.lock();
+
+        if state.bits == 0 {
+            *context = AsidContext(0);
+            return AsidFlush::All;
+        }
+
+        let mut flush = AsidFlush::None;
+
+        if context.generation() != state.generation {
+            let asid = match state.take_free() {
+                Some(asid) => asid,
+                None => {
+                    state.rollover();
+
+                    for pending in &self.flush_pending {
+                        pending.store(true, Ordering::Relaxed);
+                    }
+
+                    state.take_free().expect("no ASID free after a rollover")
+                }
+            };
+
+            *context = AsidContext((state.generation << MAX_ASID_BITS) | asid as u64);
+            flush = AsidFlush::Asid;
+        }
+
+        // Harts past the flags have to assume a rollover happened
+        let pending = self
+            .flush_pending
+            .get(hart)
+            .is_none_or(|pending| pending.swap(false, Ordering::Relaxed));
+
+        if pending { AsidFlush::All } else { flush }
+    }
+}
+
+impl Default for AsidAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///
+/// A root page table sharing the kernel's top-level entries, with the ASID
+/// it runs under
+///
+pub struct AddressSpace {
+    pub page_table: PageTable,
+
+    root_phys_addr: usize,
+
+    ///
+    /// Root entries copied from the kernel, one bit per entry
+    ///
+    shared: [u64; 8],
+
+    asid: AsidContext,
+
+    ///
+    /// Harts which ran the address space, and may still hold translations
+    /// tagged with its ASID
+    ///
+    harts: HartSet,
+}
+
+impl AddressSpace {
+    ///
+    /// Create an empty address space
+    ///
+    /// Every root entry `kernel` uses is shared, like the self-ref section in
+    /// the upper half and the identity mapping of the kernel image. Kernel
+    /// mappings made later on in root entries which were free at this point
+    /// are not seen until `share_kernel_entries`
+    ///
+    /// # Safety
+    ///
+    /// `kernel` must be the kernel page table, and outlive the address space
+    ///
+    pub unsafe fn new(
+        frame_table: &mut FrameTable,
+        kernel: &mut PageTable,
+    ) -> Result<Self, MapError> {
+        // The root belongs in the self-ref section like any other table
+        let root = unsafe { kernel.meta_allocate_page_table(frame_table) }?;
+
+        let mut space = Self {
+            page_table: unsafe { PageTable::from_pointer(root.phys_addr) },
+            root_phys_addr: root.phys_addr,
+            shared: [0; 8],
+            asid: AsidContext::default(),
+            harts: HartSet::new(),
+        };
+
+        space.share_kernel_entries(kernel);
+
+        Ok(space)
+    }
+
+    ///
+    /// Copy the kernel's root entries which are in use, and not taken by
+    /// this address space's own mappings
+    ///
+    pub fn share_kernel_entries(&mut self, kernel: &PageTable) {
+        for (index, entry) in kernel.entries.iter().enumerate() {
+            let own = self.page_table.entries[index].is_valid() && !self.is_shared(index);
+
+            if entry.is_valid() && !own {
+                self.page_table.entries[index] = *entry;
+                self.shared[index / 64] |= 1 << (index % 64);
+            }
+        }
+    }
+
+    ///
+    /// Whether root entry `index` is the kernel's
+    ///
+    pub fn is_shared(&self, index: usize) -> bool {
+        self.shared[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub fn root_phys_addr(&self) -> usize {
+        self.root_phys_addr
+    }
+
+    ///
+    /// The ASID this address space ran under last, which may be out of date
+    ///
+    pub fn asid(&self) -> u16 {
+        self.asid.asid()
+    }
+
+    ///
+    /// The `satp` value running this address space
+    ///
+    pub fn satp(&self) -> usize {
+        paging_mode().satp(self.root_phys_addr, self.asid())
+    }
+
+    ///
+    /// A flush of this address space's translations, on every hart which ran
+    /// it
+    ///
+    /// Changes to its own mappings go through here, changes to the kernel
+    /// entries it shares take a `TlbFlush::kernel`
+    ///
+    pub fn tlb_flush(&self) -> TlbFlush {
+        TlbFlush::new(FlushScope::Asid(self.asid()), self.harts.bits())
+    }
+
+    ///
+    /// Switch `hart` over to this address space
+    ///
+    /// # Safety
+    ///
+    /// Must run on `hart`, and the code and stack in use must be mapped by the
+    /// kernel entries shared with this address space
+    ///
+    pub unsafe fn activate(&mut self, asids: &AsidAllocator, hart: usize) {
+        let flush = asids.acquire(&mut self.asid, hart);
+
+        self.harts.insert(hart);
+
+        write_satp(self.satp());
+
+        match flush {
+            AsidFlush::None => {}
+            AsidFlush::Asid => crate::page_table::sfence_vma_asid(self.asid()),
+            AsidFlush::All => crate::page_table::sfence_vma_all(),
+        }
+    }
+
+    ///
+    /// Duplicate the user pages of this address space into a new one,
+    /// copy-on-write
+    ///
+    /// The new address space shares the kernel entries like one made by
+    /// `new`, every other root entry is cloned with `virt_map::clone_cow`.
+    /// Nothing is left allocated when this fails
+    ///
+    /// # Safety
+    ///
+    /// `kernel` must be the table this address space was created from, and
+    /// the address space must not be written to on another hart meanwhile
+    ///
+    pub unsafe fn fork(
+        &mut self,
+        frame_table: &mut FrameTable,
+        kernel: &mut PageTable,
+    ) -> Result<Self, CloneError> {
+        let mut child = unsafe { Self::new(frame_table, kernel) }
+            .map_err(|error| CloneError::Root { error })?;
+
+        let mode = paging_mode();
+        let span = mode.root_size().pages();
+
+        // The parent's pages lose their write permission, on every hart
+        let mut tlb = self.tlb_flush();
+
+        for index in 0..512 {
+            if self.is_shared(index) || !self.page_table.entries[index].is_valid() {
+                continue;
+            }
+
+            // The kernel may have taken the entry since this address space
+            // was made, it stays private to the child as well
+            child.page_table.entries[index].clear();
+            child.shared[index / 64] &= !(1 << (index % 64));
+
+            let virt_addr = mode.canonical((index * span) << 12);
+            let cloned = unsafe {
+                virt_map::clone_cow(
+                    &mut self.page_table,
+                    &mut child.page_table,
+                    frame_table,
+                    virt_addr,
+                    span,
+                    &mut tlb,
+                )
+            };
+
+            if let Err(error) = cloned {
+                tlb.flush();
+
+                for region in unsafe { child.destroy(frame_table, kernel) } {
+                    for page in 0..region.count {
+                        frame_table
+                            .frame_unref(region.address + page * PAGE_SIZE_B)
+                            .expect("AddressSpace: failed to drop a shared frame");
+                    }
+                }
+
+                return Err(error);
+            }
+        }
+
+        tlb.flush();
+
+        Ok(child)
+    }
+
+    ///
+    /// Sample the accessed bits of this address space's user pages into
+    /// `aging`, see `PageAging::sample`
+    ///
+    /// Returns the number of 4KiB pages sampled. The pages are flushed from
+    /// every hart which ran the address space, accesses made afterwards are
+    /// seen by the next scan
+    ///
+    /// # Safety
+    ///
+    /// The address space must not be changed on another hart meanwhile
+    ///
+    pub unsafe fn sample_accessed(
+        &mut self,
+        frame_table: &mut FrameTable,
+        aging: &mut PageAging,
+    ) -> usize {
+        let mode = paging_mode();
+        let span = mode.root_size().pages();
+
+        let mut tlb = self.tlb_flush();
+        let mut sampled = 0;
+
+        for index in 0..512 {
+            if self.is_shared(index) || !self.page_table.entries[index].is_valid() {
+                continue;
+            }
+
+            let virt_addr = mode.canonical((index * span) << 12);
+            sampled += unsafe {
+                aging.sample(&mut self.page_table, frame_table, virt_addr, span, &mut tlb)
+            };
+        }
+
+        tlb.flush();
+
+        sampled
+    }
+
+    ///
+    /// Tear the address space down, freeing every page table it owns
+    ///
+    /// Returns the physical memory which was mapped outside of the kernel
+    /// entries, for the caller to free. Frames which may be shared with a fork
+    /// are let go of with `FrameTable::frame_unref`, which frees them along
+    /// with their last reference
+    ///
+    /// # Safety
+    ///
+    /// The address space must not be active on any hart, `kernel` must be
+    /// the table it was created from
+    ///
+    pub unsafe fn destroy(
+        mut self,
+        frame_table: &mut FrameTable,
+        kernel: &mut PageTable,
+    ) -> Vec<PageRegion> {
+        let mode = paging_mode();
+        let span = mode.root_size().pages();
+
+        let mut unmapped = Vec::new();
+
+        // The harts which ran it may still hold its translations, they must
+        // let go of them before the frames are handed out again
+        let mut tlb = self.tlb_flush();
+
+        for index in 0..512 {
+            if self.is_shared(index) || !self.page_table.entries[index].is_valid() {
+                continue;
+            }
+
+            let virt_addr = mode.canonical((index * span) << 12);
+
+            // Whole root entries never have a large page to split
+            unmapped.extend(
+                unsafe {
+                    virt_map::unmap(&mut self.page_table, frame_table, virt_addr, span, &mut tlb)
+                }
+                .expect("AddressSpace: failed to unmap a root entry"),
+            );
+        }
+
+        tlb.flush_freeing(frame_table);
+
+        unsafe { kernel.meta_free_page_table(frame_table, self.root_phys_addr) }
+            .expect("AddressSpace: failed to free the root table");
+
+        unmapped
+    }
+}
+
+fn write_satp(value: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("csrw satp, {}", in(reg) value)
+    };
+
+    #[cfg(not(target_arch = "riscv64"))]
+    let _ = value;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_table::FrameState;
+    use crate::page_table::fault::{FaultError, LazyRegions, PageFault, handle_page_fault};
+    use crate::page_table::virt_map::{virtual_map_linear, virtual_map_linear_with_flags};
+    use crate::page_table::{PageTableEntry, PageTableIndex, pt_virt_start};
+    use crate::phys::phys_to_ptr;
+    use crate::testing::{Arena, bootstrapped, walk};
+
+    const RAM_BASE: usize = 0x8000_0000;
+
+    #[test]
+    fn asids_roll_over_by_generation() {
+        let asids = AsidAllocator::new();
+        let mut spaces = [AsidContext::default(); 4];
+
+        // Without ASIDs every switch flushes everything
+        assert_eq!(asids.acquire(&mut spaces[0], 0), AsidFlush::All);
+        assert_eq!(spaces[0].asid(), 0);
+
+        asids.set_bits(2);
+
+        // ASIDs 1 to 3 are handed out, then reused while current
+        for (index, space) in spaces[..3].iter_mut().enumerate() {
+            assert_eq!(asids.acquire(space, 0), AsidFlush::Asid);
+            assert_eq!(space.asid() as usize, index + 1);
+        }
+        assert_eq!(asids.acquire(&mut spaces[1], 1), AsidFlush::None);
+
+        // The fourth address space starts a new generation
+        assert_eq!(asids.acquire(&mut spaces[3], 0), AsidFlush::All);
+        assert_eq!(spaces[3].asid(), 1);
+
+        // Every hart flushes once, the older address spaces get new ASIDs
+        assert_eq!(asids.acquire(&mut spaces[3], 1), AsidFlush::All);
+        assert_eq!(asids.acquire(&mut spaces[3], 1), AsidFlush::None);
+        assert_eq!(asids.acquire(&mut spaces[0], 1), AsidFlush::Asid);
+        assert_eq!(spaces[0].asid(), 2);
+
+        assert_eq!(asids.acquire(&mut spaces[0], MAX_HARTS), AsidFlush::All);
+    }
+
+    #[test]
+    fn address_spaces_share_the_kernel_half() {
+        let arena = Arena::at(RAM_BASE, 128);
+        let mut ft = arena.frame_table();
+
+        let (mut kernel, root) = bootstrapped(&mut ft);
+
+        // The kernel image, identity mapped
+        let image = PageRegion {
+            address: RAM_BASE,
+            count: 4,
+        };
+        unsafe { virtual_map_linear(&mut kernel, &mut ft, image, RAM_BASE) }.unwrap();
+
+        let tables = ft.count_frames(0, FrameState::PageTable);
+
+        let mut space = unsafe { AddressSpace::new(&mut ft, &mut kernel) }.unwrap();
+        let space_root = space.root_phys_addr();
+
+        assert!(space.is_shared(2) && space.is_shared(280));
+        assert_eq!(walk(space_root, RAM_BASE), walk(root, RAM_BASE));
+
+        // User mappings stay out of the kernel table
+        let user = PageRegion {
+            address: RAM_BASE + 64 * 4096,
+            count: 2,
+        };
+        unsafe { virtual_map_linear(&mut space.page_table, &mut ft, user.clone(), 0x1000) }
+            .unwrap();
+
+        assert!(walk(space_root, 0x1000).is_some());
+        assert_eq!(walk(root, 0x1000), None);
+
+        // The root and the user tables show up in the self-ref section
+        let window = pt_virt_start() + (space_root - ft.root_frame_address());
+        assert_eq!(kernel.translate(window), Some(space_root));
+
+        let asids = AsidAllocator::new();
+        asids.set_bits(16);
+        unsafe { space.activate(&asids, 0) };
+        assert_eq!(space.satp(), (8 << 60) | (1 << 44) | (space_root >> 12));
+
+        let unmapped = unsafe { space.destroy(&mut ft, &mut kernel) };
+
+        assert_eq!(unmapped, std::vec![user]);
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+        assert_eq!(walk(root, RAM_BASE), Some((RAM_BASE, 0xF)));
+    }
+
+    #[test]
+    fn forks_share_user_pages_copy_on_write() {
+        let arena = Arena::at(RAM_BASE, 128);
+        let mut ft = arena.frame_table();
+
+        let (mut kernel, _) = bootstrapped(&mut ft);
+
+        let tables = ft.count_frames(0, FrameState::PageTable);
+
+        let mut parent = unsafe { AddressSpace::new(&mut ft, &mut kernel) }.unwrap();
+
+        let data = ft.alloc_front(2, FrameState::User, 7).unwrap();
+        unsafe { data.as_slice::<u64>()[0] = 0xC0FFEE };
+
+        let text = ft.alloc_front(1, FrameState::User, 7).unwrap();
+
+        let rw = PageTableEntry::FLAG_V
+            | PageTableEntry::FLAG_R
+            | PageTableEntry::FLAG_W
+            | PageTableEntry::FLAG_U;
+        let rx = PageTableEntry::FLAG_V
+            | PageTableEntry::FLAG_R
+            | PageTableEntry::FLAG_X
+            | PageTableEntry::FLAG_U;
+
+        let map = |space: &mut AddressSpace, ft: &mut FrameTable, address, count, virt, flags| {
+            let region = PageRegion { address, count };
+            unsafe {
+                virtual_map_linear_with_flags(&mut space.page_table, ft, region, virt, flags)
+            }
+            .unwrap();
+        };
+        map(&mut parent, &mut ft, data.phys_addr, 2, 0x10000, rw);
+        map(&mut parent, &mut ft, text.phys_addr, 1, 0x1000, rx);
+
+        // An empty table the parent set up ahead of use
+        let mut upper =
+            unsafe { PageTable::from_pointer(parent.page_table.entries[0].phys_addr()) };
+        let spare =
+            unsafe { upper.allocate_intermediary(PageTableIndex::new(5), &mut ft) }.unwrap();
+        let before = ft.count_frames(0, FrameState::PageTable);
+
+        let mut child = unsafe { parent.fork(&mut ft, &mut kernel) }.unwrap();
+
+        // Forking leaves the parent's tables alone, the child gets a root and
+        // the two tables its pages need
+        assert_eq!(
+            upper.entries[5].phys_addr(),
+            spare.physical_starting_memory_address
+        );
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), before + 3);
+        let (parent_root, child_root) = (parent.root_phys_addr(), child.root_phys_addr());
+
+        // Both copies of the data lose their write permission, the text is
+        // shared as it is
+        let cow = (rw & !PageTableEntry::FLAG_W) | PageTableEntry::FLAG_COW;
+        for root in [parent_root, child_root] {
+            assert_eq!(walk(root, 0x10000), Some((data.phys_addr, cow)));
+            assert_eq!(walk(root, 0x1000), Some((text.phys_addr, rx)));
+        }
+        assert_eq!(ft.ref_count(data.phys_addr), Some(2));
+        assert_eq!(ft.ref_count(text.phys_addr), Some(2));
+
+        let regions = LazyRegions::new();
+        let mut tlb = child.tlb_flush();
+        let written = rw | PageTableEntry::FLAG_A | PageTableEntry::FLAG_D;
+
+        // The child stores first and gets a copy
+        unsafe {
+            handle_page_fault(
+                &mut child.page_table,
+                &mut ft,
+                &regions,
+                0x10008,
+                PageFault::Store,
+                &mut tlb,
+            )
+        }
+        .unwrap();
+
+        let (copy, flags) = walk(child_root, 0x10000).unwrap();
+        assert_ne!(copy, data.phys_addr);
+        assert_eq!(flags, written);
+        assert_eq!(unsafe { *phys_to_ptr::<u64>(copy) }, 0xC0FFEE);
+        assert_eq!(ft.ref_count(data.phys_addr), Some(1));
+        assert_eq!(ft.count_frames(7, FrameState::User), 4);
+        tlb.flush();
+
+        // The parent is left as the only user of the frame and takes it over
+        unsafe {
+            handle_page_fault(
+                &mut parent.page_table,
+                &mut ft,
+                &regions,
+                0x10000,
+                PageFault::Store,
+                &mut tlb,
+            )
+        }
+        .unwrap();
+        assert_eq!(walk(parent_root, 0x10000), Some((data.phys_addr, written)));
+        assert_eq!(ft.count_frames(7, FrameState::User), 4);
+
+        // Loads, and stores to pages which were never writable, are not
+        // copy-on-write faults
+        assert_eq!(
+            unsafe {
+                handle_page_fault(
+                    &mut child.page_table,
+                    &mut ft,
+                    &regions,
+                    0x11000,
+                    PageFault::Load,
+                    &mut tlb,
+                )
+            },
+            Ok(())
+        );
+        assert_eq!(
+            unsafe {
+                handle_page_fault(
+                    &mut child.page_table,
+                    &mut ft,
+                    &regions,
+                    0x1000,
+                    PageFault::Store,
+                    &mut tlb,
+                )
+            },
+            Err(FaultError::AccessDenied {
+                addr: 0x1000,
+                fault: PageFault::Store
+            })
+        );
+
+        tlb.flush();
+
+        // Dropping both address spaces hands back every frame
+        for space in [parent, child] {
+            for region in unsafe { space.destroy(&mut ft, &mut kernel) } {
+                for page in 0..region.count {
+                    ft.frame_unref(region.address + page * 4096).unwrap();
+                }
+            }
+        }
+
+        assert_eq!(ft.count_frames(7, FrameState::User), 0);
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+    }
+}
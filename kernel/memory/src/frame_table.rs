@@ -1,7 +1,19 @@
+mod accounting;
+mod buddy;
+mod global;
+mod refcount;
+mod reserve;
+mod stats;
+
 use alloc::vec::Vec;
-use core::ops::Add;
 
 use crate::PAGE_SIZE_B;
+use crate::phys::phys_to_ptr;
+
+pub use buddy::{MAX_ORDER, ORDER_COUNT};
+pub use global::{GlobalFrameTable, HART_CACHE_SIZE, MAX_HARTS};
+pub use refcount::RefCountError;
+pub use stats::{FrameCounts, FrameTableStats, SegmentStats};
 
 ///
 /// This data structure tracks all frames
@@ -26,6 +38,14 @@ pub struct FrameSegment {
     ///
     pub frame_metadata_start_addr: usize,
 
+    ///
+    /// The address at which the per-frame reference
+    /// counts start at
+    ///
+    /// Each reference count is 2 bytes
+    ///
+    pub frame_refcount_start_addr: usize,
+
     ///
     /// The address of the first page
     ///
@@ -35,6 +55,13 @@ pub struct FrameSegment {
     /// The number of pages
     ///
     pub page_count: usize,
+
+    ///
+    /// Buddy free lists, one per block order
+    ///
+    /// these live in the bookkeeping area right after the reference counts
+    ///
+    free_lists: [buddy::BitTree; ORDER_COUNT],
 }
 
 impl FrameTable {
@@ -82,23 +109,174 @@ impl FrameTable {
 
         None
     }
+
+    ///
+    /// Tries to allocate `count` contiguous pages from any segment, with the
+    /// first page aligned to `align` bytes
+    ///
+    /// Used for megapage (2MiB) and gigapage (1GiB) backing, and for naturally
+    /// aligned DMA buffers
+    ///
+    pub fn alloc_aligned(
+        &mut self,
+        count: usize,
+        align: usize,
+        state: FrameState,
+        pid: u16,
+    ) -> Option<MemoryAllocation> {
+        self.alloc_aligned_below(count, align, usize::MAX, state, pid)
+    }
+
+    ///
+    /// Like `alloc_aligned`, but the whole allocation must end at or below the
+    /// physical address `limit`
+    ///
+    /// Devices which can only address 32 bits pass a limit of `1 << 32`
+    ///
+    pub fn alloc_aligned_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: usize,
+        state: FrameState,
+        pid: u16,
+    ) -> Option<MemoryAllocation> {
+        for segment in &mut self.segments {
+            if segment.first_page_addr >= limit {
+                continue;
+            }
+
+            if let Some(alloc) = segment.alloc_aligned(count, align, limit, state, pid) {
+                return Some(alloc);
+            }
+        }
+
+        None
+    }
+
+    ///
+    /// Return an allocation to the frame table
+    ///
+    /// All frames of the allocation are reset to `FrameState::Free`,
+    /// nothing is modified if any frame fails validation
+    ///
+    pub fn free(&mut self, allocation: MemoryAllocation) -> Result<(), FreeError> {
+        self.free_addr(allocation.phys_addr, allocation.page_count, allocation.pid)
+    }
+
+    ///
+    /// Free `count` frames starting at the physical address `phys_addr`
+    ///
+    /// `pid` must match the owner recorded for every frame
+    ///
+    pub fn free_addr(&mut self, phys_addr: usize, count: usize, pid: u16) -> Result<(), FreeError> {
+        if phys_addr & (PAGE_SIZE_B - 1) != 0 {
+            return Err(FreeError::Misaligned { addr: phys_addr });
+        }
+
+        let segment = self
+            .segment_of_mut(phys_addr)
+            .ok_or(FreeError::NotInTable { addr: phys_addr })?;
+
+        let index = segment.index_of(phys_addr);
+
+        segment.free(index, count, pid)
+    }
+
+    ///
+    /// Find the segment which tracks the frame at `phys_addr`
+    ///
+    pub fn segment_of(&self, phys_addr: usize) -> Option<&FrameSegment> {
+        self.segments.iter().find(|s| s.contains(phys_addr))
+    }
+
+    ///
+    /// Find the segment which tracks the frame at `phys_addr`
+    ///
+    pub fn segment_of_mut(&mut self, phys_addr: usize) -> Option<&mut FrameSegment> {
+        self.segments.iter_mut().find(|s| s.contains(phys_addr))
+    }
 }
 impl FrameSegment {
     pub unsafe fn get_page(&self, idx: usize) -> &'static mut [u8; 4096] {
         assert!(idx < self.page_count, "get_page: index out of bounds");
-        unsafe { &mut *(self.first_page_addr.add(idx * 4096) as *mut [u8; 4096]) }
+        unsafe { &mut *phys_to_ptr::<[u8; 4096]>(self.first_page_addr + idx * 4096) }
     }
 
     pub unsafe fn get_metadata(&self, idx: usize) -> &'static mut FrameMetadataEntry {
         assert!(idx < self.page_count, "get_metadata: index out of bounds");
         unsafe {
-            &mut *(self
-                .frame_metadata_start_addr
-                .add(idx * core::mem::size_of::<FrameMetadataEntry>())
-                as *mut FrameMetadataEntry)
+            &mut *phys_to_ptr::<FrameMetadataEntry>(
+                self.frame_metadata_start_addr
+                    + idx * core::mem::size_of::<FrameMetadataEntry>(),
+            )
         }
     }
 
+    ///
+    /// Does this segment track the frame at `phys_addr`
+    ///
+    pub fn contains(&self, phys_addr: usize) -> bool {
+        phys_addr >= self.first_page_addr
+            && phys_addr < self.first_page_addr + self.page_count * PAGE_SIZE_B
+    }
+
+    ///
+    /// Convert a physical address within this segment into a frame index
+    ///
+    pub fn index_of(&self, phys_addr: usize) -> usize {
+        assert!(self.contains(phys_addr), "index_of: address not in segment");
+        (phys_addr - self.first_page_addr) / PAGE_SIZE_B
+    }
+
+    ///
+    /// Release `count` frames starting at frame `idx` back to this segment
+    ///
+    /// Every frame is validated before any metadata is touched, so a failed
+    /// free leaves the segment unchanged
+    ///
+    pub fn free(&mut self, idx: usize, count: usize, pid: u16) -> Result<(), FreeError> {
+        if idx + count > self.page_count {
+            return Err(FreeError::NotInTable {
+                addr: self.first_page_addr + self.page_count * PAGE_SIZE_B,
+            });
+        }
+
+        for offset in 0..count {
+            let addr = self.first_page_addr + (idx + offset) * PAGE_SIZE_B;
+            let meta = unsafe { self.get_metadata(idx + offset) };
+
+            match meta.state() {
+                FrameState::Free => return Err(FreeError::DoubleFree { addr }),
+                FrameState::Reserved => return Err(FreeError::Reserved { addr }),
+                _ => {}
+            }
+
+            let refs = *unsafe { self.get_refcount(idx + offset) };
+            if refs > 1 {
+                return Err(FreeError::Shared { addr, refs });
+            }
+
+            if meta.pid() != pid {
+                return Err(FreeError::PidMismatch {
+                    addr,
+                    expected: pid,
+                    found: meta.pid(),
+                });
+            }
+        }
+
+        for offset in 0..count {
+            let meta = unsafe { self.get_metadata(idx + offset) };
+            *meta = FrameMetadataEntry::new();
+            *unsafe { self.get_refcount(idx + offset) } = 0;
+        }
+
+        self.release_range(idx, count);
+
+        Ok(())
+    }
+
     pub unsafe fn iter_front(&self) -> impl Iterator<Item = usize> {
         (0..self.page_count)
             .map(move |index| (index, unsafe { self.get_metadata(index) }))
@@ -110,98 +288,274 @@ impl FrameSegment {
     /// Initialize a frame segment from a continuous memory region.
     /// Returns `None` if the region is too small to contain both metadata and at least one page.
     ///
-pub fn initialize(start_address: usize, size: usize) -> Option<FrameSegment> {
-    const PAGE_SIZE: usize = 4096;
-    const METADATA_SIZE_PER_PAGE: usize = core::mem::size_of::<FrameMetadataEntry>();
+    /// The start of the region is used for bookkeeping (frame metadata and the
+    /// buddy free lists), every remaining page starts out free.
+    ///
+    /// # Safety
+    ///
+    /// The region must be unused, writable memory, it is owned by the segment afterwards
+    ///
+    pub unsafe fn initialize(start_address: usize, size: usize) -> Option<FrameSegment> {
+        const PAGE_SIZE: usize = 4096;
+
+        if size < PAGE_SIZE {
+            return None;
+        }
+
+        // Max number of pages if metadata were "free"
+        let max_possible_pages = size / (PAGE_SIZE + METADATA_SIZE_PER_PAGE);
+
+        if max_possible_pages == 0 {
+            return None;
+        }
+
+        // Compute the start address of usable pages
+        let mut first_page_addr = start_address + Self::bookkeeping_size(max_possible_pages);
+        first_page_addr = align_up(first_page_addr, PAGE_SIZE);
 
-    if size < PAGE_SIZE {
-        return None;
+        // Calculate usable bytes after metadata
+        let total_used = first_page_addr - start_address;
+        if total_used >= size {
+            return None;
+        }
+
+        let usable_bytes = size - total_used;
+        let usable_page_count = (usable_bytes / PAGE_SIZE).min(max_possible_pages);
+
+        if usable_page_count == 0 {
+            return None;
+        }
+
+        Some(unsafe { Self::initialize_at(first_page_addr, usable_page_count, start_address) })
     }
 
-    // Max number of pages if metadata were "free"
-    let max_possible_pages = size / (PAGE_SIZE + METADATA_SIZE_PER_PAGE);
+    ///
+    /// Bytes of bookkeeping (metadata, reference counts and free lists)
+    /// needed by a segment of `page_count` frames, rounded up to whole pages
+    ///
+    pub fn bookkeeping_size(page_count: usize) -> usize {
+        let layout = BookkeepingLayout::new(page_count);
+        align_up(layout.size, PAGE_SIZE_B)
+    }
 
-    if max_possible_pages == 0 {
-        return None;
+    ///
+    /// Initialize a frame segment of `page_count` frames starting at `first_page_addr`,
+    /// keeping its bookkeeping at `bookkeeping_addr`
+    ///
+    /// The bookkeeping may live inside the segment itself, in which case
+    /// those frames are marked as `Kernel`. Every other frame starts out free.
+    ///
+    /// # Safety
+    ///
+    /// `bookkeeping_size(page_count)` bytes at `bookkeeping_addr` must be unused,
+    /// writable and page aligned, and they are owned by the segment afterwards
+    ///
+    pub unsafe fn initialize_at(
+        first_page_addr: usize,
+        page_count: usize,
+        bookkeeping_addr: usize,
+    ) -> FrameSegment {
+        assert!(
+            first_page_addr & (PAGE_SIZE_B - 1) == 0,
+            "first_page_addr must be page-aligned"
+        );
+        assert!(
+            bookkeeping_addr & (PAGE_SIZE_B - 1) == 0,
+            "bookkeeping must be page-aligned"
+        );
+        assert!(page_count > 0, "frame segments can not be empty");
+
+        let layout = BookkeepingLayout::new(page_count);
+        let bookkeeping_size = Self::bookkeeping_size(page_count);
+
+        // All frames start out free, which is an all-zero metadata entry
+        unsafe { core::ptr::write_bytes(phys_to_ptr::<u8>(bookkeeping_addr), 0, bookkeeping_size) };
+
+        let free_lists = unsafe {
+            buddy::layout_free_lists(
+                bookkeeping_addr + layout.free_lists_offset,
+                first_page_addr >> 12,
+                page_count,
+            )
+        };
+
+        let mut segment = FrameSegment {
+            frame_metadata_start_addr: bookkeeping_addr,
+            frame_refcount_start_addr: bookkeeping_addr + layout.refcount_offset,
+            first_page_addr,
+            page_count,
+            free_lists,
+        };
+
+        segment.release_range(0, page_count);
+        segment.reserve_range(bookkeeping_addr, bookkeeping_size, FrameState::Kernel, 0);
+
+        segment
     }
 
-    // Compute raw metadata bytes needed
-    let raw_metadata_bytes = max_possible_pages * METADATA_SIZE_PER_PAGE;
+    ///
+    /// Find the first run of `count` free frames by scanning the metadata
+    ///
+    /// This is the reference the buddy allocator is tested against
+    ///
+    /// Returns the index of the first frame of the run
+    ///
+    #[cfg(test)]
+    pub(crate) fn scan_front(&self, count: usize) -> Option<usize> {
+        self.scan_aligned(count, 1, usize::MAX)
+    }
+
+    ///
+    /// Find the first run of `count` free frames whose first frame number is a
+    /// multiple of `align_pages` and at most `max_start_pfn`
+    ///
+    /// Every frame is looked at once at most, a run is restarted at the next
+    /// aligned frame after one which isn't free
+    ///
+    /// Returns the index of the first frame of the run
+    ///
+    pub(crate) fn scan_aligned(
+        &self,
+        count: usize,
+        align_pages: usize,
+        max_start_pfn: usize,
+    ) -> Option<usize> {
+        if count == 0 || count > self.page_count {
+            return None;
+        }
+
+        let first_pfn = self.first_page_addr >> 12;
+        let aligned = |idx: usize| align_up(first_pfn + idx, align_pages) - first_pfn;
 
-    // Align metadata size up to page boundary
-    let aligned_metadata_bytes = align_up(raw_metadata_bytes, PAGE_SIZE);
+        let mut start = aligned(0);
+        let mut idx = start;
 
-    // Compute the start address of usable pages
-    let mut first_page_addr = start_address + aligned_metadata_bytes;
-    first_page_addr = align_up(first_page_addr, PAGE_SIZE);
+        while start + count <= self.page_count && first_pfn + start <= max_start_pfn {
+            if unsafe { self.get_metadata(idx) }.state() != FrameState::Free {
+                start = aligned(idx + 1);
+                idx = start;
+                continue;
+            }
 
-    // Calculate usable bytes after metadata
-    let total_used = first_page_addr - start_address;
-    if total_used >= size {
-        return None;
+            idx += 1;
+
+            if idx - start == count {
+                return Some(start);
+            }
+        }
+
+        None
     }
 
-    let usable_bytes = size - total_used;
-    let usable_page_count = usable_bytes / PAGE_SIZE;
+    ///
+    /// Find the last run of `count` free frames by scanning the metadata
+    ///
+    /// Every frame is looked at once at most
+    ///
+    /// Returns the index of the first frame of the run
+    ///
+    pub(crate) fn scan_back(&self, count: usize) -> Option<usize> {
+        if count == 0 || count > self.page_count {
+            return None;
+        }
+
+        let mut run = 0;
+
+        for idx in (0..self.page_count).rev() {
+            if unsafe { self.get_metadata(idx) }.state() != FrameState::Free {
+                run = 0;
+                continue;
+            }
+
+            run += 1;
 
-    if usable_page_count == 0 {
-        return None;
+            if run == count {
+                return Some(idx);
+            }
+        }
+
+        None
     }
 
-    assert!(
-        first_page_addr % PAGE_SIZE == 0,
-        "first_page_addr must be page-aligned"
-    );
+    ///
+    /// Mark `count` frames starting at `idx` as allocated
+    ///
+    fn mark_allocated(
+        &mut self,
+        idx: usize,
+        count: usize,
+        state: FrameState,
+        pid: u16,
+    ) -> MemoryAllocation {
+        for offset in 0..count {
+            let meta = unsafe { self.get_metadata(idx + offset) };
+            meta.set_state(state);
+            meta.set_pid(pid);
+            *unsafe { self.get_refcount(idx + offset) } = 1;
+        }
 
-    Some(FrameSegment {
-        frame_metadata_start_addr: start_address,
-        first_page_addr,
-        page_count: usable_page_count,
-    })
-}
+        MemoryAllocation {
+            phys_addr: self.first_page_addr + idx * PAGE_SIZE_B,
+            page_count: count,
+            pid,
+            state,
+        }
+    }
 
     /// Allocates `count` contiguous 4K pages from this segment.
     /// Returns a `MemoryAllocation` with metadata set, or `None` if no space.
+    ///
+    /// The pages come from the lowest free buddy block which holds them, so
+    /// they start on a multiple of `count` rounded up to a power of two. A
+    /// lower run which isn't aligned is only used when no block fits.
     pub fn alloc_front(
         &mut self,
         count: usize,
         state: FrameState,
         pid: u16,
     ) -> Option<MemoryAllocation> {
-        let end_idx = self.page_count.saturating_sub(count);
-
-        for idx in 0..=end_idx {
-            // Check if the range [idx, idx + count) is all free
-            let mut all_free = true;
-            for offset in 0..count {
-                let meta = unsafe { self.get_metadata(idx + offset) };
-                if meta.state() != FrameState::Free {
-                    all_free = false;
-                    break;
-                }
-            }
+        if count == 0 || count > self.page_count {
+            return None;
+        }
 
-            if all_free {
-                for offset in 0..count {
-                    let meta = unsafe { self.get_metadata(idx + offset) };
-                    meta.set_state(state);
-                    meta.set_pid(pid);
-                }
-
-                return Some(MemoryAllocation {
-                    phys_addr: self.first_page_addr + idx * 4096,
-                    page_count: count,
-                    pid,
-                    state,
-                });
-            }
+        let idx = self.buddy_take_front(count)?;
+
+        Some(self.mark_allocated(idx, count, state, pid))
+    }
+
+    ///
+    /// Allocates `count` contiguous 4K pages with the first page aligned to `align`
+    /// bytes, and the whole run ending at or below the physical address `limit`
+    ///
+    pub fn alloc_aligned(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: usize,
+        state: FrameState,
+        pid: u16,
+    ) -> Option<MemoryAllocation> {
+        assert!(
+            align.is_power_of_two(),
+            "alloc_aligned: alignment must be a power of two"
+        );
+
+        if count == 0 || count > self.page_count {
+            return None;
         }
 
-        None
+        let align_order = (align.max(PAGE_SIZE_B) / PAGE_SIZE_B).trailing_zeros() as usize;
+        let idx = self.buddy_take_aligned(count, align_order, limit >> 12)?;
+
+        Some(self.mark_allocated(idx, count, state, pid))
     }
 
     /// Allocates `count` contiguous 4K pages from the end (high address side) of this segment.
     /// Returns a `MemoryAllocation` with metadata set, or `None` if no space.
+    ///
+    /// The pages come from the top of the highest free buddy block which holds
+    /// them, so they end on a multiple of `count` rounded up to a power of
+    /// two. A higher run which isn't aligned is only used when no block fits.
     pub fn alloc_back(
         &mut self,
         count: usize,
@@ -212,42 +566,9 @@ pub fn initialize(start_address: usize, size: usize) -> Option<FrameSegment> {
             return None;
         }
 
-        // Start from the end and look backwards for a free run
-        let mut idx = self.page_count;
-
-        while idx >= count {
-            let start = idx - count;
-            let mut all_free = true;
-
-            for offset in 0..count {
-                let meta = unsafe { self.get_metadata(start + offset) };
-                if meta.state() != FrameState::Free {
-                    all_free = false;
-                    break;
-                }
-            }
-
-            if all_free {
-                for offset in 0..count {
-                    let meta = unsafe { self.get_metadata(start + offset) };
-                    meta.set_state(state);
-                    meta.set_pid(pid);
-                }
+        let idx = self.buddy_take_back(count)?;
 
-                let phys_addr = self.first_page_addr + start * 4096;
-
-                return Some(MemoryAllocation {
-                    phys_addr,
-                    page_count: count,
-                    pid,
-                    state,
-                });
-            }
-
-            idx -= 1;
-        }
-
-        None
+        Some(self.mark_allocated(idx, count, state, pid))
     }
 }
 
@@ -317,6 +638,20 @@ pub enum FrameState {
     Reserved = 5,
 }
 
+impl FrameState {
+    ///
+    /// Every frame state, in discriminant order
+    ///
+    pub const ALL: [FrameState; 6] = [
+        FrameState::Free,
+        FrameState::Used,
+        FrameState::Kernel,
+        FrameState::User,
+        FrameState::PageTable,
+        FrameState::Reserved,
+    ];
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FrameMetadataEntry {
@@ -324,6 +659,34 @@ pub struct FrameMetadataEntry {
 }
 
 impl FrameMetadataEntry {
+    ///
+    /// The frame must never be mapped, even by the kernel
+    ///
+    /// Set on firmware regions marked `no-map` in the device tree,
+    /// these can not be released back to the frame table
+    ///
+    pub const FLAG_NO_MAP: u8 = 1 << 0;
+
+    ///
+    /// A page backed by the frame was accessed since the last aging scan
+    ///
+    /// Set by `PageAging::sample`, and cleared again once the scan is done
+    ///
+    pub const FLAG_REFERENCED: u8 = 1 << 1;
+
+    ///
+    /// The frame is on the active list of page aging
+    ///
+    pub const FLAG_ACTIVE: u8 = 1 << 2;
+
+    ///
+    /// The frame was claimed by `FrameTable::reserve_region`
+    ///
+    /// Only these are handed back by `release_region`, frames allocated in
+    /// the same range later on stay with their owner
+    ///
+    pub const FLAG_RESERVED_REGION: u8 = 1 << 3;
+
     pub fn new() -> Self {
         Self { raw: 0 }
     }
@@ -361,6 +724,33 @@ impl FrameMetadataEntry {
     }
 }
 
+const METADATA_SIZE_PER_PAGE: usize =
+    core::mem::size_of::<FrameMetadataEntry>() + core::mem::size_of::<u16>();
+
+///
+/// Where each bookkeeping array lives, relative to the start of the bookkeeping
+///
+/// the metadata comes first, followed by the reference counts, then the free lists
+///
+struct BookkeepingLayout {
+    refcount_offset: usize,
+    free_lists_offset: usize,
+    size: usize,
+}
+
+impl BookkeepingLayout {
+    fn new(page_count: usize) -> Self {
+        let refcount_offset = page_count * core::mem::size_of::<FrameMetadataEntry>();
+        let free_lists_offset = align_up(page_count * METADATA_SIZE_PER_PAGE, 8);
+
+        Self {
+            refcount_offset,
+            free_lists_offset,
+            size: free_lists_offset + buddy::bookkeeping_bytes(page_count),
+        }
+    }
+}
+
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
@@ -389,7 +779,7 @@ impl MemoryAllocation {
     /// and writable by the kernel.
     pub unsafe fn zero(&self) {
         for i in 0..self.page_count {
-            let page_ptr = (self.phys_addr + i * 4096) as *mut u8;
+            let page_ptr = phys_to_ptr::<u8>(self.phys_addr + i * 4096);
             unsafe { core::ptr::write_bytes(page_ptr, 0, 4096) };
         }
     }
@@ -405,12 +795,60 @@ impl MemoryAllocation {
     /// - Memory must be initialized to `T`-compatible values
     /// - Caller must ensure correct lifetime management
     pub unsafe fn as_slice<T>(&self) -> &'static mut [T] {
-        let ptr = self.phys_addr as *mut T;
+        let ptr = phys_to_ptr::<T>(self.phys_addr);
         let count = self.page_count * crate::PAGE_SIZE_B / core::mem::size_of::<T>();
         unsafe { core::slice::from_raw_parts_mut(ptr, count) }
     }
 }
 
+///
+/// Reasons a free request was rejected by the frame table
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeError {
+    ///
+    /// The address is not tracked by any frame segment
+    ///
+    NotInTable { addr: usize },
+
+    ///
+    /// The address is not aligned to a page boundary
+    ///
+    Misaligned { addr: usize },
+
+    ///
+    /// The frame is already free
+    ///
+    DoubleFree { addr: usize },
+
+    ///
+    /// The frame is reserved and can never be freed
+    ///
+    Reserved { addr: usize },
+
+    ///
+    /// The frame was never handed out as the freed allocation, it holds
+    /// something else
+    ///
+    NotAllocated { addr: usize, state: FrameState },
+
+    ///
+    /// The frame is owned by a different process
+    ///
+    PidMismatch {
+        addr: usize,
+        expected: u16,
+        found: u16,
+    },
+
+    ///
+    /// The frame is still referenced from elsewhere
+    ///
+    /// shared frames are released by dropping references instead
+    ///
+    Shared { addr: usize, refs: u16 },
+}
+
 impl core::fmt::Debug for MemoryAllocation {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let size_kib = self.page_count * 4; // 4 KiB per page
@@ -463,3 +901,39 @@ impl<'a> Iterator for FrameTableFrontIterator<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Arena;
+
+    #[test]
+    fn simulated_ram_is_reached_through_the_hook() {
+        const RAM_BASE: usize = 0x8000_0000;
+
+        let arena = Arena::at(RAM_BASE, 128);
+        let mut ft = arena.frame_table();
+
+        let first = ft.root_frame_address();
+        assert!(first > RAM_BASE && first < RAM_BASE + 128 * PAGE_SIZE_B);
+
+        let single = ft.alloc_front(1, FrameState::User, 3).unwrap();
+        assert_eq!(single.phys_addr, first);
+
+        let page = ft.alloc_front(2, FrameState::User, 3).unwrap();
+        assert!(page.phys_addr > first);
+
+        let words = unsafe { page.as_slice::<u64>() };
+        words.fill(u64::MAX);
+        unsafe { page.zero() };
+        assert!(words.iter().all(|&w| w == 0));
+
+        let back = ft.alloc_back(1, FrameState::Kernel, 0).unwrap();
+        assert_eq!(back.phys_addr, RAM_BASE + 127 * PAGE_SIZE_B);
+
+        ft.free(single).unwrap();
+        ft.free(page).unwrap();
+        ft.free(back).unwrap();
+        assert_eq!(ft.stats().totals.get(FrameState::Free), ft.segments[0].page_count);
+    }
+}
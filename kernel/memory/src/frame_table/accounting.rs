@@ -0,0 +1,155 @@
+//!
+//! Per-process frame accounting
+//!
+//! Every allocated frame records the pid that owns it, these helpers
+//! answer ownership questions by walking the frame metadata, and let
+//! process teardown hand back everything a process owned at once
+//!
+
+use super::{FrameSegment, FrameState, FrameTable};
+use crate::PAGE_SIZE_B;
+
+impl FrameTable {
+    ///
+    /// Count the frames owned by `pid` which are in `state`
+    ///
+    pub fn count_frames(&self, pid: u16, state: FrameState) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.count_frames(pid, state))
+            .sum()
+    }
+
+    ///
+    /// Iterate over the physical address and state of every frame owned by `pid`
+    ///
+    pub fn frames_owned_by(&self, pid: u16) -> impl Iterator<Item = (usize, FrameState)> + '_ {
+        self.segments
+            .iter()
+            .flat_map(move |segment| segment.frames_owned_by(pid))
+    }
+
+    ///
+    /// Free every frame owned by `pid`, returning how many frames were freed
+    ///
+    /// Reserved frames are never released, and frames still shared with
+    /// another address space only lose a reference. The kernel (pid 0) can
+    /// not be reclaimed this way, as free frames are also recorded under pid 0
+    ///
+    pub fn reclaim_pid(&mut self, pid: u16) -> usize {
+        assert_ne!(
+            pid, 0,
+            "reclaim_pid: the kernel's frames can not be reclaimed"
+        );
+
+        self.segments
+            .iter_mut()
+            .map(|segment| segment.reclaim_pid(pid))
+            .sum()
+    }
+}
+
+impl FrameSegment {
+    fn is_owned_by(&self, idx: usize, pid: u16) -> bool {
+        let meta = unsafe { self.get_metadata(idx) };
+
+        meta.pid() == pid && !matches!(meta.state(), FrameState::Free | FrameState::Reserved)
+    }
+
+    pub fn count_frames(&self, pid: u16, state: FrameState) -> usize {
+        (0..self.page_count)
+            .filter(|&idx| {
+                let meta = unsafe { self.get_metadata(idx) };
+                meta.pid() == pid && meta.state() == state
+            })
+            .count()
+    }
+
+    pub fn frames_owned_by(&self, pid: u16) -> impl Iterator<Item = (usize, FrameState)> + '_ {
+        (0..self.page_count)
+            .filter(move |&idx| self.is_owned_by(idx, pid))
+            .map(move |idx| {
+                let state = unsafe { self.get_metadata(idx) }.state();
+                (self.first_page_addr + idx * PAGE_SIZE_B, state)
+            })
+    }
+
+    fn is_shared(&self, idx: usize) -> bool {
+        *unsafe { self.get_refcount(idx) } > 1
+    }
+
+    ///
+    /// Free every frame owned by `pid`, one contiguous run at a time
+    ///
+    pub fn reclaim_pid(&mut self, pid: u16) -> usize {
+        let mut reclaimed = 0;
+        let mut idx = 0;
+
+        while idx < self.page_count {
+            if !self.is_owned_by(idx, pid) {
+                idx += 1;
+                continue;
+            }
+
+            if self.is_shared(idx) {
+                self.frame_unref(idx)
+                    .expect("reclaim_pid: shared frame failed to unref");
+                idx += 1;
+                continue;
+            }
+
+            let run = (idx..self.page_count)
+                .take_while(|&i| self.is_owned_by(i, pid) && !self.is_shared(i))
+                .count();
+
+            self.free(idx, run, pid)
+                .expect("reclaim_pid: owned frames failed to free");
+
+            reclaimed += run;
+            idx += run;
+        }
+
+        reclaimed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Arena;
+    use std::vec::Vec;
+
+    #[test]
+    fn counts_and_reclaims_by_pid() {
+        let arena = Arena::new(300);
+        let mut ft = FrameTable {
+            segments: std::vec![arena.segment(0, 150), arena.segment(150, 150)],
+        };
+
+        let kernel = ft.alloc_front(4, FrameState::Kernel, 0).unwrap();
+        ft.alloc_front(3, FrameState::User, 7).unwrap();
+        ft.alloc_back(2, FrameState::PageTable, 7).unwrap();
+        ft.alloc_front(5, FrameState::User, 9).unwrap();
+        // Force an allocation into the second segment
+        let free_in_first = ft.segments[0].page_count - 14;
+        ft.alloc_front(free_in_first, FrameState::Used, 1).unwrap();
+        ft.alloc_front(6, FrameState::User, 7).unwrap();
+
+        assert_eq!(ft.count_frames(7, FrameState::User), 9);
+        assert_eq!(ft.count_frames(7, FrameState::PageTable), 2);
+        assert_eq!(ft.count_frames(9, FrameState::User), 5);
+
+        let owned: Vec<_> = ft.frames_owned_by(7).collect();
+        assert_eq!(owned.len(), 11);
+        assert!(owned.iter().all(|(addr, _)| ft.segment_of(*addr).is_some()));
+
+        assert_eq!(ft.reclaim_pid(7), 11);
+        assert_eq!(ft.frames_owned_by(7).count(), 0);
+        assert_eq!(ft.count_frames(9, FrameState::User), 5);
+        assert_eq!(ft.count_frames(0, FrameState::Kernel), kernel.page_count);
+
+        for segment in &ft.segments {
+            segment.assert_free_lists_consistent();
+        }
+    }
+}
@@ -0,0 +1,767 @@
+//!
+//! Buddy bookkeeping for frame segments
+//!
+//! Free frames are grouped into naturally aligned power-of-two blocks, a block
+//! of order `k` spans `2^k` frames and its physical frame number is a multiple
+//! of `2^k`. Each order has its own free list, stored as a bitmap of block
+//! slots with summary levels on top so the lowest and highest free block of an
+//! order can be found in O(log n).
+//!
+//! The frame metadata remains the source of truth for state and ownership,
+//! the free lists only index which frames are free.
+//!
+
+use super::FrameSegment;
+use crate::phys::phys_to_ptr;
+
+///
+/// The largest block order, 2^18 frames is 1GiB
+///
+pub const MAX_ORDER: usize = 18;
+
+///
+/// The number of free lists kept per segment
+///
+pub const ORDER_COUNT: usize = MAX_ORDER + 1;
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+///
+/// Enough summary levels for 64^6 slots
+///
+const MAX_LEVELS: usize = 6;
+
+///
+/// A bitmap with summary levels
+///
+/// Level 0 holds one bit per slot, every level above holds one bit per
+/// word of the level below, which is set when that word is non-zero.
+/// The top level is always a single word.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct BitTree {
+    ///
+    /// Physical address of the first word, so the tree stays usable when
+    /// the way physical memory is reached changes
+    ///
+    base: usize,
+    bits: usize,
+}
+
+impl BitTree {
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            bits: 0,
+        }
+    }
+
+    ///
+    /// The number of words (across all levels) needed to hold `bits` slots
+    ///
+    pub fn words_required(bits: usize) -> usize {
+        let mut words = bits.div_ceil(WORD_BITS).max(1);
+        let mut total = words;
+
+        while words > 1 {
+            words = words.div_ceil(WORD_BITS);
+            total += words;
+        }
+
+        total
+    }
+
+    ///
+    /// Create a tree over the words at physical address `base`
+    ///
+    /// # Safety
+    ///
+    /// `base` must hold `words_required(bits)` zeroed, writable words
+    /// which stay valid for as long as the tree is used
+    ///
+    pub unsafe fn new(base: usize, bits: usize) -> Self {
+        Self { base, bits }
+    }
+
+    ///
+    /// Word offsets of each level, and the number of levels
+    ///
+    fn levels(&self) -> ([usize; MAX_LEVELS], usize) {
+        let mut offsets = [0; MAX_LEVELS];
+        let mut words = self.bits.div_ceil(WORD_BITS).max(1);
+        let mut count = 1;
+
+        while words > 1 {
+            offsets[count] = offsets[count - 1] + words;
+            words = words.div_ceil(WORD_BITS);
+            count += 1;
+        }
+
+        (offsets, count)
+    }
+
+    fn word(&self, offset: usize) -> *mut u64 {
+        phys_to_ptr(self.base + offset * 8)
+    }
+
+    pub fn get(&self, idx: usize) -> bool {
+        if idx >= self.bits {
+            return false;
+        }
+
+        unsafe { *self.word(idx / WORD_BITS) & (1 << (idx % WORD_BITS)) != 0 }
+    }
+
+    pub fn set(&mut self, idx: usize) {
+        assert!(idx < self.bits, "BitTree::set: index out of bounds");
+
+        let (offsets, count) = self.levels();
+        let mut idx = idx;
+
+        for offset in offsets.iter().take(count) {
+            let word = self.word(offset + idx / WORD_BITS);
+            let was = unsafe { *word };
+
+            unsafe { *word = was | (1 << (idx % WORD_BITS)) };
+
+            if was != 0 {
+                break;
+            }
+
+            idx /= WORD_BITS;
+        }
+    }
+
+    pub fn clear(&mut self, idx: usize) {
+        assert!(idx < self.bits, "BitTree::clear: index out of bounds");
+
+        let (offsets, count) = self.levels();
+        let mut idx = idx;
+
+        for offset in offsets.iter().take(count) {
+            let word = self.word(offset + idx / WORD_BITS);
+            let now = unsafe { *word } & !(1 << (idx % WORD_BITS));
+
+            unsafe { *word = now };
+
+            if now != 0 {
+                break;
+            }
+
+            idx /= WORD_BITS;
+        }
+    }
+
+    ///
+    /// The lowest set slot
+    ///
+    pub fn first(&self) -> Option<usize> {
+        if self.bits == 0 {
+            return None;
+        }
+
+        let (offsets, count) = self.levels();
+        let mut idx = 0;
+
+        for level in (0..count).rev() {
+            let word = unsafe { *self.word(offsets[level] + idx) };
+
+            if word == 0 {
+                return None;
+            }
+
+            idx = idx * WORD_BITS + word.trailing_zeros() as usize;
+        }
+
+        Some(idx)
+    }
+
+    ///
+    /// The highest set slot
+    ///
+    pub fn last(&self) -> Option<usize> {
+        if self.bits == 0 {
+            return None;
+        }
+
+        let (offsets, count) = self.levels();
+        let mut idx = 0;
+
+        for level in (0..count).rev() {
+            let word = unsafe { *self.word(offsets[level] + idx) };
+
+            if word == 0 {
+                return None;
+            }
+
+            idx = idx * WORD_BITS + (WORD_BITS - 1 - word.leading_zeros() as usize);
+        }
+
+        Some(idx)
+    }
+}
+
+///
+/// The number of block slots of `order` for a run of frames
+///
+fn slot_count(first_pfn: usize, page_count: usize, order: usize) -> usize {
+    ((first_pfn + page_count - 1) >> order) - (first_pfn >> order) + 1
+}
+
+///
+/// Bytes of free list storage needed by a segment of `page_count` frames,
+/// regardless of where the segment starts
+///
+pub fn bookkeeping_bytes(page_count: usize) -> usize {
+    (0..ORDER_COUNT)
+        .map(|order| BitTree::words_required((page_count >> order) + 2))
+        .sum::<usize>()
+        * core::mem::size_of::<u64>()
+}
+
+///
+/// Lay out the free lists of a segment at `base`
+///
+/// # Safety
+///
+/// `base` must point to `bookkeeping_bytes(page_count)` zeroed, writable
+/// bytes aligned to 8 bytes
+///
+pub unsafe fn layout_free_lists(
+    base: usize,
+    first_pfn: usize,
+    page_count: usize,
+) -> [BitTree; ORDER_COUNT] {
+    let mut trees = [BitTree::empty(); ORDER_COUNT];
+    let mut cursor = base;
+
+    for (order, tree) in trees.iter_mut().enumerate() {
+        let bits = slot_count(first_pfn, page_count, order);
+
+        *tree = unsafe { BitTree::new(cursor, bits) };
+        cursor += BitTree::words_required(bits) * 8;
+    }
+
+    trees
+}
+
+fn ceil_order(count: usize) -> usize {
+    count.next_power_of_two().trailing_zeros() as usize
+}
+
+impl FrameSegment {
+    fn first_pfn(&self) -> usize {
+        self.first_page_addr >> 12
+    }
+
+    fn end_pfn(&self) -> usize {
+        self.first_pfn() + self.page_count
+    }
+
+    fn slot(&self, pfn: usize, order: usize) -> usize {
+        (pfn >> order) - (self.first_pfn() >> order)
+    }
+
+    ///
+    /// Does the whole block lie within this segment
+    ///
+    fn block_in_segment(&self, pfn: usize, order: usize) -> bool {
+        pfn >= self.first_pfn() && pfn + (1 << order) <= self.end_pfn()
+    }
+
+    fn is_free_block(&self, pfn: usize, order: usize) -> bool {
+        self.block_in_segment(pfn, order) && self.free_lists[order].get(self.slot(pfn, order))
+    }
+
+    fn push_block(&mut self, pfn: usize, order: usize) {
+        let slot = self.slot(pfn, order);
+        self.free_lists[order].set(slot);
+    }
+
+    fn pop_block(&mut self, pfn: usize, order: usize) {
+        let slot = self.slot(pfn, order);
+        self.free_lists[order].clear(slot);
+    }
+
+    ///
+    /// Put a free block back, merging it with its buddy for as long as possible
+    ///
+    fn release_block(&mut self, pfn: usize, order: usize) {
+        let mut pfn = pfn;
+        let mut order = order;
+
+        while order < MAX_ORDER {
+            let buddy = pfn ^ (1 << order);
+
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+
+            self.pop_block(buddy, order);
+            pfn = pfn.min(buddy);
+            order += 1;
+        }
+
+        self.push_block(pfn, order);
+    }
+
+    ///
+    /// Add the frames `start..end` (as frame numbers) to the free lists
+    ///
+    fn release_pfns(&mut self, start: usize, end: usize) {
+        let mut pfn = start;
+
+        while pfn < end {
+            let mut order = (pfn.trailing_zeros() as usize).min(MAX_ORDER);
+
+            while pfn + (1 << order) > end {
+                order -= 1;
+            }
+
+            self.release_block(pfn, order);
+            pfn += 1 << order;
+        }
+    }
+
+    ///
+    /// Add the frames at indexes `idx..idx + count` to the free lists
+    ///
+    pub(crate) fn release_range(&mut self, idx: usize, count: usize) {
+        let start = self.first_pfn() + idx;
+        self.release_pfns(start, start + count);
+    }
+
+    ///
+    /// Find the free block holding `pfn`
+    ///
+    fn block_containing(&self, pfn: usize) -> Option<(usize, usize)> {
+        (0..ORDER_COUNT)
+            .map(|order| (pfn & !((1 << order) - 1), order))
+            .find(|&(head, order)| self.is_free_block(head, order))
+    }
+
+    ///
+    /// Remove the frames at indexes `idx..idx + count` from the free lists
+    ///
+    /// Every frame in the range must currently be free
+    ///
+    pub(crate) fn claim_range(&mut self, idx: usize, count: usize) {
+        let start = self.first_pfn() + idx;
+        let end = start + count;
+        let mut pfn = start;
+
+        while pfn < end {
+            let (head, order) = self
+                .block_containing(pfn)
+                .expect("claim_range: frame is not on a free list");
+            let block_end = head + (1 << order);
+
+            self.pop_block(head, order);
+            self.release_pfns(head, start.max(head));
+            self.release_pfns(end.min(block_end), block_end);
+
+            pfn = block_end;
+        }
+    }
+
+    ///
+    /// Take the lowest addressed free block of at least `order`,
+    /// as long as it starts at or below `max_start`
+    ///
+    fn take_block_front(&mut self, order: usize, max_start: usize) -> Option<(usize, usize)> {
+        let first_pfn = self.first_pfn();
+
+        let (pfn, found) = (order..ORDER_COUNT)
+            .filter_map(|o| {
+                self.free_lists[o]
+                    .first()
+                    .map(|slot| (((first_pfn >> o) + slot) << o, o))
+            })
+            .min_by_key(|&(pfn, _)| pfn)?;
+
+        if pfn > max_start {
+            return None;
+        }
+
+        self.pop_block(pfn, found);
+        Some((pfn, found))
+    }
+
+    ///
+    /// Take the highest addressed free block of at least `order`
+    ///
+    fn take_block_back(&mut self, order: usize) -> Option<(usize, usize)> {
+        let first_pfn = self.first_pfn();
+
+        let (pfn, found) = (order..ORDER_COUNT)
+            .filter_map(|o| {
+                self.free_lists[o]
+                    .last()
+                    .map(|slot| (((first_pfn >> o) + slot) << o, o))
+            })
+            .max_by_key(|&(pfn, o)| pfn + (1 << o))?;
+
+        self.pop_block(pfn, found);
+        Some((pfn, found))
+    }
+
+    ///
+    /// Find `count` free frames as low in the segment as possible,
+    /// removing them from the free lists
+    ///
+    /// Returns the index of the first frame
+    ///
+    pub(crate) fn buddy_take_front(&mut self, count: usize) -> Option<usize> {
+        self.buddy_take_aligned(count, 0, usize::MAX)
+    }
+
+    ///
+    /// Find `count` free frames as low in the segment as possible, with the
+    /// first frame number aligned to `2^align_order` and the last frame
+    /// below the frame number `limit_pfn`
+    ///
+    /// Returns the index of the first frame
+    ///
+    pub(crate) fn buddy_take_aligned(
+        &mut self,
+        count: usize,
+        align_order: usize,
+        limit_pfn: usize,
+    ) -> Option<usize> {
+        let order = ceil_order(count).max(align_order);
+        let max_start = limit_pfn.checked_sub(count)?;
+
+        // Blocks are naturally aligned, so any block of a large enough
+        // order satisfies the alignment
+        if order <= MAX_ORDER
+            && let Some((pfn, found)) = self.take_block_front(order, max_start)
+        {
+            // Split off the upper halves until the block is the right size
+            for o in (order..found).rev() {
+                self.push_block(pfn + (1 << o), o);
+            }
+
+            self.release_pfns(pfn + count, pfn + (1 << order));
+
+            return Some(pfn - self.first_pfn());
+        }
+
+        // No single block is large enough, but the frames may still be
+        // contiguous across block boundaries
+        let idx = self.scan_aligned(count, 1 << align_order, max_start)?;
+        self.claim_range(idx, count);
+        Some(idx)
+    }
+
+    ///
+    /// Find `count` free frames as high in the segment as possible,
+    /// removing them from the free lists
+    ///
+    /// Returns the index of the first frame
+    ///
+    pub(crate) fn buddy_take_back(&mut self, count: usize) -> Option<usize> {
+        let order = ceil_order(count);
+
+        if order <= MAX_ORDER
+            && let Some((mut pfn, found)) = self.take_block_back(order)
+        {
+            // Split off the lower halves until the block is the right size
+            for o in (order..found).rev() {
+                self.push_block(pfn, o);
+                pfn += 1 << o;
+            }
+
+            let start = pfn + (1 << order) - count;
+            self.release_pfns(pfn, start);
+
+            return Some(start - self.first_pfn());
+        }
+
+        let idx = self.scan_back(count)?;
+        self.claim_range(idx, count);
+        Some(idx)
+    }
+}
+
+#[cfg(test)]
+impl FrameSegment {
+    fn free_pages_from_lists(&self) -> std::vec::Vec<bool> {
+        let mut free = std::vec![false; self.page_count];
+
+        for order in 0..ORDER_COUNT {
+            let slots = slot_count(self.first_pfn(), self.page_count, order);
+
+            for slot in (0..slots).filter(|&s| self.free_lists[order].get(s)) {
+                let pfn = ((self.first_pfn() >> order) + slot) << order;
+                assert!(
+                    self.block_in_segment(pfn, order),
+                    "free block leaks out of segment"
+                );
+
+                for page in pfn..pfn + (1 << order) {
+                    let idx = page - self.first_pfn();
+                    assert!(!free[idx], "frame {idx} is on two free lists");
+                    free[idx] = true;
+                }
+            }
+        }
+
+        free
+    }
+
+    pub(crate) fn assert_free_lists_consistent(&self) {
+        let free = self.free_pages_from_lists();
+
+        for (idx, listed) in free.into_iter().enumerate() {
+            let state = unsafe { self.get_metadata(idx) }.state();
+            assert_eq!(
+                state == super::FrameState::Free,
+                listed,
+                "frame {idx} is {state:?} but listed free = {listed}"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_table::FrameState;
+    use crate::phys::ptr_to_phys;
+    use crate::testing::{Arena, XorShift};
+    use std::vec::Vec;
+
+    #[test]
+    fn bit_tree_finds_first_and_last() {
+        let bits = 64 * 64 * 3 + 5;
+        let mut words = std::vec![0u64; BitTree::words_required(bits)];
+        let mut tree = unsafe { BitTree::new(ptr_to_phys(words.as_mut_ptr()), bits) };
+
+        assert_eq!(tree.first(), None);
+
+        for idx in [7, 4100, bits - 1, 64 * 64 * 2] {
+            tree.set(idx);
+        }
+
+        assert_eq!(tree.first(), Some(7));
+        assert_eq!(tree.last(), Some(bits - 1));
+
+        tree.clear(7);
+        tree.clear(bits - 1);
+
+        assert_eq!(tree.first(), Some(4100));
+        assert_eq!(tree.last(), Some(64 * 64 * 2));
+    }
+
+    #[test]
+    fn fresh_segment_is_fully_free() {
+        let arena = Arena::new(600);
+        // Start one page in so the segment is not nicely aligned
+        let seg = arena.segment(1, 599);
+
+        seg.assert_free_lists_consistent();
+        assert!(seg.free_pages_from_lists().iter().all(|f| *f));
+    }
+
+    #[test]
+    fn matches_linear_scanner() {
+        let arena = Arena::new(700);
+        let mut seg = arena.segment(3, 690);
+
+        let mut rng = XorShift(0x2545F4914F6CDD1D);
+        let mut live = Vec::new();
+
+        for _ in 0..4000 {
+            if live.is_empty() || rng.below(3) != 0 {
+                let count = 1 + rng.below(24);
+                let from_back = rng.below(2) == 0;
+
+                let expected = if from_back {
+                    seg.scan_back(count)
+                } else {
+                    seg.scan_front(count)
+                };
+
+                let allocation = if from_back {
+                    seg.alloc_back(count, FrameState::Kernel, 3)
+                } else {
+                    seg.alloc_front(count, FrameState::Kernel, 3)
+                };
+
+                assert_eq!(expected.is_some(), allocation.is_some());
+
+                if let Some(allocation) = allocation {
+                    // The buddy allocator may skip the run the scanner finds
+                    // for an aligned block further in, but only for one
+                    let expected = expected.unwrap();
+                    let idx = seg.index_of(allocation.phys_addr);
+                    let block = 1 << ceil_order(count);
+
+                    let aligned = if from_back {
+                        assert!(idx <= expected);
+                        (seg.first_pfn() + idx + count).is_multiple_of(block)
+                    } else {
+                        assert!(idx >= expected);
+                        (seg.first_pfn() + idx).is_multiple_of(block)
+                    };
+
+                    assert!(idx == expected || aligned);
+
+                    // The lowest free frame always starts a free block and the
+                    // highest always ends one, so single frames match exactly
+                    if count == 1 {
+                        assert_eq!(idx, expected);
+                    }
+
+                    live.push(allocation);
+                }
+            } else {
+                let victim = live.swap_remove(rng.below(live.len()));
+                let idx = seg.index_of(victim.phys_addr);
+                seg.free(idx, victim.page_count, victim.pid).unwrap();
+            }
+
+            seg.assert_free_lists_consistent();
+        }
+
+        for victim in live {
+            let idx = seg.index_of(victim.phys_addr);
+            seg.free(idx, victim.page_count, victim.pid).unwrap();
+        }
+
+        // Everything coalesces back into the largest aligned blocks
+        let mut expected = Vec::new();
+        let mut pfn = seg.first_pfn();
+
+        while pfn < seg.end_pfn() {
+            let mut order = (pfn.trailing_zeros() as usize).min(MAX_ORDER);
+
+            while pfn + (1 << order) > seg.end_pfn() {
+                order -= 1;
+            }
+
+            expected.push((pfn, order));
+            pfn += 1 << order;
+        }
+
+        let mut blocks = Vec::new();
+
+        for order in 0..ORDER_COUNT {
+            let slots = slot_count(seg.first_pfn(), seg.page_count, order);
+
+            for slot in (0..slots).filter(|&s| seg.free_lists[order].get(s)) {
+                blocks.push((((seg.first_pfn() >> order) + slot) << order, order));
+            }
+        }
+
+        blocks.sort();
+        assert_eq!(blocks, expected);
+    }
+
+    #[test]
+    fn aligned_allocations() {
+        const MEGAPAGE: usize = 2 * 1024 * 1024;
+
+        // Start the segment a few pages past a megapage boundary, with the
+        // bookkeeping kept in front of it, so the layout doesn't depend on
+        // where the arena lands
+        let arena = Arena::new(3072);
+        let bookkeeping = arena.addr();
+        let boundary =
+            (bookkeeping + FrameSegment::bookkeeping_size(2040)).next_multiple_of(MEGAPAGE);
+        let mut seg =
+            unsafe { FrameSegment::initialize_at(boundary + 5 * 4096, 2040, bookkeeping) };
+
+        let small = seg.alloc_front(1, FrameState::Kernel, 0).unwrap();
+        let huge = seg
+            .alloc_aligned(3, MEGAPAGE, usize::MAX, FrameState::Kernel, 0)
+            .unwrap();
+
+        assert_eq!(huge.phys_addr % MEGAPAGE, 0);
+        assert_eq!(huge.page_count, 3);
+        assert_ne!(small.phys_addr, huge.phys_addr);
+
+        // Nothing aligned fits below the first megapage boundary
+        let limit = huge.phys_addr;
+        assert!(
+            seg.alloc_aligned(1, MEGAPAGE, limit, FrameState::Kernel, 0)
+                .is_none()
+        );
+
+        let below = seg
+            .alloc_aligned(2, 8 * 4096, limit, FrameState::Kernel, 0)
+            .unwrap();
+        assert_eq!(below.phys_addr % (8 * 4096), 0);
+        assert!(below.phys_addr + 2 * 4096 <= limit);
+
+        seg.assert_free_lists_consistent();
+    }
+
+    #[test]
+    fn aligned_allocation_across_blocks() {
+        let arena = Arena::new(64);
+        let mut seg = arena.segment(0, 48);
+
+        let pages: Vec<_> = (0..seg.page_count)
+            .map(|_| seg.alloc_front(1, FrameState::Kernel, 0).unwrap())
+            .collect();
+
+        // Free a run which is 4 page aligned but only spans an order 2 and
+        // an order 1 block
+        let start = pages
+            .iter()
+            .position(|p| (p.phys_addr >> 12) % 8 == 4)
+            .unwrap();
+
+        for page in &pages[start..start + 6] {
+            let idx = seg.index_of(page.phys_addr);
+            seg.free(idx, 1, 0).unwrap();
+        }
+
+        let run = seg
+            .alloc_aligned(6, 4 * 4096, usize::MAX, FrameState::User, 2)
+            .unwrap();
+
+        assert_eq!(run.phys_addr, pages[start].phys_addr);
+        seg.assert_free_lists_consistent();
+    }
+
+    #[test]
+    fn fallback_scans_find_the_same_runs_as_a_naive_search() {
+        let arena = Arena::new(300);
+        let mut seg = arena.segment(3, 290);
+        let mut rng = XorShift(0x5DEE_CE66_D1CE_4E5B);
+
+        let pages: Vec<_> = (0..seg.page_count)
+            .map(|_| seg.alloc_front(1, FrameState::Kernel, 0).unwrap())
+            .collect();
+
+        for page in pages.iter().filter(|_| rng.below(4) != 0) {
+            let idx = seg.index_of(page.phys_addr);
+            seg.free(idx, 1, 0).unwrap();
+        }
+
+        let free = |idx: usize| unsafe { seg.get_metadata(idx) }.state() == FrameState::Free;
+        let run_at = |idx: usize, count: usize| (idx..idx + count).all(free);
+        let first_pfn = seg.first_page_addr >> 12;
+        let max_start = first_pfn + 200;
+
+        for count in 1..12 {
+            for align in [1, 2, 4, 8] {
+                let naive = (0..=seg.page_count - count).find(|&idx| {
+                    (first_pfn + idx).is_multiple_of(align)
+                        && first_pfn + idx <= max_start
+                        && run_at(idx, count)
+                });
+
+                assert_eq!(seg.scan_aligned(count, align, max_start), naive);
+            }
+
+            let naive = (0..=seg.page_count - count)
+                .rev()
+                .find(|&idx| run_at(idx, count));
+
+            assert_eq!(seg.scan_back(count), naive);
+        }
+    }
+}
@@ -0,0 +1,472 @@
+//!
+//! The frame table shared between harts
+//!
+//! The table itself sits behind a single lock, single kernel pages are
+//! handed out through small per-hart caches so the common case doesn't
+//! contend on it. Cached frames stay allocated as `Kernel` frames of pid 0
+//! in the table.
+//!
+//! A cached frame has a reference count of 0, which is what tells it apart
+//! from a live page when one is freed. It is set under the table lock as the
+//! frame enters a cache, and only the cache holding the frame sets it back
+//! to 1 when handing it out.
+//!
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::{FrameState, FrameTable, FreeError, MemoryAllocation};
+use crate::PAGE_SIZE_B;
+use crate::sync::{SpinLock, SpinLockGuard};
+
+///
+/// Harts with an id at or above this allocate straight from the table
+///
+pub const MAX_HARTS: usize = 8;
+
+///
+/// Frames each hart can hold on to
+///
+pub const HART_CACHE_SIZE: usize = 32;
+
+///
+/// Frames moved between a hart's cache and the table at once
+///
+const HART_CACHE_BATCH: usize = HART_CACHE_SIZE / 2;
+
+struct CachedFrame {
+    phys_addr: usize,
+    refs: &'static AtomicU16,
+}
+
+struct HartCache {
+    frames: [Option<CachedFrame>; HART_CACHE_SIZE],
+    len: usize,
+}
+
+impl HartCache {
+    const fn new() -> Self {
+        Self {
+            frames: [const { None }; HART_CACHE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<CachedFrame> {
+        self.len = self.len.checked_sub(1)?;
+        self.frames[self.len].take()
+    }
+
+    fn push(&mut self, frame: CachedFrame) {
+        self.frames[self.len] = Some(frame);
+        self.len += 1;
+    }
+}
+
+///
+/// A frame table which can be used from every hart at once
+///
+/// Locks are always taken cache first, so the table guard from `lock` must
+/// be dropped before calling any of the per-hart methods
+///
+pub struct GlobalFrameTable {
+    table: SpinLock<FrameTable>,
+    caches: [SpinLock<HartCache>; MAX_HARTS],
+}
+
+impl GlobalFrameTable {
+    pub const fn new() -> Self {
+        Self {
+            table: SpinLock::new(FrameTable {
+                segments: alloc::vec::Vec::new(),
+            }),
+            caches: [const { SpinLock::new(HartCache::new()) }; MAX_HARTS],
+        }
+    }
+
+    ///
+    /// Hand the boot-time frame table over, this can only be done once
+    ///
+    pub fn init(&self, table: FrameTable) {
+        let mut current = self.table.lock();
+
+        assert!(
+            current.segments.is_empty(),
+            "GlobalFrameTable::init: already initialized"
+        );
+
+        *current = table;
+    }
+
+    ///
+    /// Lock the whole table, for anything beyond single kernel pages
+    ///
+    pub fn lock(&self) -> SpinLockGuard<'_, FrameTable> {
+        self.table.lock()
+    }
+
+    ///
+    /// Lock the whole table if no one holds it
+    ///
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, FrameTable>> {
+        self.table.try_lock()
+    }
+
+    ///
+    /// Whether the hart running this holds the whole table
+    ///
+    pub fn held_by_current_hart(&self) -> bool {
+        self.table.held_by_current_hart()
+    }
+
+    ///
+    /// Allocate a single `Kernel` page for pid 0 through the cache of `hart`
+    ///
+    pub fn alloc_page(&self, hart: usize) -> Option<MemoryAllocation> {
+        let Some(cache) = self.caches.get(hart) else {
+            return self.lock().alloc_back(1, FrameState::Kernel, 0);
+        };
+
+        let mut cache = cache.lock();
+
+        if cache.len == 0 {
+            let mut table = self.table.lock();
+
+            while cache.len < HART_CACHE_BATCH {
+                let Some(page) = table.alloc_back(1, FrameState::Kernel, 0) else {
+                    break;
+                };
+
+                let refs = Self::refcount(&table, page.phys_addr);
+                refs.store(0, Ordering::Relaxed);
+
+                cache.push(CachedFrame {
+                    phys_addr: page.phys_addr,
+                    refs,
+                });
+            }
+        }
+
+        let frame = cache.pop()?;
+        frame.refs.store(1, Ordering::Relaxed);
+
+        Some(MemoryAllocation {
+            phys_addr: frame.phys_addr,
+            page_count: 1,
+            pid: 0,
+            state: FrameState::Kernel,
+        })
+    }
+
+    ///
+    /// Free a page, keeping it in the cache of `hart` when it came from `alloc_page`
+    ///
+    /// Anything other than a single `Kernel` page of pid 0 goes straight back
+    /// to the table
+    ///
+    pub fn free_page(&self, hart: usize, page: MemoryAllocation) -> Result<(), FreeError> {
+        let cacheable = page.page_count == 1 && page.state == FrameState::Kernel && page.pid == 0;
+
+        let cache = match self.caches.get(hart) {
+            Some(cache) if cacheable => cache,
+            _ => return self.lock().free(page),
+        };
+
+        let addr = page.phys_addr;
+
+        if addr & (PAGE_SIZE_B - 1) != 0 {
+            return Err(FreeError::Misaligned { addr });
+        }
+
+        let mut cache = cache.lock();
+        let mut table = self.table.lock();
+
+        // Checked and marked as cached under the same lock, so a page freed on
+        // two harts at once only makes it into one cache
+        let refs = Self::check_live(&table, addr)?;
+        refs.store(0, Ordering::Relaxed);
+
+        if cache.len == HART_CACHE_SIZE {
+            Self::flush(&mut table, &mut cache, HART_CACHE_BATCH);
+        }
+
+        cache.push(CachedFrame {
+            phys_addr: addr,
+            refs,
+        });
+
+        Ok(())
+    }
+
+    ///
+    /// Return every frame cached by `hart` to the table
+    ///
+    pub fn drain_cache(&self, hart: usize) -> usize {
+        let Some(cache) = self.caches.get(hart) else {
+            return 0;
+        };
+
+        let mut cache = cache.lock();
+        let count = cache.len;
+
+        Self::flush(&mut self.table.lock(), &mut cache, count);
+
+        count
+    }
+
+    ///
+    /// Return the frames cached by every hart to the table
+    ///
+    pub fn drain_all(&self) -> usize {
+        (0..MAX_HARTS).map(|hart| self.drain_cache(hart)).sum()
+    }
+
+    ///
+    /// Number of frames currently held in per-hart caches
+    ///
+    /// These are counted as `Kernel` frames by the table statistics
+    ///
+    pub fn cached_pages(&self) -> usize {
+        self.caches.iter().map(|cache| cache.lock().len).sum()
+    }
+
+    ///
+    /// Make sure the page at `addr` is a live `Kernel` page of pid 0 which
+    /// can go into a cache, returning its reference count
+    ///
+    fn check_live(table: &FrameTable, addr: usize) -> Result<&'static AtomicU16, FreeError> {
+        let segment = table
+            .segment_of(addr)
+            .ok_or(FreeError::NotInTable { addr })?;
+        let idx = segment.index_of(addr);
+        let meta = unsafe { segment.get_metadata(idx) };
+
+        match meta.state() {
+            FrameState::Kernel => {}
+            FrameState::Free => return Err(FreeError::DoubleFree { addr }),
+            FrameState::Reserved => return Err(FreeError::Reserved { addr }),
+            state => return Err(FreeError::NotAllocated { addr, state }),
+        }
+
+        if meta.pid() != 0 {
+            return Err(FreeError::PidMismatch {
+                addr,
+                expected: 0,
+                found: meta.pid(),
+            });
+        }
+
+        let refs = Self::refcount(table, addr);
+
+        match refs.load(Ordering::Relaxed) {
+            0 => Err(FreeError::DoubleFree { addr }),
+            1 => Ok(refs),
+            refs => Err(FreeError::Shared { addr, refs }),
+        }
+    }
+
+    ///
+    /// The reference count of the frame at `addr`, which a cache writes to
+    /// without holding the table lock
+    ///
+    fn refcount(table: &FrameTable, addr: usize) -> &'static AtomicU16 {
+        let segment = table
+            .segment_of(addr)
+            .expect("GlobalFrameTable: frame outside of the table");
+        let refs = unsafe { segment.get_refcount(segment.index_of(addr)) };
+
+        unsafe { AtomicU16::from_ptr(refs) }
+    }
+
+    fn flush(table: &mut FrameTable, cache: &mut HartCache, count: usize) {
+        for _ in 0..count {
+            let Some(frame) = cache.pop() else {
+                break;
+            };
+
+            table
+                .free_addr(frame.phys_addr, 1, 0)
+                .expect("GlobalFrameTable: cached frame failed to free");
+        }
+    }
+}
+
+impl Default for GlobalFrameTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Arena, XorShift};
+    use std::vec::Vec;
+
+    #[test]
+    fn caches_pages_per_hart() {
+        let arena = Arena::new(256);
+        let frames = GlobalFrameTable::new();
+        frames.init(arena.frame_table());
+
+        let free = frames.lock().stats().totals.get(FrameState::Free);
+
+        let page = frames.alloc_page(1).unwrap();
+        assert_eq!(frames.cached_pages(), HART_CACHE_BATCH - 1);
+        assert_eq!(
+            frames.lock().stats().totals.get(FrameState::Free),
+            free - HART_CACHE_BATCH
+        );
+
+        frames.free_page(1, page.clone()).unwrap();
+        assert_eq!(
+            frames.free_page(1, page.clone()).unwrap_err(),
+            FreeError::DoubleFree {
+                addr: page.phys_addr
+            }
+        );
+
+        // Harts without a cache, and other allocations, go straight to the table
+        let uncached = frames.alloc_page(MAX_HARTS).unwrap();
+        frames.free_page(MAX_HARTS, uncached).unwrap();
+
+        let user = frames.lock().alloc_front(1, FrameState::User, 4).unwrap();
+        frames.free_page(1, user).unwrap();
+
+        assert_eq!(frames.drain_all(), HART_CACHE_BATCH);
+        assert_eq!(frames.cached_pages(), 0);
+        assert_eq!(frames.lock().stats().totals.get(FrameState::Free), free);
+    }
+
+    #[test]
+    fn pages_not_live_are_rejected_on_every_hart() {
+        let arena = Arena::new(256);
+        let frames = GlobalFrameTable::new();
+        frames.init(arena.frame_table());
+
+        let free = frames.lock().stats().totals.get(FrameState::Free);
+        let double_free = |page: &MemoryAllocation| FreeError::DoubleFree {
+            addr: page.phys_addr,
+        };
+
+        // A page already sitting in another hart's cache
+        let page = frames.alloc_page(1).unwrap();
+        frames.free_page(1, page.clone()).unwrap();
+        assert_eq!(
+            frames.free_page(2, page.clone()).unwrap_err(),
+            double_free(&page)
+        );
+
+        // A page already back in the table
+        frames.drain_all();
+        assert_eq!(
+            frames.free_page(2, page.clone()).unwrap_err(),
+            double_free(&page)
+        );
+
+        // A page which was never allocated, or not as a kernel page
+        let never = MemoryAllocation {
+            phys_addr: page.phys_addr + PAGE_SIZE_B,
+            ..page.clone()
+        };
+        assert_eq!(
+            frames.free_page(1, never.clone()).unwrap_err(),
+            double_free(&never)
+        );
+
+        let user = frames.lock().alloc_front(1, FrameState::User, 0).unwrap();
+        let as_kernel = MemoryAllocation {
+            state: FrameState::Kernel,
+            ..user.clone()
+        };
+        assert_eq!(
+            frames.free_page(1, as_kernel).unwrap_err(),
+            FreeError::NotAllocated {
+                addr: user.phys_addr,
+                state: FrameState::User
+            }
+        );
+        frames.free_page(1, user).unwrap();
+
+        assert_eq!(frames.cached_pages(), 0);
+        assert_eq!(frames.lock().stats().totals.get(FrameState::Free), free);
+    }
+
+    #[test]
+    fn page_freed_on_two_harts_at_once_is_cached_once() {
+        let arena = Arena::new(256);
+        let frames = GlobalFrameTable::new();
+        frames.init(arena.frame_table());
+
+        let free = frames.lock().stats().totals.get(FrameState::Free);
+
+        for _ in 0..200 {
+            let page = frames.alloc_page(0).unwrap();
+
+            let freed = std::thread::scope(|scope| {
+                let racing = [1, 2].map(|hart| {
+                    let (frames, page) = (&frames, page.clone());
+                    scope.spawn(move || frames.free_page(hart, page).is_ok())
+                });
+
+                racing.into_iter().map(|t| t.join().unwrap()).filter(|&ok| ok).count()
+            });
+
+            assert_eq!(freed, 1);
+        }
+
+        frames.drain_all();
+
+        let table = frames.lock();
+        assert_eq!(table.stats().totals.get(FrameState::Free), free);
+        table.segments[0].assert_free_lists_consistent();
+    }
+
+    #[test]
+    fn concurrent_harts_get_distinct_pages() {
+        const HARTS: usize = 4;
+
+        let arena = Arena::new(1024);
+        let frames = GlobalFrameTable::new();
+        frames.init(arena.frame_table());
+
+        let free = frames.lock().stats().totals.get(FrameState::Free);
+
+        std::thread::scope(|scope| {
+            for hart in 0..HARTS {
+                let frames = &frames;
+
+                scope.spawn(move || {
+                    let mut rng = XorShift(0x9E37_79B9 + hart as u64);
+                    let mut held: Vec<(MemoryAllocation, u64)> = Vec::new();
+
+                    for round in 0..20_000u64 {
+                        if held.len() < 64 && rng.below(2) == 0 {
+                            let page = frames.alloc_page(hart).unwrap();
+                            let tag = ((hart as u64) << 32) | round;
+                            unsafe { page.as_slice::<u64>() }.fill(tag);
+                            held.push((page, tag));
+                        } else if !held.is_empty() {
+                            let (page, tag) = held.swap_remove(rng.below(held.len()));
+                            let contents = unsafe { page.as_slice::<u64>() };
+                            assert!(
+                                contents.iter().all(|&t| t == tag),
+                                "page shared between harts"
+                            );
+                            frames.free_page(hart, page).unwrap();
+                        }
+                    }
+
+                    for (page, _) in held {
+                        frames.free_page(hart, page).unwrap();
+                    }
+                });
+            }
+        });
+
+        frames.drain_all();
+
+        let table = frames.lock();
+        assert_eq!(table.stats().totals.get(FrameState::Free), free);
+        table.segments[0].assert_free_lists_consistent();
+    }
+}
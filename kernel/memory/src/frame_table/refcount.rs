@@ -0,0 +1,202 @@
+//!
+//! Per-frame reference counts
+//!
+//! A frame may be mapped into several address spaces at once (shared memory,
+//! copy-on-write, the page cache). Allocation hands out frames with a single
+//! reference, every additional user takes a reference, and the frame returns
+//! to the free lists once the last reference is dropped
+//!
+
+use super::{FrameSegment, FrameState, FrameTable};
+use crate::PAGE_SIZE_B;
+use crate::phys::phys_to_ptr;
+
+///
+/// Reasons a reference count could not be changed
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefCountError {
+    ///
+    /// The address is not tracked by any frame segment
+    ///
+    NotInTable { addr: usize },
+
+    ///
+    /// The address is not aligned to a page boundary
+    ///
+    Misaligned { addr: usize },
+
+    ///
+    /// The frame is free, so there is nothing to reference
+    ///
+    NotAllocated { addr: usize },
+
+    ///
+    /// Reserved frames are not reference counted
+    ///
+    Reserved { addr: usize },
+
+    ///
+    /// The frame already holds the maximum number of references
+    ///
+    Overflow { addr: usize },
+}
+
+impl FrameTable {
+    fn refcounted_segment(&mut self, phys_addr: usize) -> Result<&mut FrameSegment, RefCountError> {
+        if phys_addr & (PAGE_SIZE_B - 1) != 0 {
+            return Err(RefCountError::Misaligned { addr: phys_addr });
+        }
+
+        self.segment_of_mut(phys_addr)
+            .ok_or(RefCountError::NotInTable { addr: phys_addr })
+    }
+
+    ///
+    /// The number of references held on the frame at `phys_addr`
+    ///
+    pub fn ref_count(&self, phys_addr: usize) -> Option<u16> {
+        let segment = self.segment_of(phys_addr)?;
+        let idx = segment.index_of(phys_addr);
+
+        Some(*unsafe { segment.get_refcount(idx) })
+    }
+
+    ///
+    /// Take another reference on the frame at `phys_addr`
+    ///
+    /// Returns the new reference count
+    ///
+    pub fn frame_ref(&mut self, phys_addr: usize) -> Result<u16, RefCountError> {
+        let segment = self.refcounted_segment(phys_addr)?;
+        let idx = segment.index_of(phys_addr);
+
+        segment.frame_ref(idx)
+    }
+
+    ///
+    /// Drop a reference on the frame at `phys_addr`
+    ///
+    /// The frame is freed once the last reference is dropped,
+    /// returns the remaining reference count
+    ///
+    pub fn frame_unref(&mut self, phys_addr: usize) -> Result<u16, RefCountError> {
+        let segment = self.refcounted_segment(phys_addr)?;
+        let idx = segment.index_of(phys_addr);
+
+        segment.frame_unref(idx)
+    }
+}
+
+impl FrameSegment {
+    ///
+    /// # Safety
+    ///
+    /// The segment bookkeeping must be mapped
+    ///
+    pub unsafe fn get_refcount(&self, idx: usize) -> &'static mut u16 {
+        assert!(idx < self.page_count, "get_refcount: index out of bounds");
+        unsafe {
+            &mut *phys_to_ptr::<u16>(
+                self.frame_refcount_start_addr + idx * core::mem::size_of::<u16>(),
+            )
+        }
+    }
+
+    fn check_refcounted(&self, idx: usize) -> Result<(), RefCountError> {
+        let addr = self.first_page_addr + idx * PAGE_SIZE_B;
+
+        match unsafe { self.get_metadata(idx) }.state() {
+            FrameState::Free => Err(RefCountError::NotAllocated { addr }),
+            FrameState::Reserved => Err(RefCountError::Reserved { addr }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn frame_ref(&mut self, idx: usize) -> Result<u16, RefCountError> {
+        self.check_refcounted(idx)?;
+
+        let refs = unsafe { self.get_refcount(idx) };
+
+        *refs = refs.checked_add(1).ok_or(RefCountError::Overflow {
+            addr: self.first_page_addr + idx * PAGE_SIZE_B,
+        })?;
+
+        Ok(*refs)
+    }
+
+    pub fn frame_unref(&mut self, idx: usize) -> Result<u16, RefCountError> {
+        self.check_refcounted(idx)?;
+
+        let refs = unsafe { self.get_refcount(idx) };
+
+        if *refs > 1 {
+            *refs -= 1;
+            return Ok(*refs);
+        }
+
+        let pid = unsafe { self.get_metadata(idx) }.pid();
+
+        self.free(idx, 1, pid)
+            .expect("frame_unref: last reference failed to free");
+
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_table::FreeError;
+    use crate::testing::Arena;
+
+    #[test]
+    fn last_reference_frees_the_frame() {
+        let arena = Arena::new(64);
+        let mut ft = FrameTable {
+            segments: std::vec![arena.segment(0, 64)],
+        };
+
+        let shared = ft.alloc_front(1, FrameState::User, 4).unwrap();
+        let addr = shared.phys_addr;
+
+        assert_eq!(ft.ref_count(addr), Some(1));
+        assert_eq!(ft.frame_ref(addr), Ok(2));
+        assert_eq!(ft.frame_ref(addr), Ok(3));
+
+        assert_eq!(
+            ft.free(shared.clone()),
+            Err(FreeError::Shared { addr, refs: 3 })
+        );
+
+        assert_eq!(ft.frame_unref(addr), Ok(2));
+        assert_eq!(ft.frame_unref(addr), Ok(1));
+        assert_eq!(ft.frame_unref(addr), Ok(0));
+
+        assert_eq!(ft.count_frames(4, FrameState::User), 0);
+        assert_eq!(
+            ft.frame_unref(addr),
+            Err(RefCountError::NotAllocated { addr })
+        );
+        assert_eq!(ft.free(shared), Err(FreeError::DoubleFree { addr }));
+
+        ft.segments[0].assert_free_lists_consistent();
+    }
+
+    #[test]
+    fn reclaim_drops_shared_references() {
+        let arena = Arena::new(64);
+        let mut ft = FrameTable {
+            segments: std::vec![arena.segment(0, 64)],
+        };
+
+        let private = ft.alloc_front(2, FrameState::User, 5).unwrap();
+        let shared = ft.alloc_front(1, FrameState::User, 5).unwrap();
+        ft.frame_ref(shared.phys_addr).unwrap();
+
+        assert_eq!(ft.reclaim_pid(5), private.page_count);
+        assert_eq!(ft.ref_count(shared.phys_addr), Some(1));
+        assert_eq!(ft.frame_unref(shared.phys_addr), Ok(0));
+        assert_eq!(ft.count_frames(5, FrameState::User), 0);
+    }
+}
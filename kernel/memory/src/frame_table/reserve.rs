@@ -0,0 +1,320 @@
+//!
+//! Reserving and releasing physical address ranges
+//!
+//! Segments cover whole memory devices, so firmware regions, the kernel
+//! image and other boot time memory are tracked as `Reserved` or `Kernel`
+//! frames rather than left out of the frame table. Once such a region is
+//! no longer needed it can be released back to the free lists, memory
+//! which no segment covers can be added to the table later on
+//!
+
+use super::{FrameMetadataEntry, FrameSegment, FrameState, FrameTable};
+use crate::PAGE_SIZE_B;
+
+impl FrameTable {
+    ///
+    /// Mark every free frame overlapping `addr..addr + size` as `state`
+    ///
+    /// Frames which are already in use are left untouched, returns the
+    /// number of frames which were reserved
+    ///
+    pub fn reserve_region(
+        &mut self,
+        addr: usize,
+        size: usize,
+        state: FrameState,
+        flags: u8,
+    ) -> usize {
+        self.segments
+            .iter_mut()
+            .map(|segment| segment.reserve_range(addr, size, state, flags))
+            .sum()
+    }
+
+    ///
+    /// Return every frame fully inside `addr..addr + size` which was reserved
+    /// as `state` to the free lists
+    ///
+    /// Frames allocated since, and frames flagged `FLAG_NO_MAP`, are never
+    /// released, returns the number of frames which were released
+    ///
+    pub fn release_region(&mut self, addr: usize, size: usize, state: FrameState) -> usize {
+        self.segments
+            .iter_mut()
+            .map(|segment| segment.release_region(addr, size, state))
+            .sum()
+    }
+
+    ///
+    /// Start tracking memory which is not covered by any segment yet
+    ///
+    /// The start of the region holds the new segment's bookkeeping, like
+    /// `FrameSegment::initialize`. Returns the number of frames added, or
+    /// `None` when the region is too small to hold any
+    ///
+    /// # Safety
+    ///
+    /// The region must be unused, writable memory, it is owned by the table afterwards
+    ///
+    pub unsafe fn add_segment(&mut self, start: usize, size: usize) -> Option<usize> {
+        let overlaps = self.segments.iter().any(|segment| {
+            // Bookkeeping placed by `initialize` sits right in front of the frames
+            let first = segment
+                .frame_metadata_start_addr
+                .min(segment.first_page_addr);
+            let end = segment.first_page_addr + segment.page_count * PAGE_SIZE_B;
+
+            start < end && first < start + size
+        });
+
+        assert!(
+            !overlaps,
+            "add_segment: region overlaps an existing segment"
+        );
+
+        let segment = unsafe { FrameSegment::initialize(start, size) }?;
+        let page_count = segment.page_count;
+
+        self.segments.push(segment);
+
+        Some(page_count)
+    }
+}
+
+impl FrameSegment {
+    ///
+    /// The frame indexes of this segment which overlap `addr..addr + size`,
+    /// rounding outwards to whole frames
+    ///
+    fn overlapping_indexes(&self, addr: usize, size: usize) -> core::ops::Range<usize> {
+        let end = self.first_page_addr + self.page_count * PAGE_SIZE_B;
+        let start = (addr & !(PAGE_SIZE_B - 1)).clamp(self.first_page_addr, end);
+        let stop = (addr + size)
+            .next_multiple_of(PAGE_SIZE_B)
+            .clamp(start, end);
+
+        (start - self.first_page_addr) / PAGE_SIZE_B..(stop - self.first_page_addr) / PAGE_SIZE_B
+    }
+
+    fn is_free(&self, idx: usize) -> bool {
+        unsafe { self.get_metadata(idx) }.state() == FrameState::Free
+    }
+
+    pub fn reserve_range(
+        &mut self,
+        addr: usize,
+        size: usize,
+        state: FrameState,
+        flags: u8,
+    ) -> usize {
+        let range = self.overlapping_indexes(addr, size);
+        let mut reserved = 0;
+        let mut idx = range.start;
+
+        while idx < range.end {
+            if !self.is_free(idx) {
+                idx += 1;
+                continue;
+            }
+
+            let run = (idx..range.end).take_while(|&i| self.is_free(i)).count();
+
+            self.claim_range(idx, run);
+
+            for offset in 0..run {
+                let meta = unsafe { self.get_metadata(idx + offset) };
+                meta.set_state(state);
+                meta.set_flags(flags | FrameMetadataEntry::FLAG_RESERVED_REGION);
+                meta.set_pid(0);
+                *unsafe { self.get_refcount(idx + offset) } = 1;
+            }
+
+            reserved += run;
+            idx += run;
+        }
+
+        reserved
+    }
+
+    pub fn release_region(&mut self, addr: usize, size: usize, state: FrameState) -> usize {
+        // Only frames which lie completely inside the region are released
+        let start = addr.next_multiple_of(PAGE_SIZE_B);
+        let stop = (addr + size) & !(PAGE_SIZE_B - 1);
+
+        if stop <= start {
+            return 0;
+        }
+
+        let range = self.overlapping_indexes(start, stop - start);
+        let mut released = 0;
+
+        for idx in range {
+            let meta = unsafe { self.get_metadata(idx) };
+
+            let reserved = meta.flags() & FrameMetadataEntry::FLAG_RESERVED_REGION != 0;
+
+            if meta.state() != state
+                || !reserved
+                || meta.flags() & FrameMetadataEntry::FLAG_NO_MAP != 0
+            {
+                continue;
+            }
+
+            *meta = FrameMetadataEntry::new();
+            *unsafe { self.get_refcount(idx) } = 0;
+            self.release_range(idx, 1);
+
+            released += 1;
+        }
+
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_table::FreeError;
+    use crate::testing::Arena;
+
+    #[test]
+    fn reserved_regions_are_tracked_and_released() {
+        let arena = Arena::new(160);
+        let base = arena.addr() + 4 * PAGE_SIZE_B;
+
+        // Cover the whole arena, keeping the bookkeeping inside the segment
+        let bookkeeping_pages = FrameSegment::bookkeeping_size(156) / PAGE_SIZE_B;
+        let bookkeeping = base + (156 - bookkeeping_pages) * PAGE_SIZE_B;
+        let mut ft = FrameTable {
+            segments: std::vec![unsafe { FrameSegment::initialize_at(base, 156, bookkeeping) }],
+        };
+
+        assert_eq!(ft.count_frames(0, FrameState::Kernel), bookkeeping_pages);
+
+        // Firmware at the start, partially covering the 9th frame
+        let firmware = ft.reserve_region(base, 8 * PAGE_SIZE_B + 12, FrameState::Reserved, 0);
+        let no_map = ft.reserve_region(
+            base + 20 * PAGE_SIZE_B,
+            2 * PAGE_SIZE_B,
+            FrameState::Reserved,
+            FrameMetadataEntry::FLAG_NO_MAP,
+        );
+        let kernel = ft.reserve_region(
+            base + 30 * PAGE_SIZE_B,
+            10 * PAGE_SIZE_B,
+            FrameState::Kernel,
+            0,
+        );
+
+        assert_eq!((firmware, no_map, kernel), (9, 2, 10));
+        assert_eq!(ft.count_frames(0, FrameState::Reserved), 11);
+
+        // Allocations skip reserved frames
+        let first = ft.alloc_front(1, FrameState::User, 1).unwrap();
+        assert_eq!(first.phys_addr, base + 9 * PAGE_SIZE_B);
+        assert_eq!(
+            ft.free_addr(base, 1, 0),
+            Err(FreeError::Reserved { addr: base })
+        );
+
+        // The firmware scratch is handed back, the no-map region is not
+        assert_eq!(
+            ft.release_region(base, 9 * PAGE_SIZE_B, FrameState::Reserved),
+            9
+        );
+        assert_eq!(
+            ft.release_region(
+                base + 20 * PAGE_SIZE_B,
+                2 * PAGE_SIZE_B,
+                FrameState::Reserved
+            ),
+            0
+        );
+        assert_eq!(
+            ft.release_region(
+                base + 30 * PAGE_SIZE_B,
+                10 * PAGE_SIZE_B,
+                FrameState::Kernel
+            ),
+            10
+        );
+
+        assert_eq!(ft.count_frames(0, FrameState::Reserved), 2);
+        assert_eq!(
+            ft.alloc_front(1, FrameState::User, 1).unwrap().phys_addr,
+            base
+        );
+
+        ft.segments[0].assert_free_lists_consistent();
+    }
+
+    #[test]
+    fn only_reserved_frames_are_released() {
+        let arena = Arena::new(64);
+        let mut ft = FrameTable {
+            segments: std::vec![arena.segment(0, 48)],
+        };
+
+        let live = ft.alloc_front(2, FrameState::Kernel, 0).unwrap();
+        let size = 4 * PAGE_SIZE_B;
+
+        // The reservation takes the two free frames behind the allocation
+        assert_eq!(
+            ft.reserve_region(live.phys_addr, size, FrameState::Kernel, 0),
+            2
+        );
+        assert_eq!(
+            ft.release_region(live.phys_addr, size, FrameState::Kernel),
+            2
+        );
+
+        // A later allocation in the range is left alone
+        let later = ft.alloc_front(2, FrameState::Kernel, 0).unwrap();
+        assert_eq!(later.phys_addr, live.phys_addr + 2 * PAGE_SIZE_B);
+        assert_eq!(
+            ft.release_region(live.phys_addr, size, FrameState::Kernel),
+            0
+        );
+
+        ft.free(live).unwrap();
+        ft.free(later).unwrap();
+        ft.segments[0].assert_free_lists_consistent();
+    }
+
+    #[test]
+    fn segments_can_be_added_late() {
+        let arena = Arena::new(96);
+        let mut ft = FrameTable {
+            segments: std::vec![arena.segment(0, 48)],
+        };
+
+        let root = ft.root_frame_address();
+        let added = unsafe { ft.add_segment(arena.addr() + 48 * PAGE_SIZE_B, 48 * PAGE_SIZE_B) };
+
+        assert_eq!(added, Some(ft.segments[1].page_count));
+        assert_eq!(ft.root_frame_address(), root);
+
+        // The new frames are free, everything else is as it was
+        let free = ft.stats().totals.get(FrameState::Free);
+        assert_eq!(free, ft.segments[0].page_count + ft.segments[1].page_count);
+
+        let last = ft.segments[1].first_page_addr + (ft.segments[1].page_count - 1) * PAGE_SIZE_B;
+        assert!(ft.segment_of(last).is_some());
+
+        assert_eq!(
+            unsafe { ft.add_segment(arena.addr() + 96 * PAGE_SIZE_B, 100) },
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps an existing segment")]
+    fn overlapping_segments_are_rejected() {
+        let arena = Arena::new(64);
+        let mut ft = FrameTable {
+            segments: std::vec![arena.segment(0, 48)],
+        };
+
+        unsafe { ft.add_segment(arena.addr() + 40 * PAGE_SIZE_B, 24 * PAGE_SIZE_B) };
+    }
+}
@@ -0,0 +1,251 @@
+//!
+//! Frame table statistics
+//!
+//! Gives an overview of how physical memory is being used, and how
+//! fragmented the free memory is
+//!
+
+use alloc::vec::Vec;
+
+use super::{FrameSegment, FrameState, FrameTable};
+use crate::PAGE_SIZE_KB;
+
+///
+/// Number of frames in each `FrameState`
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameCounts {
+    counts: [usize; FrameState::ALL.len()],
+}
+
+impl FrameCounts {
+    pub fn get(&self, state: FrameState) -> usize {
+        self.counts[state as usize]
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    fn add(&mut self, other: &FrameCounts) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+    }
+}
+
+///
+/// Usage of a single frame segment
+///
+#[derive(Debug, Clone)]
+pub struct SegmentStats {
+    pub first_page_addr: usize,
+    pub page_count: usize,
+    pub counts: FrameCounts,
+
+    ///
+    /// The longest run of contiguous free frames, in frames
+    ///
+    pub largest_free_run: usize,
+}
+
+///
+/// Usage of the whole frame table
+///
+#[derive(Clone)]
+pub struct FrameTableStats {
+    pub segments: Vec<SegmentStats>,
+    pub totals: FrameCounts,
+
+    ///
+    /// The longest run of contiguous free frames in any segment, in frames
+    ///
+    pub largest_free_run: usize,
+}
+
+impl FrameTableStats {
+    ///
+    /// How fragmented the free memory is, in parts per thousand
+    ///
+    /// 0 means the free frames of every segment form a single run, values
+    /// close to 1000 mean the free memory is scattered into many small runs
+    ///
+    pub fn fragmentation_permille(&self) -> usize {
+        let free = self.totals.get(FrameState::Free);
+
+        if free == 0 {
+            return 0;
+        }
+
+        // Segments are never contiguous with each other, so the best case
+        // is one run per segment
+        let best_runs: usize = self.segments.iter().map(|s| s.largest_free_run).sum();
+
+        1000 - best_runs * 1000 / free
+    }
+
+    ///
+    /// Write the statistics to the kernel log
+    ///
+    pub fn log(&self) {
+        log::info!("Frame table: {} segments", self.segments.len());
+
+        for segment in &self.segments {
+            log::info!(
+                ">: Segment at {:#X}: {} frames, largest free run {} frames",
+                segment.first_page_addr,
+                segment.page_count,
+                segment.largest_free_run
+            );
+            log_counts(&segment.counts);
+        }
+
+        log::info!(">: Totals");
+        log_counts(&self.totals);
+        log::info!(
+            ">: Largest free run {} KiB, fragmentation {}.{}%",
+            self.largest_free_run * PAGE_SIZE_KB,
+            self.fragmentation_permille() / 10,
+            self.fragmentation_permille() % 10
+        );
+    }
+}
+
+fn log_counts(counts: &FrameCounts) {
+    for state in FrameState::ALL {
+        log::info!(
+            ">: :: {:<10} {:>8} frames ({} KiB)",
+            alloc::format!("{state:?}"),
+            counts.get(state),
+            counts.get(state) * PAGE_SIZE_KB
+        );
+    }
+}
+
+impl core::fmt::Debug for FrameTableStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "FrameTableStats {{")?;
+
+        for segment in &self.segments {
+            writeln!(
+                f,
+                "  segment {:#X} [{} frames, largest free run {}]",
+                segment.first_page_addr, segment.page_count, segment.largest_free_run
+            )?;
+        }
+
+        for state in FrameState::ALL {
+            writeln!(f, "  {:?}: {}", state, self.totals.get(state))?;
+        }
+
+        writeln!(
+            f,
+            "  largest free run: {}, fragmentation: {}/1000",
+            self.largest_free_run,
+            self.fragmentation_permille()
+        )?;
+
+        write!(f, "}}")
+    }
+}
+
+impl FrameTable {
+    ///
+    /// Gather usage statistics over every segment
+    ///
+    /// This walks the metadata of every frame
+    ///
+    pub fn stats(&self) -> FrameTableStats {
+        let segments: Vec<SegmentStats> = self.segments.iter().map(|s| s.stats()).collect();
+
+        let mut totals = FrameCounts::default();
+
+        for segment in &segments {
+            totals.add(&segment.counts);
+        }
+
+        FrameTableStats {
+            largest_free_run: segments
+                .iter()
+                .map(|s| s.largest_free_run)
+                .max()
+                .unwrap_or(0),
+            segments,
+            totals,
+        }
+    }
+}
+
+impl FrameSegment {
+    pub fn stats(&self) -> SegmentStats {
+        let mut counts = FrameCounts::default();
+        let mut largest_free_run = 0;
+        let mut run = 0;
+
+        for idx in 0..self.page_count {
+            let state = unsafe { self.get_metadata(idx) }.state();
+
+            counts.counts[state as usize] += 1;
+
+            if state == FrameState::Free {
+                run += 1;
+                largest_free_run = largest_free_run.max(run);
+            } else {
+                run = 0;
+            }
+        }
+
+        SegmentStats {
+            first_page_addr: self.first_page_addr,
+            page_count: self.page_count,
+            counts,
+            largest_free_run,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Arena;
+
+    #[test]
+    fn reports_usage_and_fragmentation() {
+        let arena = Arena::new(128);
+        let mut ft = FrameTable {
+            segments: std::vec![arena.segment(0, 64), arena.segment(64, 64)],
+        };
+
+        let fresh = ft.stats();
+        assert_eq!(fresh.fragmentation_permille(), 0);
+        assert_eq!(
+            fresh.largest_free_run,
+            ft.segments[0].page_count.max(ft.segments[1].page_count)
+        );
+
+        let pages: Vec<_> = (0..ft.segments[0].page_count)
+            .map(|_| ft.alloc_front(1, FrameState::User, 3).unwrap())
+            .collect();
+
+        // Free every other frame of the first segment
+        for page in pages.iter().step_by(2) {
+            ft.free(page.clone()).unwrap();
+        }
+
+        let stats = ft.stats();
+        let free_in_first = pages.len().div_ceil(2);
+
+        assert_eq!(
+            stats.segments[0].counts.get(FrameState::Free),
+            free_in_first
+        );
+        assert_eq!(stats.segments[0].largest_free_run, 1);
+        assert_eq!(stats.totals.get(FrameState::User), pages.len() / 2);
+        assert_eq!(
+            stats.totals.total(),
+            ft.segments[0].page_count + ft.segments[1].page_count
+        );
+        assert_eq!(stats.largest_free_run, ft.segments[1].page_count);
+        assert!(stats.fragmentation_permille() > 0);
+    }
+}
@@ -3,8 +3,18 @@
 use core::mem::MaybeUninit;
 extern crate alloc;
 
+#[cfg(test)]
+extern crate std;
+
+pub mod address_space;
 pub mod frame_table;
 pub mod page_table;
+pub mod phys;
+pub mod sync;
+pub mod tlb;
+
+#[cfg(test)]
+mod testing;
 
 
 
@@ -15,5 +25,9 @@ pub const PAGE_SIZE_KB : usize = 4;
 
 
 
-pub static mut KERNEL_FRAME_TABLE : MaybeUninit<frame_table::FrameTable> = MaybeUninit::zeroed();
+pub static KERNEL_FRAME_TABLE : frame_table::GlobalFrameTable = frame_table::GlobalFrameTable::new();
+pub static KERNEL_ASIDS : address_space::AsidAllocator = address_space::AsidAllocator::new();
+pub static KERNEL_HARTS : tlb::HartSet = tlb::HartSet::new();
+pub static KERNEL_VMALLOC : sync::SpinLock<page_table::vmalloc::Vmalloc> = sync::SpinLock::new(page_table::vmalloc::Vmalloc::new());
+pub static KERNEL_LAZY_REGIONS : sync::SpinLock<page_table::fault::LazyRegions> = sync::SpinLock::new(page_table::fault::LazyRegions::new());
 pub static mut KERNEL_PAGE_TABLE : MaybeUninit<page_table::PageTable> = MaybeUninit::zeroed();
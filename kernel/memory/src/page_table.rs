@@ -1,50 +1,179 @@
+pub mod aging;
 mod bootstrap;
+pub mod dump;
+pub mod extensions;
+pub mod fault;
+pub mod mode;
+pub mod physmap;
 pub mod virt_map;
+pub mod vmalloc;
 
-pub use bootstrap::bootstrap_pt;
+pub use bootstrap::{bootstrap_pt, pt_virt_start, SELF_REF_ROOT_INDEX};
+pub use mode::{paging_mode, set_paging_mode, PagingMode};
 
-use crate::frame_table::{self, FrameTable, MemoryAllocation};
+use crate::frame_table::{self, FrameTable, FreeError, MemoryAllocation};
+use crate::phys::phys_to_ptr;
 
 unsafe fn pte_pointer_as_mut_slice(pte_ptr: *mut PageTableEntry) -> &'static mut [PageTableEntry] {
     // assume 512 entries
     unsafe { core::slice::from_raw_parts_mut(pte_ptr, 512) }
 }
 
+///
+/// Flush the translation of `virt_addr` from this hart's TLB
+///
+pub fn sfence_vma(virt_addr: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("sfence.vma {}, zero", in(reg) virt_addr)
+    };
+
+    #[cfg(not(target_arch = "riscv64"))]
+    let _ = virt_addr;
+}
+
+///
+/// Flush every translation tagged with `asid` from this hart's TLB
+///
+/// Global mappings are kept
+///
+pub fn sfence_vma_asid(asid: u16) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("sfence.vma zero, {}", in(reg) asid as usize)
+    };
+
+    #[cfg(not(target_arch = "riscv64"))]
+    let _ = asid;
+}
+
+///
+/// Flush every translation from this hart's TLB
+///
+pub fn sfence_vma_all() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("sfence.vma zero, zero")
+    };
+}
+
 pub struct IntermediaryPageTableResult {
     pub page_table: PageTable,
     pub physical_starting_memory_address: usize,
     pub allocated: bool,
 }
 
+///
+/// Reasons a mapping could not be made
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    ///
+    /// The entry the mapping needs is already in use
+    ///
+    AlreadyMapped,
+
+    ///
+    /// The frame table has no frame left for a page or a page table
+    ///
+    OutOfFrames,
+
+    ///
+    /// A large page leaf sits where a table is needed
+    ///
+    HugePageConflict,
+
+    ///
+    /// The address is not aligned to a page boundary
+    ///
+    Misaligned { addr: usize },
+
+    ///
+    /// The table index or virtual page is past what the table can hold
+    ///
+    IndexOutOfRange { index: usize },
+
+    ///
+    /// No run of `pages` free virtual pages was found
+    ///
+    OutOfVirtualSpace { pages: usize },
+}
+
 pub struct PageTable {
     pub entries: &'static mut [PageTableEntry],
 }
 
 impl PageTable {
-    pub unsafe fn is_free(
-        &self,
-        l1_index: PageTableIndex,
-        l2_index: PageTableIndex,
-        l3_index: PageTableIndex,
-    ) -> bool {
-        let e1 = &self.entries[l1_index.as_addr()];
-
-        if e1.is_unused() {
-            true
-        } else {
-            let p2 = unsafe { Self::from_pointer(e1.phys_addr()) };
+    ///
+    /// Whether virtual page `virt_page` is not mapped yet
+    ///
+    pub unsafe fn is_free(&self, virt_page: usize) -> bool {
+        let parts = virt_map::decompose_virt_pageaddr(virt_page);
+        let mut entries: &[PageTableEntry] = self.entries;
 
-            let e2 = &p2.entries[l2_index.as_addr()];
+        for size in paging_mode().sizes() {
+            let entry = &entries[parts.index(size).as_addr()];
 
-            if e2.is_unused() {
-                true
-            } else {
-                let p3 = unsafe { Self::from_pointer(e2.phys_addr()) };
-                let e3 = &p3.entries[l3_index.as_addr()];
+            if entry.is_unused() {
+                return true;
+            }
 
-                if e3.is_unused() { true } else { false }
+            // A megapage or gigapage maps the page already
+            if entry.is_leaf() || size == PageSize::Page {
+                break;
             }
+
+            entries = unsafe { Self::from_pointer(entry.phys_addr()) }.entries;
         }
+
+        false
+    }
+
+    ///
+    /// Walk the table down to the leaf entry which maps `virt_addr`
+    ///
+    /// The walk goes through physical memory, so it works for any mapping in
+    /// the table, including the identity mappings and the self-ref section.
+    /// Returns `None` when the address isn't canonical or isn't mapped
+    ///
+    pub fn walk(&self, virt_addr: usize) -> Option<Translation> {
+        let mode = paging_mode();
+
+        if !mode.is_canonical(virt_addr) {
+            return None;
+        }
+
+        let mut entries: &[PageTableEntry] = self.entries;
+
+        for size in mode.sizes() {
+            let index = (virt_addr >> size.shift()) & 0x1FF;
+            let entry = entries[index];
+
+            if !entry.is_valid() {
+                return None;
+            }
+
+            if entry.is_leaf() {
+                return Some(Translation {
+                    entry,
+                    size,
+                    phys_addr: entry.leaf_phys_addr(mode.vpn(virt_addr))
+                        + (virt_addr & (size.bytes() - 1)),
+                    permissions: entry.0 & Translation::PERMISSIONS,
+                });
+            }
+
+            entries = unsafe { PageTable::from_pointer(entry.phys_addr()) }.entries;
+        }
+
+        None
+    }
+
+    ///
+    /// The physical address `virt_addr` maps to, if any
+    ///
+    pub fn translate(&self, virt_addr: usize) -> Option<usize> {
+        self.walk(virt_addr).map(|translation| translation.phys_addr)
     }
 
     ///
@@ -60,24 +189,28 @@ impl PageTable {
     pub unsafe fn from_pointer(pointer: usize) -> Self {
         // assume 512 entries
         let elements =
-            unsafe { core::slice::from_raw_parts_mut(pointer as *mut PageTableEntry, 512) };
+            unsafe { core::slice::from_raw_parts_mut(phys_to_ptr::<PageTableEntry>(pointer), 512) };
 
         Self { entries: elements }
     }
 
+    ///
+    /// Make sure entry `index` points at a table, allocating one if it is unused
+    ///
     pub unsafe fn allocate_intermediary(
         &mut self,
         index: PageTableIndex,
         frame_table: &mut FrameTable,
-    ) -> Result<IntermediaryPageTableResult, ()> {
+    ) -> Result<IntermediaryPageTableResult, MapError> {
         let e = &mut self.entries[index.as_addr()];
 
         if e.is_unused() {
             // Good to allocate
             let mem = frame_table
                 .alloc_front(1, crate::frame_table::FrameState::PageTable, 0)
-                .unwrap();
+                .ok_or(MapError::OutOfFrames)?;
 
+            unsafe { mem.zero() };
             let pt = unsafe { PageTable::from_pointer(mem.phys_addr) };
 
             e.set(mem.phys_addr as u64, PageTableEntry::FLAG_V);
@@ -95,127 +228,192 @@ impl PageTable {
                 physical_starting_memory_address: e.phys_addr(),
                 allocated: false,
             })
-        } else {
+        } else if e.is_leaf() {
             log::warn!("Failed to allocate intermediary as it is already allocated for mapping");
-            Err(())
+            Err(MapError::HugePageConflict)
+        } else {
+            Err(MapError::AlreadyMapped)
+        }
+    }
+
+    ///
+    /// Whether `bootstrap_pt` set up the self-ref section in this table
+    ///
+    pub fn has_self_ref(&self) -> bool {
+        let entry = self.entries[SELF_REF_ROOT_INDEX];
+        entry.is_valid() && !entry.is_leaf()
+    }
+
+    ///
+    /// The table of the self-ref section whose entries point at the tables
+    /// mapping page tables
+    ///
+    /// It sits beneath `SELF_REF_ROOT_INDEX` of the root, deeper modes add a
+    /// table at index 0 for every extra level
+    ///
+    unsafe fn self_ref_directory(&self) -> PageTable {
+        let mut table =
+            unsafe { PageTable::from_pointer(self.entries[SELF_REF_ROOT_INDEX].phys_addr()) };
+
+        for _ in 3..paging_mode().levels() {
+            table = unsafe { PageTable::from_pointer(table.entries[0].phys_addr()) };
         }
+
+        table
     }
 
     ///
-    /// This will allocate a page to be used as a page table
-    /// and also map this sub-table into the page table
-    /// at the self-ref address
+    /// Allocate a zeroed page to be used as a page table
+    ///
+    /// Page tables are reached through `phys_to_ptr`, the table is only
+    /// mapped at the self-ref address as well when this table has the
+    /// optional self-ref section
     ///
     /// Returns the physical address of the page
     ///
     pub unsafe fn meta_allocate_page_table(
         &mut self,
         frame_table: &mut FrameTable,
-    ) -> MemoryAllocation {
-        let self_ref_root_index = PageTableIndex::new(280);
-
+    ) -> Result<MemoryAllocation, MapError> {
         let allocation = frame_table
             .alloc_front(1, crate::frame_table::FrameState::PageTable, 0)
-            .unwrap();
+            .ok_or(MapError::OutOfFrames)?;
 
         unsafe { allocation.zero() };
 
-        let offset_from_base = allocation.phys_addr - frame_table.root_frame_address();
-        let pages_offset_from_base = offset_from_base >> 12;
-
-        // log::debug!(
-        //     "Meta-allocating new page on page table self-ref section at index #{pages_offset_from_base}"
-        // );
+        if !self.has_self_ref() {
+            return Ok(allocation);
+        }
 
-        let l1_index = (pages_offset_from_base >> 9) & 0x1FF;
-        let l2_index = (pages_offset_from_base) & 0x1FF;
+        if let Err(error) = unsafe { self.map_in_self_ref(frame_table, allocation.phys_addr) } {
+            frame_table
+                .free_addr(allocation.phys_addr, 1, 0)
+                .expect("Failed to hand back the page table frame");
+            return Err(error);
+        }
 
-        // log::debug!("Index at 280 > {l1_index} > {l2_index}");
+        Ok(allocation)
+    }
 
-        let l1_pt = unsafe {
-            PageTable::from_pointer(self.entries[self_ref_root_index.as_addr()].phys_addr())
+    ///
+    /// Map the page table at `phys_addr` into the self-ref section, at the
+    /// index of its frame counted from the first frame of the frame table
+    ///
+    /// The last level table of the section holding it is allocated if it
+    /// is missing. That table lands anywhere in memory, it is mapped into
+    /// the section too when the last level table for its own index is there
+    ///
+    unsafe fn map_in_self_ref(
+        &mut self,
+        frame_table: &mut FrameTable,
+        phys_addr: usize,
+    ) -> Result<(), MapError> {
+        let flags = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W;
+        let root_frame = frame_table.root_frame_address();
+        let window_index = |phys_addr: usize| {
+            let page = (phys_addr - root_frame) >> 12;
+            ((page >> 9) & 0x1FF, page & 0x1FF)
         };
-        // unsafe { self.allocate_intermediary(self_ref_root_index, frame_table) }.unwrap();
 
-        // assert!(
-        //     !l2.allocated,
-        //     "L2 SHOULD HAVE BEEN ALLOCATED BY BOOTSTRAP - SOMETHING IS WRONG"
-        // );
+        let (l1_index, l2_index) = window_index(phys_addr);
+        let directory = unsafe { self.self_ref_directory() };
 
-        let l2_ref = &mut l1_pt.entries[l1_index];
+        if directory.entries[l1_index].is_leaf() {
+            return Err(MapError::HugePageConflict);
+        }
 
-        if l2_ref.is_unused() {
-            // We are allocating new intermediary table
+        if directory.entries[l1_index].is_unused() {
             let intermediary = frame_table
                 .alloc_front(1, crate::frame_table::FrameState::PageTable, 0)
-                .unwrap();
+                .ok_or(MapError::OutOfFrames)?;
 
             unsafe { intermediary.zero() };
 
-            l2_ref.set(intermediary.phys_addr as u64, PageTableEntry::FLAG_V);
+            directory.entries[l1_index].set(intermediary.phys_addr as u64, PageTableEntry::FLAG_V);
 
-            let intermediary_pt = unsafe { PageTable::from_pointer(intermediary.phys_addr) };
+            let (own_l1, own_l2) = window_index(intermediary.phys_addr);
+            let own_table = directory.entries[own_l1];
 
-            assert_eq!(
-                l2_index, 0,
-                "L3 index should be 0, otherwise a core assumption is incorrect"
-            );
+            if own_table.is_valid() && !own_table.is_leaf() {
+                let table = unsafe { PageTable::from_pointer(own_table.phys_addr()) };
+                table.entries[own_l2].set(intermediary.phys_addr as u64, flags);
+            }
+        }
 
-            // Intermediary should be exactly 4096 bytes after allocation
-            assert_eq!(
-                allocation.phys_addr + 4096,
-                intermediary.phys_addr,
-                "Intermediary should come immediately after leaf allocation"
-            );
+        let table = unsafe { PageTable::from_pointer(directory.entries[l1_index].phys_addr()) };
+        let entry = &mut table.entries[l2_index];
 
-            intermediary_pt.entries[0].set(
-                allocation.phys_addr as u64,
-                PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W,
-            );
+        if !entry.is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
 
-            intermediary_pt.entries[1].set(intermediary.phys_addr as u64, PageTableEntry::FLAG_V);
+        entry.set(phys_addr as u64, flags);
 
-            // log::debug!("Successfully self-mapped page table entry with new-mapped l2");
-            // log::debug!("Intermediary created at memaddr: {:#X?}", intermediary.phys_addr);
+        Ok(())
+    }
 
-            allocation
-        } else {
-            // Best case - allocate within existing l2
-            let l2_pt = unsafe { PageTable::from_pointer(l2_ref.phys_addr()) };
+    ///
+    /// Undo `meta_allocate_page_table`, removing the page table at `phys_addr`
+    /// from the self-ref section if there is one and freeing its frame
+    ///
+    /// # Safety
+    ///
+    /// The page table must no longer be referenced by any entry, nor cached
+    /// by any hart
+    ///
+    pub unsafe fn meta_free_page_table(
+        &mut self,
+        frame_table: &mut FrameTable,
+        phys_addr: usize,
+    ) -> Result<(), FreeError> {
+        unsafe { self.meta_unlink_page_table(frame_table, phys_addr) };
+
+        frame_table.free_addr(phys_addr, 1, 0)
+    }
 
-            let entry = &mut l2_pt.entries[l2_index];
+    ///
+    /// Remove the page table at `phys_addr` from the self-ref section if
+    /// there is one, leaving its frame allocated
+    ///
+    /// The frame can be freed once every hart let go of the table, see
+    /// `TlbFlush::add_table`
+    ///
+    /// # Safety
+    ///
+    /// The page table must no longer be referenced by any entry
+    ///
+    pub unsafe fn meta_unlink_page_table(&mut self, frame_table: &FrameTable, phys_addr: usize) {
+        if !self.has_self_ref() {
+            return;
+        }
 
-            assert!(entry.is_unused(), "Target PTE is unused");
+        let pages_offset_from_base = (phys_addr - frame_table.root_frame_address()) >> 12;
 
-            entry.set(
-                allocation.phys_addr as u64,
-                PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W,
-            );
+        let l1_index = (pages_offset_from_base >> 9) & 0x1FF;
+        let l2_index = (pages_offset_from_base) & 0x1FF;
 
-            // log::debug!("Successfully self-mapped page table entry");
-            allocation
+        let l1_pt = unsafe { self.self_ref_directory() };
+
+        let l2_ref = l1_pt.entries[l1_index];
+
+        if l2_ref.is_valid() && !l2_ref.is_leaf() {
+            let l2_pt = unsafe { PageTable::from_pointer(l2_ref.phys_addr()) };
+            l2_pt.entries[l2_index].clear();
         }
     }
 
     ///
     /// Create a new allocation within the page table
     ///
+    /// Fails with `MapError::OutOfVirtualSpace` when no last level table has
+    /// `page_count` consecutive free entries
     ///
     pub fn create_allocation_pages(
         &mut self,
         page_count: usize,
         frame_table: &mut FrameTable,
         flags: u64,
-    ) -> Result<usize, ()> {
-        assert!(
-            page_count <= 512,
-            "Current allocator can only allocate up to 512 pages at a time (l1 max)"
-        );
-
-        if page_count > 512 {
-            panic!()
-        }
-
+    ) -> Result<usize, MapError> {
         // TODO: Enable cross-level allocations
         //
         // For now, we just allocate consecutive blocks
@@ -223,135 +421,151 @@ impl PageTable {
         //
         // We should also potentially randomize allocations a bit
         //
-        // and prioritize allocating within existing tables over making new tables when possible
-        //
         // theres lots of parameters here that must be investigated
         //
         // for now, we just linearly allocate
         //
         // so the max allocation size is
         // 4KiB * 512 = 2MiB
-        //
-        // TODO: Also emit failure reasons
-
-        // L3 Loop
-        for (l3_index, e) in self.entries.iter_mut().enumerate() {
-            match e.next_level(frame_table) {
-                Some(l2_table) => {
-                    // L2 Loop
-
-                    for (l2_index, e) in l2_table.entries.iter_mut().enumerate() {
-                        match e.next_level(frame_table) {
-                            Some(l1_table) => {
-                                // find page_count consecutive free entries
-                                let earliest_free_segment =
-                                    l1_table.first_free_cells_accommodating(page_count);
-
-                                if let Some(l1_index) = earliest_free_segment {
-                                    let pages_to_allocate =
-                                        &mut l1_table.entries[l1_index..l1_index + page_count];
-
-                                    let frames = frame_table.alloc_back(page_count, crate::frame_table::FrameState::Kernel, 0).expect("Failed to allocate backing page memory from frame table");
-
-                                    assert_eq!(
-                                        frames.page_count,
-                                        pages_to_allocate.len(),
-                                        "PTE count and frame allocation length dont match"
-                                    );
-                                    for (index, page) in pages_to_allocate.iter_mut().enumerate() {
-                                        let physical_page_address =
-                                            frames.phys_addr + crate::PAGE_SIZE_B * index;
-                                        page.set(physical_page_address as u64, flags);
-                                    }
-
-                                    // We now have a full allocation, just determine the virtual
-                                    // address now
-                                    //
-                                    // SIGN | VPN[2] | VPN[1] | VPN[0] | OFFSET
-                                    //  25  |   9    |   9    |   9    |  12
-                                    //
-
-                                    let vpn_index = l1_index | (l2_index << 9) | (l3_index << 18);
-
-                                    // Zero offset
-                                    let vpn_index = vpn_index << 12;
-
-                                    // Sign extension logic
-                                    const S_EXT : usize = 0b1111111111111111111111111000000000000000000000000000000000000000;
-                                    const EXT: [usize; 2] = [0, S_EXT];
-
-                                    let extend = EXT[l3_index >> 8];
-
-                                    let vpn_index = vpn_index | extend;
-
-                                    return Ok(vpn_index);
-                                } else {
-                                    continue;
-                                }
-                            }
-                            None => {
-                                continue;
-                            }
-                        }
-                    }
-                }
-                None => {
-                    // Scan next
-                    continue;
-                }
-            }
+
+        let mode = paging_mode();
+
+        if page_count > self.entries.len() {
+            return Err(MapError::OutOfVirtualSpace { pages: page_count });
         }
 
-        Err(())
+        self.allocate_pages_below(mode.levels() - 1, 0, page_count, frame_table, flags)
+            .map(|virt_page| mode.canonical(virt_page << 12))
     }
 
-    pub unsafe fn virtually_map(
+    ///
+    /// Find `page_count` consecutive free entries in a last level table beneath
+    /// this table, which sits at `level` and maps from virtual page `base`, and
+    /// back them with fresh frames
+    ///
+    /// The tables already in place are tried first, a new one is only made
+    /// when none of them has room
+    ///
+    /// Returns the first virtual page of the allocation
+    ///
+    fn allocate_pages_below(
         &mut self,
+        level: usize,
+        base: usize,
+        page_count: usize,
         frame_table: &mut FrameTable,
-        l0_index: PageTableIndex,
-        l1_index: PageTableIndex,
-        l2_index: PageTableIndex,
-        hardware_address: usize,
         flags: u64,
-    ) {
-        let l0_entry = self.entries[l0_index.as_addr()];
+    ) -> Result<usize, MapError> {
+        let not_found = MapError::OutOfVirtualSpace { pages: page_count };
 
-        let l1_table = if l0_entry.kind() == PTEKind::NextLevel && l0_entry.phys_addr() != 0 {
-            unsafe { PageTable::from_pointer(l0_entry.phys_addr()) }
-        } else if l0_entry.kind() == PTEKind::NextLevel && l0_entry.phys_addr() == 0 {
-            let raw_pt = unsafe { self.meta_allocate_page_table(frame_table) };
-            let pt = unsafe { PageTable::from_pointer(raw_pt.phys_addr) };
+        if level == 0 {
+            // find page_count consecutive free entries
+            let l1_index = self
+                .first_free_cells_accommodating(page_count)
+                .ok_or(not_found)?;
 
-            self.entries[l0_index.as_addr()].set(raw_pt.phys_addr as u64, PageTableEntry::FLAG_V);
+            let pages_to_allocate = &mut self.entries[l1_index..l1_index + page_count];
 
-            pt
-        } else {
-            panic!()
+            let frames = frame_table
+                .alloc_back(page_count, crate::frame_table::FrameState::Kernel, 0)
+                .ok_or(MapError::OutOfFrames)?;
+
+            assert_eq!(
+                frames.page_count,
+                pages_to_allocate.len(),
+                "PTE count and frame allocation length dont match"
+            );
+            for (index, page) in pages_to_allocate.iter_mut().enumerate() {
+                let physical_page_address = frames.phys_addr + crate::PAGE_SIZE_B * index;
+                page.set(physical_page_address as u64, flags);
+            }
+
+            return Ok(base | l1_index);
+        }
+
+        // The self-ref section is never handed out
+        let reserved = |index: usize| {
+            level == paging_mode().levels() - 1 && index == SELF_REF_ROOT_INDEX
         };
 
-        let l1_entry = l1_table.entries[l1_index.as_addr()];
+        for (index, e) in self.entries.iter().enumerate() {
+            if reserved(index) {
+                continue;
+            }
 
-        let l2_table = if l1_entry.kind() == PTEKind::NextLevel && l1_entry.phys_addr() != 0 {
-            unsafe { PageTable::from_pointer(l1_entry.phys_addr()) }
-        } else if l1_entry.kind() == PTEKind::NextLevel && l1_entry.phys_addr() == 0 {
-            let raw_pt = unsafe { self.meta_allocate_page_table(frame_table) };
-            let pt = unsafe { PageTable::from_pointer(raw_pt.phys_addr) };
+            let Some(mut next) = e.next_level() else {
+                // Scan next
+                continue;
+            };
+
+            let found = next.allocate_pages_below(
+                level - 1,
+                base | (index << (9 * level)),
+                page_count,
+                frame_table,
+                flags,
+            );
 
-            l1_table.entries[l1_index.as_addr()].set(raw_pt.phys_addr as u64, PageTableEntry::FLAG_V);
+            if found != Err(not_found) {
+                return found;
+            }
+        }
 
-            pt
-        } else {
-            panic!()
-        };
+        let index = (0..self.entries.len())
+            .find(|&index| !reserved(index) && self.entries[index].is_unused())
+            .ok_or(not_found)?;
 
-        let l2_entry = l2_table.entries[l2_index.as_addr()];
+        let table_index = PageTableIndex::new(index as u16);
+        let mut next = unsafe { self.allocate_intermediary(table_index, frame_table) }?;
 
-        if l2_entry.is_unused() {
-            // Set it
-            l2_table.entries[l2_index.as_addr()].set(hardware_address as u64, flags);
-            let k = l2_table.entries[l2_index.as_addr()].kind();
-        } else {
-            panic!()
+        let found = next.page_table.allocate_pages_below(
+            level - 1,
+            base | (index << (9 * level)),
+            page_count,
+            frame_table,
+            flags,
+        );
+
+        if found.is_err() {
+            self.entries[index].clear();
+            frame_table
+                .free_addr(next.physical_starting_memory_address, 1, 0)
+                .expect("allocate_pages_below: failed to free a new table");
+        }
+
+        found
+    }
+
+    ///
+    /// Map virtual page `virt_page` to the frame at `hardware_address`,
+    /// allocating the tables on the way as needed
+    ///
+    pub unsafe fn virtually_map(
+        &mut self,
+        frame_table: &mut FrameTable,
+        virt_page: usize,
+        hardware_address: usize,
+        flags: u64,
+    ) -> Result<(), MapError> {
+        if hardware_address & (crate::PAGE_SIZE_B - 1) != 0 {
+            return Err(MapError::Misaligned {
+                addr: hardware_address,
+            });
+        }
+
+        if virt_page >= paging_mode().page_count() {
+            return Err(MapError::IndexOutOfRange { index: virt_page });
+        }
+
+        unsafe {
+            virt_map::map_leaf(
+                self,
+                frame_table,
+                virt_page,
+                hardware_address,
+                PageSize::Page,
+                flags,
+            )
         }
     }
 
@@ -362,11 +576,11 @@ impl PageTable {
         let mut current_run_length = 0usize;
 
         for (i, page) in self.entries.iter().enumerate() {
-            if matches!(page.kind(), PTEKind::NextLevel) {
+            if page.is_unused() {
                 current_run_length += 1;
 
                 if current_run_length == page_count {
-                    return Some(i - current_run_length);
+                    return Some(i + 1 - current_run_length);
                 }
             } else {
                 current_run_length = 0;
@@ -377,6 +591,129 @@ impl PageTable {
     }
 }
 
+///
+/// The amount of memory a leaf entry maps, by the level it sits at
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    ///
+    /// 4KiB, a leaf in the last level
+    ///
+    Page,
+
+    ///
+    /// 2MiB megapage
+    ///
+    Mega,
+
+    ///
+    /// 1GiB gigapage, a leaf in the root table of Sv39
+    ///
+    Giga,
+
+    ///
+    /// 512GiB terapage, a leaf in the root table of Sv48
+    ///
+    Tera,
+
+    ///
+    /// 256TiB petapage, a leaf in the root table of Sv57
+    ///
+    Peta,
+}
+
+impl PageSize {
+    pub fn from_level(level: usize) -> Self {
+        match level {
+            0 => PageSize::Page,
+            1 => PageSize::Mega,
+            2 => PageSize::Giga,
+            3 => PageSize::Tera,
+            4 => PageSize::Peta,
+            _ => panic!("no paging mode has {} levels", level + 1),
+        }
+    }
+
+    ///
+    /// The level of the tables holding leaves of this size, 0 being the last
+    ///
+    pub fn level(&self) -> usize {
+        *self as usize
+    }
+
+    ///
+    /// The size of the leaves in the table a leaf of this size is split into
+    ///
+    pub fn smaller(&self) -> Option<Self> {
+        self.level().checked_sub(1).map(Self::from_level)
+    }
+
+    ///
+    /// The bit position of the virtual page number indexing this level
+    ///
+    pub fn shift(&self) -> usize {
+        12 + 9 * self.level()
+    }
+
+    pub fn bytes(&self) -> usize {
+        1 << self.shift()
+    }
+
+    ///
+    /// Number of 4KiB pages a leaf of this size spans
+    ///
+    pub fn pages(&self) -> usize {
+        1 << (9 * self.level())
+    }
+}
+
+///
+/// The result of walking a page table for a virtual address
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    ///
+    /// The leaf entry mapping the address
+    ///
+    pub entry: PageTableEntry,
+
+    pub size: PageSize,
+
+    ///
+    /// The physical address the virtual address maps to, including its page offset
+    ///
+    pub phys_addr: usize,
+
+    ///
+    /// The `R`, `W`, `X`, `U` and `G` bits of the leaf
+    ///
+    pub permissions: u64,
+}
+
+impl Translation {
+    pub const PERMISSIONS: u64 = PageTableEntry::FLAG_R
+        | PageTableEntry::FLAG_W
+        | PageTableEntry::FLAG_X
+        | PageTableEntry::FLAG_U
+        | PageTableEntry::FLAG_G;
+
+    pub fn is_readable(&self) -> bool {
+        self.permissions & PageTableEntry::FLAG_R != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.permissions & PageTableEntry::FLAG_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.permissions & PageTableEntry::FLAG_X != 0
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.permissions & PageTableEntry::FLAG_U != 0
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PTEKind {
     NextLevel,
@@ -401,7 +738,7 @@ pub enum PTEKind {
     _Reserved2,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct PageTableEntry(pub u64);
 
@@ -452,6 +789,39 @@ impl PageTableEntry {
     ///
     pub const FLAG_D: u64 = 1 << 7;
 
+    ///
+    /// Is this memory shared copy-on-write
+    ///
+    /// the first of the bits left to software, the leaf is
+    /// read-only until a store gives it a frame of its own
+    ///
+    pub const FLAG_COW: u64 = 1 << 8;
+
+    ///
+    /// Non-cacheable main memory, with Svpbmt
+    ///
+    pub const PBMT_NC: u64 = 1 << 61;
+
+    ///
+    /// Strongly ordered I/O memory, with Svpbmt
+    ///
+    pub const PBMT_IO: u64 = 2 << 61;
+
+    pub const PBMT_MASK: u64 = 3 << 61;
+
+    ///
+    /// Is this leaf part of a naturally aligned 64KiB run, with Svnapot
+    ///
+    /// all 16 entries of the run are the same, with the low bits
+    /// of the PPN set to `0b1000`
+    ///
+    pub const FLAG_N: u64 = 1 << 63;
+
+    ///
+    /// Every bit which is not part of the PPN
+    ///
+    pub const FLAGS: u64 = 0x3FF | Self::PBMT_MASK | Self::FLAG_N;
+
     pub fn is_valid(&self) -> bool {
         self.0 & Self::FLAG_V != 0
     }
@@ -460,6 +830,27 @@ impl PageTableEntry {
         self.0 & (Self::FLAG_R | Self::FLAG_W | Self::FLAG_X) != 0
     }
 
+    pub fn is_cow(&self) -> bool {
+        self.is_leaf() && self.0 & Self::FLAG_COW != 0
+    }
+
+    pub fn is_napot(&self) -> bool {
+        self.is_leaf() && self.0 & Self::FLAG_N != 0
+    }
+
+    ///
+    /// The physical address this leaf maps virtual page `vpn` to, which is
+    /// the start of the leaf for anything but NAPOT runs
+    ///
+    pub fn leaf_phys_addr(&self, vpn: usize) -> usize {
+        if !self.is_napot() {
+            return self.phys_addr();
+        }
+
+        let run = extensions::NAPOT_PAGES * 4096;
+        (self.phys_addr() & !(run - 1)) + (vpn % extensions::NAPOT_PAGES) * 4096
+    }
+
     pub fn phys_addr(&self) -> usize {
         let ppn = (self.0 >> 10) & 0xFFFFFFFFFFF;
         (ppn << 12) as usize
@@ -498,6 +889,7 @@ impl PageTableEntry {
             "Physical address too large for PPN field: {:#x}",
             phys_addr
         );
+        assert_eq!(flags & !Self::FLAGS, 0, "flags overlap the PPN field: {flags:#x}");
 
         self.0 = (ppn << 10) | flags;
     }
@@ -521,10 +913,10 @@ impl PageTableEntry {
         self.0 |= ((r as u64) << 1) | ((w as u64) << 2) | ((x as u64) << 3);
     }
 
-    pub fn next_level(
-        &self,
-        frame_table: &mut crate::frame_table::FrameTable,
-    ) -> Option<PageTable> {
+    ///
+    /// The table this entry points at, if it points at one
+    ///
+    pub fn next_level(&self) -> Option<PageTable> {
         let is_nextlevel = matches!(self.kind(), PTEKind::NextLevel);
         let has_value = self.phys_addr() != 0;
 
@@ -540,21 +932,9 @@ impl PageTableEntry {
                 Some(pt)
             }
             (true, false) => {
-                // Allocate next level and return it
-
-                let next_level =
-                    frame_table.alloc_front(1, crate::frame_table::FrameState::PageTable, 0);
-
-                match next_level {
-                    Some(allocation) => {
-                        let pt = unsafe { PageTable::from_pointer(allocation.phys_addr) };
-                        Some(pt)
-                    }
-                    None => {
-                        // Cascade the failure
-                        None
-                    }
-                }
+                // Unused, tables are only ever allocated by their owner
+                // which installs them
+                None
             }
             (false, true) => {
                 // This is a non-standard page, dont touch
@@ -579,8 +959,17 @@ pub struct PageTableIndex(u16);
 
 impl PageTableIndex {
     pub fn new(val: u16) -> Self {
-        assert!(val <= 512, "Value within bounds");
-        Self(val)
+        Self::try_new(val).expect("Value within bounds")
+    }
+
+    pub fn try_new(val: u16) -> Result<Self, MapError> {
+        if val >= 512 {
+            return Err(MapError::IndexOutOfRange {
+                index: val as usize,
+            });
+        }
+
+        Ok(Self(val))
     }
 
     pub fn as_addr(&self) -> usize {
@@ -593,3 +982,215 @@ impl From<PageTableIndex> for usize {
         value.0 as usize
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_table::FrameState;
+    use crate::testing::{Arena, bootstrapped};
+
+    #[test]
+    fn entries_round_trip() {
+        let mut e = PageTableEntry(0);
+        assert!(e.is_unused());
+
+        e.set(0x8020_3000, PageTableEntry::FLAG_V | PageTableEntry::FLAG_R);
+        assert!(e.is_valid() && e.is_leaf());
+        assert_eq!(e.phys_addr(), 0x8020_3000);
+        assert_eq!(e.kind(), PTEKind::ReadOnly);
+
+        for kind in [
+            PTEKind::NextLevel,
+            PTEKind::ReadOnly,
+            PTEKind::ReadWrite,
+            PTEKind::ExecOnly,
+            PTEKind::ReadExecute,
+            PTEKind::ReadWriteExecute,
+        ] {
+            e.set_kind(kind);
+            assert_eq!(e.kind(), kind);
+            assert_eq!(e.phys_addr(), 0x8020_3000);
+        }
+
+        e.clear();
+        assert!(e.is_unused());
+    }
+
+    #[test]
+    fn intermediaries_are_reused() {
+        let arena = Arena::at(0x8000_0000, 16);
+        let mut ft = arena.frame_table();
+
+        let root = ft.alloc_front(1, FrameState::PageTable, 0).unwrap();
+        unsafe { root.zero() };
+        let mut pt = PageTable {
+            entries: unsafe { root.as_slice() },
+        };
+
+        let index = PageTableIndex::new(5);
+        let first = unsafe { pt.allocate_intermediary(index, &mut ft) }.unwrap();
+        let again = unsafe { pt.allocate_intermediary(index, &mut ft) }.unwrap();
+
+        assert!(first.allocated && !again.allocated);
+        assert_eq!(
+            first.physical_starting_memory_address,
+            again.physical_starting_memory_address
+        );
+        assert_eq!(pt.entries[5].phys_addr(), first.physical_starting_memory_address);
+
+        // Loop the intermediary back to the root, so the root is the last level
+        let l0 = PageTableIndex::new(0);
+        let looped = 5 << 18 | 5;
+        unsafe {
+            assert!(pt.is_free(looped));
+            first.page_table.entries[0].set(root.phys_addr as u64, PageTableEntry::FLAG_V);
+            assert!(pt.is_free(5 << 18));
+            assert!(!pt.is_free(looped));
+        }
+
+        // Leaf entries can't be used as intermediaries
+        let leaf = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R;
+        unsafe { pt.make_mapping(l0, 0x8000_0000, leaf) };
+        assert!(unsafe { !pt.is_free(3) });
+        assert!(matches!(
+            unsafe { pt.allocate_intermediary(l0, &mut ft) },
+            Err(MapError::HugePageConflict)
+        ));
+        assert!(matches!(
+            PageTableIndex::try_new(512),
+            Err(MapError::IndexOutOfRange { index: 512 })
+        ));
+    }
+
+    #[test]
+    fn walks_every_leaf_size() {
+        let arena = Arena::at(0x8000_0000, 64);
+        let mut ft = arena.frame_table();
+
+        let root = ft.alloc_front(1, FrameState::PageTable, 0).unwrap();
+        unsafe { root.zero() };
+        let mut pt = PageTable {
+            entries: unsafe { root.as_slice() },
+        };
+
+        let rw = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W;
+        let rx_user = PageTableEntry::FLAG_V
+            | PageTableEntry::FLAG_R
+            | PageTableEntry::FLAG_X
+            | PageTableEntry::FLAG_U;
+
+        // An identity mapped gigapage, and a page and megapage beneath entry 3
+        unsafe { pt.make_mapping(PageTableIndex::new(2), 0x8000_0000, rw) };
+
+        let mid = unsafe { pt.allocate_intermediary(PageTableIndex::new(3), &mut ft) }.unwrap();
+        let mut mid_pt = mid.page_table;
+        unsafe { mid_pt.make_mapping(PageTableIndex::new(4), 0x4000_0000, rx_user) };
+
+        let mut last = unsafe { mid_pt.allocate_intermediary(PageTableIndex::new(5), &mut ft) }
+            .unwrap()
+            .page_table;
+        unsafe { last.make_mapping(PageTableIndex::new(6), 0x8001_2000, rw) };
+
+        let giga = pt.walk(0x8123_4567).unwrap();
+        assert_eq!(giga.size, PageSize::Giga);
+        assert_eq!(giga.phys_addr, 0x8123_4567);
+        assert!(giga.is_readable() && giga.is_writable() && !giga.is_user());
+
+        let mega_va = (3 << 30) | (4 << 21) | 0x1_2345;
+        let mega = pt.walk(mega_va).unwrap();
+        assert_eq!(mega.size, PageSize::Mega);
+        assert_eq!(mega.phys_addr, 0x4001_2345);
+        assert!(mega.is_executable() && mega.is_user() && !mega.is_writable());
+
+        let page_va = (3 << 30) | (5 << 21) | (6 << 12) | 0xABC;
+        let page = pt.walk(page_va).unwrap();
+        assert_eq!(page.size, PageSize::Page);
+        assert_eq!(page.entry, last.entries[6]);
+        assert_eq!(pt.translate(page_va), Some(0x8001_2ABC));
+
+        // Holes, and addresses which aren't sign extended, don't translate
+        assert_eq!(pt.translate(page_va + 4096), None);
+        assert_eq!(pt.translate(1 << 38 | 0x8000_0000), None);
+        assert_eq!(pt.translate(0x8000_0000 | 1 << 39), None);
+    }
+
+    #[test]
+    fn tables_are_allocated_without_the_self_ref_section() {
+        let arena = Arena::at(0x8000_0000, 16);
+        let mut ft = arena.frame_table();
+
+        let root = ft.alloc_front(1, FrameState::PageTable, 0).unwrap();
+        unsafe { root.zero() };
+        let mut pt = PageTable {
+            entries: unsafe { root.as_slice() },
+        };
+        assert!(!pt.has_self_ref());
+
+        let table = unsafe { pt.meta_allocate_page_table(&mut ft) }.unwrap();
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), 2);
+        assert!(pt.entries.iter().all(|entry| entry.is_unused()));
+
+        // The table is reached through the physical memory offset alone
+        let entries = unsafe { PageTable::from_pointer(table.phys_addr) }.entries;
+        assert!(entries.iter().all(|entry| entry.is_unused()));
+
+        unsafe { pt.meta_free_page_table(&mut ft, table.phys_addr) }.unwrap();
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), 1);
+    }
+
+    #[test]
+    fn walks_follow_the_self_ref_section() {
+        let arena = Arena::at(0x8000_0000, 64);
+        let mut ft = arena.frame_table();
+
+        let (mut pt, root) = bootstrapped(&mut ft);
+        let table = unsafe { pt.meta_allocate_page_table(&mut ft) }.unwrap();
+
+        for phys_addr in [root, table.phys_addr] {
+            let virt_addr = pt_virt_start() + (phys_addr - ft.root_frame_address());
+
+            let translation = pt.walk(virt_addr + 8).unwrap();
+            assert_eq!(translation.phys_addr, phys_addr + 8);
+            assert_eq!(translation.size, PageSize::Page);
+            assert_eq!(
+                translation.permissions,
+                PageTableEntry::FLAG_R | PageTableEntry::FLAG_W
+            );
+        }
+    }
+
+    #[test]
+    fn allocations_fill_the_slots_after_used_ones() {
+        let arena = Arena::at(0x8000_0000, 16);
+        let mut ft = arena.frame_table();
+
+        let root = ft.alloc_front(1, FrameState::PageTable, 0).unwrap();
+        unsafe { root.zero() };
+        let mut pt = PageTable {
+            entries: unsafe { root.as_slice() },
+        };
+
+        let rw = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W;
+        let first = pt.create_allocation_pages(1, &mut ft, rw).unwrap();
+        let tables = ft.count_frames(0, FrameState::PageTable);
+        assert_eq!(tables, paging_mode().levels());
+
+        // The run starts right after the page in use, in the same tables
+        let run = pt.create_allocation_pages(3, &mut ft, rw).unwrap();
+        assert_eq!(run, first + 4096);
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+
+        let first_frame = pt.translate(first).unwrap();
+        for page in 0..3 {
+            let frame = pt.translate(run + page * 4096).unwrap();
+            assert_ne!(frame, first_frame);
+        }
+
+        // Runs which no table can hold leave nothing behind
+        assert_eq!(
+            pt.create_allocation_pages(513, &mut ft, rw),
+            Err(MapError::OutOfVirtualSpace { pages: 513 })
+        );
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+    }
+}
@@ -0,0 +1,258 @@
+//!
+//! Page aging
+//!
+//! Reclaim needs to know which user frames are in use. A scan clears and
+//! samples the `A` bit of every user page, one `PageAging::sample` per
+//! address space, then `PageAging::finish` sorts the frames seen into two
+//! lists. Frames accessed since the previous scan are active, the others
+//! inactive. Active frames which went a whole scan without an access join
+//! the back of the inactive list, so its front holds the frames which have
+//! gone unused the longest, the first to reclaim.
+//!
+//! Whether a frame was accessed, and whether it is active, is kept in its
+//! metadata flags. Hardware which leaves `A` to software faults on the
+//! first access after the bit is cleared, `fault::mark_accessed` sets it
+//! again
+//!
+
+use alloc::vec::Vec;
+
+use crate::PAGE_SIZE_B;
+use crate::frame_table::{FrameMetadataEntry, FrameState, FrameTable};
+use crate::tlb::TlbFlush;
+
+use super::PageTable;
+use super::virt_map;
+
+///
+/// The user frames of a scan, by physical address
+///
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AgingLists {
+    ///
+    /// Frames accessed since the previous scan
+    ///
+    pub active: Vec<usize>,
+
+    ///
+    /// Frames which were not, the longest unused first
+    ///
+    pub inactive: Vec<usize>,
+}
+
+///
+/// A scan of the accessed bits of user pages
+///
+#[derive(Debug, Default)]
+pub struct PageAging {
+    ///
+    /// Every user frame sampled so far, shared frames may show up more than
+    /// once
+    ///
+    seen: Vec<usize>,
+}
+
+impl PageAging {
+    pub const fn new() -> Self {
+        Self { seen: Vec::new() }
+    }
+
+    ///
+    /// Sample the user pages among the `count` virtual pages starting at
+    /// `virtual_start_addr`, marking the user frames behind pages which were
+    /// accessed as referenced
+    ///
+    /// Returns the number of 4KiB pages sampled, the pages which lost their
+    /// `A` bit are added to `tlb`
+    ///
+    /// # Safety
+    ///
+    /// Same as `virt_map::sample_accessed`
+    ///
+    pub unsafe fn sample(
+        &mut self,
+        page_table: &mut PageTable,
+        frame_table: &mut FrameTable,
+        virtual_start_addr: usize,
+        count: usize,
+        tlb: &mut TlbFlush,
+    ) -> usize {
+        let mut leaves = Vec::new();
+
+        let sampled = unsafe {
+            virt_map::sample_accessed(
+                page_table,
+                virtual_start_addr,
+                count,
+                tlb,
+                &mut |region, accessed| leaves.push((region, accessed)),
+            )
+        };
+
+        for (region, accessed) in leaves {
+            for page in 0..region.count {
+                let phys_addr = region.address + page * PAGE_SIZE_B;
+
+                // Device memory and kernel frames mapped to user space aren't
+                // for reclaim to take
+                let Some(meta) = user_frame(frame_table, phys_addr) else {
+                    continue;
+                };
+
+                if accessed {
+                    meta.set_flags(meta.flags() | FrameMetadataEntry::FLAG_REFERENCED);
+                }
+
+                self.seen.push(phys_addr);
+            }
+        }
+
+        sampled
+    }
+
+    ///
+    /// Sort every frame sampled since the last call into the active and
+    /// inactive lists, and start the next scan
+    ///
+    /// A frame shared between address spaces is active when any of them
+    /// accessed it. Frames freed since they were sampled are left out
+    ///
+    pub fn finish(&mut self, frame_table: &FrameTable) -> AgingLists {
+        self.seen.sort_unstable();
+        self.seen.dedup();
+
+        let mut lists = AgingLists::default();
+        let mut demoted = Vec::new();
+
+        for phys_addr in self.seen.drain(..) {
+            let Some(meta) = user_frame(frame_table, phys_addr) else {
+                continue;
+            };
+
+            let flags = meta.flags();
+            let others =
+                flags & !(FrameMetadataEntry::FLAG_REFERENCED | FrameMetadataEntry::FLAG_ACTIVE);
+
+            if flags & FrameMetadataEntry::FLAG_REFERENCED != 0 {
+                meta.set_flags(others | FrameMetadataEntry::FLAG_ACTIVE);
+                lists.active.push(phys_addr);
+            } else if flags & FrameMetadataEntry::FLAG_ACTIVE != 0 {
+                meta.set_flags(others);
+                demoted.push(phys_addr);
+            } else {
+                lists.inactive.push(phys_addr);
+            }
+        }
+
+        lists.inactive.extend(demoted);
+
+        lists
+    }
+}
+
+///
+/// The metadata of the frame at `phys_addr`, if it holds user memory
+///
+fn user_frame(
+    frame_table: &FrameTable,
+    phys_addr: usize,
+) -> Option<&'static mut FrameMetadataEntry> {
+    let segment = frame_table.segment_of(phys_addr)?;
+    let meta = unsafe { segment.get_metadata(segment.index_of(phys_addr)) };
+
+    (meta.state() == FrameState::User).then_some(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page_table::fault::{PageFault, mark_accessed};
+    use crate::page_table::virt_map::{PageRegion, virtual_map_linear_with_flags};
+    use crate::page_table::{PageTableEntry, paging_mode};
+    use crate::testing::{Arena, bootstrapped, walk};
+
+    const RAM_BASE: usize = 0x8000_0000;
+
+    #[test]
+    fn scans_sort_user_frames_by_their_accessed_bits() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+
+        let (mut pt, root) = bootstrapped(&mut ft);
+
+        let user = PageTableEntry::FLAG_V
+            | PageTableEntry::FLAG_R
+            | PageTableEntry::FLAG_W
+            | PageTableEntry::FLAG_U;
+        let frames = ft.alloc_front(3, FrameState::User, 7).unwrap().phys_addr;
+        let kernel = ft.alloc_front(1, FrameState::Kernel, 0).unwrap().phys_addr;
+        let page = |index: usize| 0x10000 + index * PAGE_SIZE_B;
+
+        // The first two pages were used already, the kernel frame is never
+        // a candidate
+        for (index, phys_addr, accessed) in [
+            (0, frames, PageTableEntry::FLAG_A),
+            (1, frames + PAGE_SIZE_B, PageTableEntry::FLAG_A),
+            (2, frames + 2 * PAGE_SIZE_B, 0),
+            (3, kernel, PageTableEntry::FLAG_A),
+        ] {
+            let region = PageRegion {
+                address: phys_addr,
+                count: 1,
+            };
+            unsafe {
+                virtual_map_linear_with_flags(
+                    &mut pt,
+                    &mut ft,
+                    region,
+                    page(index),
+                    user | accessed,
+                )
+            }
+            .unwrap();
+        }
+
+        let span = paging_mode().root_size().pages();
+        let mut aging = PageAging::new();
+        let scan = |pt: &mut PageTable, ft: &mut FrameTable, aging: &mut PageAging| {
+            let mut tlb = TlbFlush::local();
+            let sampled = unsafe { aging.sample(pt, ft, 0, span, &mut tlb) };
+            tlb.flush();
+
+            (sampled, aging.finish(ft))
+        };
+
+        let (sampled, lists) = scan(&mut pt, &mut ft, &mut aging);
+        assert_eq!(sampled, 4);
+        assert_eq!(lists.active, [frames, frames + PAGE_SIZE_B]);
+        assert_eq!(lists.inactive, [frames + 2 * PAGE_SIZE_B]);
+        assert_eq!(walk(root, page(0)), Some((frames, user)));
+
+        // Only the second page is used again, through a fault on hardware
+        // which leaves `A` to software
+        let mut tlb = TlbFlush::local();
+        assert!(unsafe { mark_accessed(&mut pt, page(1), PageFault::Load, &mut tlb) });
+        tlb.flush();
+
+        // The frame which went unused joins the back of the inactive list
+        let (_, lists) = scan(&mut pt, &mut ft, &mut aging);
+        assert_eq!(lists.active, [frames + PAGE_SIZE_B]);
+        assert_eq!(lists.inactive, [frames + 2 * PAGE_SIZE_B, frames]);
+
+        // Freed frames drop out of the lists
+        let region = PageRegion {
+            address: frames + 2 * PAGE_SIZE_B,
+            count: 1,
+        };
+        let mut tlb = TlbFlush::local();
+        let unmapped = unsafe { virt_map::unmap(&mut pt, &mut ft, page(2), 1, &mut tlb) }.unwrap();
+        tlb.flush_freeing(&mut ft);
+        assert_eq!(unmapped, [region]);
+        ft.free_addr(frames + 2 * PAGE_SIZE_B, 1, 7).unwrap();
+
+        let (sampled, lists) = scan(&mut pt, &mut ft, &mut aging);
+        assert_eq!(sampled, 3);
+        assert!(lists.active.is_empty());
+        assert_eq!(lists.inactive, [frames, frames + PAGE_SIZE_B]);
+    }
+}
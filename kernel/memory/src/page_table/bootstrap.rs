@@ -1,5 +1,5 @@
-use crate::frame_table::FrameTable;
-use crate::page_table::{PTEKind, PageTable, PageTableEntry, PageTableIndex};
+use crate::frame_table::{FrameState, FrameTable};
+use crate::page_table::{paging_mode, PTEKind, PageTable, PageTableEntry, PageTableIndex};
 
 ///
 /// Bootstrap the page table
@@ -9,6 +9,11 @@ use crate::page_table::{PTEKind, PageTable, PageTableEntry, PageTableIndex};
 ///
 /// this is a critical operation in the virtmem init process
 ///
+/// The section is optional, page tables are reached through the physmap.
+/// It only serves to find every page table at a fixed virtual address, for
+/// debugging. Tables allocated with `meta_allocate_page_table` are mapped
+/// into it while it exists
+///
 /// NOTE:
 ///
 /// This MUST be the first operation used on the page table
@@ -17,10 +22,22 @@ pub unsafe fn bootstrap_pt(root_page_table: &mut PageTable, frame_table: &mut Fr
     log::info!("Bootstapping page table entries");
     // We start the page table at
     // 280, 0, 0
+    //
+    // with another 0 for every level past Sv39
 
     let mut inter_1 = root_page_table
-        .allocate_intermediary(PageTableIndex::new(280), frame_table)
+        .allocate_intermediary(PageTableIndex::new(SELF_REF_ROOT_INDEX as u16), frame_table)
+        .unwrap();
+
+    for _ in 3..paging_mode().levels() {
+        inter_1 = unsafe {
+            inter_1
+                .page_table
+                .allocate_intermediary(PageTableIndex::new(0), frame_table)
+        }
         .unwrap();
+    }
+
     let mut inter_2 = inter_1
         .page_table
         .allocate_intermediary(PageTableIndex::new(0), frame_table)
@@ -33,10 +50,23 @@ pub unsafe fn bootstrap_pt(root_page_table: &mut PageTable, frame_table: &mut Fr
 
     // We can basically guarantee there are no more than 512 allocation at this time
 
-    frame_table
-        .iter_front()
+    let front: alloc::vec::Vec<usize> = frame_table.iter_front().collect();
+
+    front
+        .into_iter()
         .enumerate()
         .for_each(|(index, address)| {
+            // Reserved firmware and kernel frames at the front of memory keep
+            // their index, but only page tables belong in the window
+            let is_page_table = frame_table.segment_of(address).is_some_and(|segment| {
+                let meta = unsafe { segment.get_metadata(segment.index_of(address)) };
+                meta.state() == FrameState::PageTable
+            });
+
+            if !is_page_table {
+                return;
+            }
+
             log::debug!("Placing {address:#016X} at child #{index}");
 
             // We have all the page-table addresses we want to map in
@@ -49,14 +79,141 @@ pub unsafe fn bootstrap_pt(root_page_table: &mut PageTable, frame_table: &mut Fr
         });
 
     PageTableOffsettingData{
-        physical_root_null_offset: root_page_table as *mut PageTable as usize,
-        virtual_root_null_offset: PT_VIRT_START
+        physical_root_null_offset: frame_table.root_frame_address(),
+        virtual_root_null_offset: pt_virt_start()
     }
 }
 
-pub const PT_VIRT_START : usize = 0b1111111111111111111111111_100011000_000000000_000000000_000000000000;
+///
+/// The root table index the self-ref section hangs off
+///
+/// The entry stays reserved in tables without the section, so mappings
+/// never depend on whether it exists
+///
+pub const SELF_REF_ROOT_INDEX: usize = 280;
+
+///
+/// The virtual address the self-ref section starts at
+///
+/// In Sv39 this is
+/// `0b1111111111111111111111111_100011000_000000000_000000000_000000000000`
+///
+pub fn pt_virt_start() -> usize {
+    let mode = paging_mode();
+    mode.canonical(SELF_REF_ROOT_INDEX << mode.root_size().shift())
+}
 
 pub struct PageTableOffsettingData{
     pub physical_root_null_offset : usize,
     pub virtual_root_null_offset : usize
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_table::FrameTable;
+    use crate::testing::{Arena, bootstrapped, walk};
+
+    const RAM_BASE: usize = 0x8000_0000;
+    const RW: u64 = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W;
+
+    fn window_addr(ft: &FrameTable, phys_addr: usize) -> usize {
+        pt_virt_start() + (phys_addr - ft.root_frame_address())
+    }
+
+    #[test]
+    fn window_maps_bootstrap_tables() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+        let (pt, root) = bootstrapped(&mut ft);
+
+        assert!(pt.entries[SELF_REF_ROOT_INDEX].is_valid() && !pt.entries[SELF_REF_ROOT_INDEX].is_leaf());
+
+        // The root and both self-ref parents sit at the front of memory
+        let tables: alloc::vec::Vec<usize> = unsafe { ft.iter_front() }.collect();
+        assert_eq!(tables.len(), 3);
+        assert_eq!(tables[0], root);
+
+        for table in tables {
+            assert_eq!(walk(root, window_addr(&ft, table)), Some((table, RW)));
+        }
+
+        assert_eq!(walk(root, window_addr(&ft, root) + 3 * 4096), None);
+    }
+
+    #[test]
+    fn window_skips_reserved_frames() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+        let firmware = ft.root_frame_address();
+        ft.reserve_region(firmware, 4096, FrameState::Reserved, 0);
+
+        let (_, root) = bootstrapped(&mut ft);
+
+        assert_eq!(root, firmware + 4096);
+        assert_eq!(walk(root, window_addr(&ft, firmware)), None);
+        assert_eq!(walk(root, window_addr(&ft, root)), Some((root, RW)));
+    }
+
+    #[test]
+    fn tables_after_other_frames_appear_in_window() {
+        let arena = Arena::at(RAM_BASE, 1100);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+
+        // Kernel frames fill the rest of the first 2MiB of the window and
+        // open the second one, so its table starts at a later index
+        let mut kernel = alloc::vec::Vec::new();
+        loop {
+            let frame = ft.alloc_front(1, FrameState::Kernel, 0).unwrap().phys_addr;
+            kernel.push(frame);
+
+            if frame - ft.root_frame_address() == 512 * 4096 {
+                break;
+            }
+        }
+
+        let table = unsafe { pt.meta_allocate_page_table(&mut ft) }.unwrap();
+        assert_eq!(table.phys_addr - ft.root_frame_address(), 513 * 4096);
+        assert_eq!(
+            walk(root, window_addr(&ft, table.phys_addr)),
+            Some((table.phys_addr, RW))
+        );
+
+        // The new last level table of the window is in the window as well
+        let intermediary = table.phys_addr + 4096;
+        assert_eq!(
+            walk(root, window_addr(&ft, intermediary)),
+            Some((intermediary, RW))
+        );
+
+        // A hole left by a freed frame is reused
+        let hole = kernel[100];
+        ft.free_addr(hole, 1, 0).unwrap();
+        let table = unsafe { pt.meta_allocate_page_table(&mut ft) }.unwrap();
+        assert_eq!(table.phys_addr, hole);
+        assert_eq!(walk(root, window_addr(&ft, hole)), Some((hole, RW)));
+    }
+
+    #[test]
+    fn meta_allocated_tables_appear_in_window() {
+        let arena = Arena::at(RAM_BASE, 1100);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+
+        // Enough tables to spill into a second leaf table of the window
+        for _ in 0..600 {
+            let table = unsafe { pt.meta_allocate_page_table(&mut ft) }.unwrap();
+
+            assert_eq!(
+                walk(root, window_addr(&ft, table.phys_addr)),
+                Some((table.phys_addr, RW))
+            );
+        }
+
+        for (index, table) in unsafe { ft.iter_front() }.enumerate() {
+            assert_eq!(ft.root_frame_address() + index * 4096, table);
+            assert_eq!(walk(root, window_addr(&ft, table)), Some((table, RW)));
+        }
+    }
+}
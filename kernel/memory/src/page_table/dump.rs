@@ -0,0 +1,403 @@
+//!
+//! Dumping page tables
+//!
+//! Walks every leaf of a page table and merges neighbouring leaves into
+//! ranges, so the whole table fits on a screen when a boot goes wrong. Leaves
+//! are merged when they are contiguous both virtually and physically, have
+//! the same size and carry the same flags. Nothing here allocates, so the
+//! panic path can use it too
+//!
+
+use core::fmt;
+
+use super::extensions::MemoryType;
+use super::{PTEKind, PageSize, PageTable, PageTableEntry, paging_mode};
+
+///
+/// A run of leaves of the same size and flags, mapping contiguous virtual
+/// memory to contiguous physical memory
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub virt_addr: usize,
+    pub phys_addr: usize,
+    pub size: PageSize,
+
+    ///
+    /// Number of leaves in the range
+    ///
+    pub count: usize,
+
+    ///
+    /// The flags of the leaves, without `V`
+    ///
+    pub flags: u64,
+}
+
+impl MappedRange {
+    ///
+    /// The flags which have to match for leaves to be merged
+    ///
+    pub const FLAGS: u64 = PageTableEntry::FLAG_R
+        | PageTableEntry::FLAG_W
+        | PageTableEntry::FLAG_X
+        | PageTableEntry::FLAG_U
+        | PageTableEntry::FLAG_G
+        | PageTableEntry::FLAG_A
+        | PageTableEntry::FLAG_D
+        | PageTableEntry::PBMT_MASK;
+
+    pub fn bytes(&self) -> usize {
+        self.count * self.size.bytes()
+    }
+
+    ///
+    /// The first virtual address past the range
+    ///
+    pub fn virt_end(&self) -> usize {
+        self.virt_addr.wrapping_add(self.bytes())
+    }
+
+    ///
+    /// Whether a leaf of `size` and `flags` mapping `virt_addr` to
+    /// `phys_addr` carries on where this range ends
+    ///
+    fn continues_with(
+        &self,
+        virt_addr: usize,
+        phys_addr: usize,
+        size: PageSize,
+        flags: u64,
+    ) -> bool {
+        self.size == size
+            && self.flags == flags
+            && self.virt_end() == virt_addr
+            && self.phys_addr + self.bytes() == phys_addr
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = match self.size {
+            PageSize::Page => "4K",
+            PageSize::Mega => "2M",
+            PageSize::Giga => "1G",
+            PageSize::Tera => "512G",
+            PageSize::Peta => "256T",
+        };
+
+        write!(
+            f,
+            "{:#018X}..{:#018X} -> {:#014X} {:>5} x {:<4} ",
+            self.virt_addr,
+            self.virt_end(),
+            self.phys_addr,
+            self.count,
+            size,
+        )?;
+
+        let kind = PageTableEntry(self.flags).kind();
+        let flags = [
+            (PageTableEntry::FLAG_R, 'R'),
+            (PageTableEntry::FLAG_W, 'W'),
+            (PageTableEntry::FLAG_X, 'X'),
+            (PageTableEntry::FLAG_U, 'U'),
+            (PageTableEntry::FLAG_G, 'G'),
+            (PageTableEntry::FLAG_A, 'A'),
+            (PageTableEntry::FLAG_D, 'D'),
+        ];
+
+        for (flag, name) in flags {
+            let name = if self.flags & flag != 0 { name } else { '-' };
+            fmt::Write::write_char(f, name)?;
+        }
+
+        match MemoryType::from_flags(self.flags) {
+            MemoryType::Main => {}
+            MemoryType::NonCacheable => write!(f, " NC")?,
+            MemoryType::Io => write!(f, " IO")?,
+        }
+
+        // Write-only and write-execute leaves fault on any access
+        if matches!(kind, PTEKind::_Reserved1 | PTEKind::_Reserved2) {
+            write!(f, " (reserved)")?;
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// How many tables a page table is made of, per level
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableUsage {
+    ///
+    /// Number of tables at each level, indexed like `PageSize::level`
+    ///
+    pub tables: [usize; 5],
+
+    pub levels: usize,
+}
+
+impl TableUsage {
+    pub fn total(&self) -> usize {
+        self.tables.iter().sum()
+    }
+}
+
+impl fmt::Display for TableUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page tables:")?;
+
+        for level in (0..self.levels).rev() {
+            write!(f, " L{level} {},", self.tables[level])?;
+        }
+
+        write!(f, " {} in total", self.total())
+    }
+}
+
+impl PageTable {
+    ///
+    /// Call `visit` with every range of merged leaves, in order of their
+    /// virtual addresses
+    ///
+    /// Returns the number of tables making up the page table, this one included
+    ///
+    pub fn for_each_range(&self, mut visit: impl FnMut(&MappedRange)) -> TableUsage {
+        let mode = paging_mode();
+
+        let mut usage = TableUsage {
+            tables: [0; 5],
+            levels: mode.levels(),
+        };
+        let mut current = None;
+
+        usage.tables[mode.levels() - 1] += 1;
+        self.collect_ranges(mode.root_size(), 0, &mut current, &mut usage, &mut visit);
+
+        if let Some(range) = current {
+            visit(&range);
+        }
+
+        usage
+    }
+
+    ///
+    /// Write every range of merged leaves to `out`, one per line, followed by
+    /// the number of tables in use if `show_tables` is set
+    ///
+    pub fn dump(&self, out: &mut impl fmt::Write, show_tables: bool) -> fmt::Result {
+        let mut result = Ok(());
+        let mut ranges = 0;
+
+        let usage = self.for_each_range(|range| {
+            ranges += 1;
+
+            if result.is_ok() {
+                result = writeln!(out, "{range}");
+            }
+        });
+
+        result?;
+
+        writeln!(out, "{ranges} mapped ranges")?;
+
+        if show_tables {
+            writeln!(out, "{usage}")?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Merge the leaves beneath this table, whose entries are of `size` and
+    /// map from virtual page `base`, into `current`
+    ///
+    fn collect_ranges(
+        &self,
+        size: PageSize,
+        base: usize,
+        current: &mut Option<MappedRange>,
+        usage: &mut TableUsage,
+        visit: &mut impl FnMut(&MappedRange),
+    ) {
+        let mode = paging_mode();
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            if !entry.is_valid() {
+                continue;
+            }
+
+            let vpn = base + index * size.pages();
+
+            if !entry.is_leaf() {
+                // A pointer at the last level is malformed, there is nothing to follow
+                let Some(child_size) = size.smaller() else {
+                    continue;
+                };
+
+                usage.tables[child_size.level()] += 1;
+
+                let child = unsafe { PageTable::from_pointer(entry.phys_addr()) };
+                child.collect_ranges(child_size, vpn, current, usage, visit);
+                continue;
+            }
+
+            let virt_addr = mode.canonical(vpn << 12);
+            let flags = entry.0 & MappedRange::FLAGS;
+            let phys_addr = entry.leaf_phys_addr(vpn);
+
+            match current {
+                Some(range) if range.continues_with(virt_addr, phys_addr, size, flags) => {
+                    range.count += 1;
+                }
+                _ => {
+                    if let Some(range) = current.take() {
+                        visit(&range);
+                    }
+
+                    *current = Some(MappedRange {
+                        virt_addr,
+                        phys_addr,
+                        size,
+                        count: 1,
+                        flags,
+                    });
+                }
+            }
+        }
+    }
+}
+
+///
+/// The physical address of the root table this hart translates with, `None`
+/// while paging is off
+///
+pub fn active_root() -> Option<usize> {
+    #[cfg(target_arch = "riscv64")]
+    {
+        let satp: usize;
+        unsafe { core::arch::asm!("csrr {}, satp", out(reg) satp) };
+
+        if satp >> 60 == 0 {
+            return None;
+        }
+
+        Some((satp & ((1 << 44) - 1)) << 12)
+    }
+
+    #[cfg(not(target_arch = "riscv64"))]
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::frame_table::{FrameState, FrameTable};
+    use crate::page_table::virt_map::{PageRegion, virtual_map_linear_with_flags};
+    use crate::page_table::pt_virt_start;
+    use crate::testing::{Arena, bootstrapped};
+
+    const RAM_BASE: usize = 0x8000_0000;
+
+    #[test]
+    fn leaves_are_merged_into_ranges() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+
+        let (mut pt, _) = bootstrapped(&mut ft);
+
+        let rx = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_X;
+        let rw = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W;
+        let map = |pt: &mut PageTable, ft: &mut FrameTable, phys, count, virt, flags| {
+            let region = PageRegion {
+                address: phys,
+                count,
+            };
+            unsafe { virtual_map_linear_with_flags(pt, ft, region, virt, flags) }.unwrap();
+        };
+
+        // Text and data next to each other, split by their flags
+        map(&mut pt, &mut ft, RAM_BASE, 3, RAM_BASE, rx);
+        map(
+            &mut pt,
+            &mut ft,
+            RAM_BASE + 0x3000,
+            2,
+            RAM_BASE + 0x3000,
+            rw,
+        );
+        // Virtually contiguous with the data, but not physically
+        map(
+            &mut pt,
+            &mut ft,
+            RAM_BASE + 0x8000,
+            1,
+            RAM_BASE + 0x5000,
+            rw,
+        );
+        // Two gigapages
+        map(&mut pt, &mut ft, 4 << 30, 2 << 18, 4 << 30, rw);
+
+        let mut ranges = Vec::new();
+        let usage = pt.for_each_range(|range| {
+            if range.virt_addr < pt_virt_start() {
+                ranges.push(*range);
+            }
+        });
+
+        let rw = rw & MappedRange::FLAGS;
+        assert_eq!(
+            ranges,
+            [
+                MappedRange {
+                    virt_addr: RAM_BASE,
+                    phys_addr: RAM_BASE,
+                    size: PageSize::Page,
+                    count: 3,
+                    flags: rx & MappedRange::FLAGS,
+                },
+                MappedRange {
+                    virt_addr: RAM_BASE + 0x3000,
+                    phys_addr: RAM_BASE + 0x3000,
+                    size: PageSize::Page,
+                    count: 2,
+                    flags: rw,
+                },
+                MappedRange {
+                    virt_addr: RAM_BASE + 0x5000,
+                    phys_addr: RAM_BASE + 0x8000,
+                    size: PageSize::Page,
+                    count: 1,
+                    flags: rw,
+                },
+                MappedRange {
+                    virt_addr: 4 << 30,
+                    phys_addr: 4 << 30,
+                    size: PageSize::Giga,
+                    count: 2,
+                    flags: rw,
+                },
+            ]
+        );
+
+        // The root, the tables down to the kernel and the self-ref section
+        assert_eq!(usage.tables[2], 1);
+        assert_eq!(usage.total(), ft.count_frames(0, FrameState::PageTable));
+
+        let mut out = String::new();
+        pt.dump(&mut out, true).unwrap();
+
+        let first = out.lines().next().unwrap();
+        assert_eq!(
+            first,
+            "0x0000000080000000..0x0000000080003000 -> 0x000080000000     3 x 4K   R-X----"
+        );
+        assert!(out.ends_with(&std::format!("{usage}\n")));
+    }
+}
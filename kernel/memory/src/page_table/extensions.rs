@@ -0,0 +1,210 @@
+//!
+//! Optional page table extensions
+//!
+//! Svpbmt gives leaves a memory type of their own, so device memory can be
+//! mapped uncached and strongly ordered regardless of the platform's
+//! physical memory attributes. Svnapot lets 16 leaves mapping 64KiB of
+//! contiguous, aligned memory share a single TLB entry.
+//!
+//! Both are only used once every hart advertises them, without them the
+//! bits are left clear: device memory falls back to the attributes the
+//! platform gives its address range, and 64KiB runs to plain pages
+//!
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::PageTableEntry;
+
+///
+/// Number of 4KiB pages a NAPOT leaf covers
+///
+pub const NAPOT_PAGES: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    pub svpbmt: bool,
+    pub svnapot: bool,
+}
+
+impl Extensions {
+    ///
+    /// Parse the `riscv,isa-extensions` property of a device tree cpu node,
+    /// a list of nul terminated extension names
+    ///
+    pub fn from_isa_extensions(property: &[u8]) -> Self {
+        let mut extensions = Self::default();
+
+        for name in property.split(|&byte| byte == 0) {
+            extensions.add(name);
+        }
+
+        extensions
+    }
+
+    ///
+    /// Parse the older `riscv,isa` property, such as `rv64imac_svpbmt`
+    ///
+    pub fn from_isa(isa: &str) -> Self {
+        let mut extensions = Self::default();
+
+        // Multi-letter extensions follow the base ISA, separated by underscores
+        for name in isa.trim_end_matches('\0').split('_').skip(1) {
+            extensions.add(name.as_bytes());
+        }
+
+        extensions
+    }
+
+    fn add(&mut self, name: &[u8]) {
+        if name.eq_ignore_ascii_case(b"svpbmt") {
+            self.svpbmt = true;
+        } else if name.eq_ignore_ascii_case(b"svnapot") {
+            self.svnapot = true;
+        }
+    }
+
+    ///
+    /// The extensions both `self` and `other` have
+    ///
+    pub fn intersection(self, other: Self) -> Self {
+        Self {
+            svpbmt: self.svpbmt && other.svpbmt,
+            svnapot: self.svnapot && other.svnapot,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        self.svpbmt as u8 | (self.svnapot as u8) << 1
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            svpbmt: bits & 1 != 0,
+            svnapot: bits & 2 != 0,
+        }
+    }
+}
+
+///
+/// The memory type of a leaf, with Svpbmt
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    ///
+    /// Whatever the platform's attributes for the address say, which is
+    /// cached main memory for RAM
+    ///
+    Main,
+
+    ///
+    /// Non-cacheable, idempotent, weakly ordered main memory
+    ///
+    NonCacheable,
+
+    ///
+    /// Non-cacheable, non-idempotent, strongly ordered I/O memory
+    ///
+    Io,
+}
+
+impl MemoryType {
+    ///
+    /// The `PBMT` bits of a leaf of this type
+    ///
+    /// Without Svpbmt the bits are reserved, so every type maps to none
+    ///
+    pub fn flags(self) -> u64 {
+        if !extensions().svpbmt {
+            return 0;
+        }
+
+        match self {
+            MemoryType::Main => 0,
+            MemoryType::NonCacheable => PageTableEntry::PBMT_NC,
+            MemoryType::Io => PageTableEntry::PBMT_IO,
+        }
+    }
+
+    pub fn from_flags(flags: u64) -> Self {
+        match flags & PageTableEntry::PBMT_MASK {
+            PageTableEntry::PBMT_NC => MemoryType::NonCacheable,
+            PageTableEntry::PBMT_IO => MemoryType::Io,
+            _ => MemoryType::Main,
+        }
+    }
+}
+
+#[cfg(not(test))]
+static EXTENSIONS: AtomicU8 = AtomicU8::new(0);
+
+// Like the paging mode, every test thread picks its own
+#[cfg(test)]
+std::thread_local! {
+    static EXTENSIONS: AtomicU8 = const { AtomicU8::new(0) };
+}
+
+///
+/// The extensions page tables are built with, none until set
+///
+pub fn extensions() -> Extensions {
+    #[cfg(not(test))]
+    let bits = EXTENSIONS.load(Ordering::Relaxed);
+
+    #[cfg(test)]
+    let bits = EXTENSIONS.with(|bits| bits.load(Ordering::Relaxed));
+
+    Extensions::from_bits(bits)
+}
+
+///
+/// Set the extensions page tables are built with
+///
+/// # Safety
+///
+/// Every hart which will run with the page tables must implement them
+///
+pub unsafe fn set_extensions(extensions: Extensions) {
+    #[cfg(not(test))]
+    EXTENSIONS.store(extensions.bits(), Ordering::Relaxed);
+
+    #[cfg(test)]
+    EXTENSIONS.with(|bits| bits.store(extensions.bits(), Ordering::Relaxed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_are_read_from_the_device_tree() {
+        let listed = Extensions::from_isa_extensions(b"i\0m\0a\0zicsr\0svnapot\0svpbmt\0");
+        assert_eq!(
+            listed,
+            Extensions {
+                svpbmt: true,
+                svnapot: true
+            }
+        );
+
+        let isa = Extensions::from_isa("rv64imac_zicsr_svpbmt\0");
+        assert_eq!(
+            isa,
+            Extensions {
+                svpbmt: true,
+                svnapot: false
+            }
+        );
+        assert_eq!(listed.intersection(isa), isa);
+
+        // Without Svpbmt the memory type is left to the platform
+        assert_eq!(MemoryType::Io.flags(), 0);
+
+        unsafe { set_extensions(isa) };
+        assert_eq!(extensions(), isa);
+        assert_eq!(MemoryType::Io.flags(), PageTableEntry::PBMT_IO);
+        assert_eq!(
+            MemoryType::from_flags(MemoryType::NonCacheable.flags()),
+            MemoryType::NonCacheable
+        );
+    }
+}
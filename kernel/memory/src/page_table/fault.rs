@@ -0,0 +1,742 @@
+//!
+//! Demand-zero paging
+//!
+//! Memory can be reserved as a `LazyRegion` instead of being backed up
+//! front. Nothing is mapped until it is first touched, the page fault which
+//! follows lands in `handle_page_fault`, which backs the faulting page with
+//! a zeroed frame and lets the access run again.
+//!
+//! Hardware which leaves the `A` and `D` bits to software faults on the
+//! first access and the first write of a page as well, those faults set the
+//! bits of the page which is already there. `mark_accessed` handles them on
+//! their own, without the frame table.
+//!
+//! Stores to pages shared copy-on-write by `virt_map::clone_cow` land here
+//! too, the page gets a copy of the frame, or takes the frame over when
+//! nobody else references it anymore
+//!
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::PAGE_SIZE_B;
+use crate::frame_table::{FrameState, FrameTable};
+use crate::phys::phys_to_ptr;
+use crate::tlb::TlbFlush;
+
+use super::extensions::NAPOT_PAGES;
+use super::virt_map::{self, PageRegion};
+use super::{MapError, PageSize, PageTable, PageTableEntry, Translation, paging_mode};
+
+///
+/// The access which caused a page fault
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFault {
+    Instruction,
+    Load,
+
+    ///
+    /// Stores and atomic memory operations
+    ///
+    Store,
+}
+
+impl PageFault {
+    ///
+    /// The page fault `scause` reports, if it is one
+    ///
+    pub fn from_scause(scause: usize) -> Option<Self> {
+        match scause {
+            12 => Some(PageFault::Instruction),
+            13 => Some(PageFault::Load),
+            15 => Some(PageFault::Store),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The leaf flag the access needs
+    ///
+    fn required_flag(self) -> u64 {
+        match self {
+            PageFault::Instruction => PageTableEntry::FLAG_X,
+            PageFault::Load => PageTableEntry::FLAG_R,
+            PageFault::Store => PageTableEntry::FLAG_W,
+        }
+    }
+
+    ///
+    /// The bits the access sets in the leaf, `A` and `D` for a store
+    ///
+    fn accessed_flags(self) -> u64 {
+        match self {
+            PageFault::Store => PageTableEntry::FLAG_A | PageTableEntry::FLAG_D,
+            _ => PageTableEntry::FLAG_A,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    ///
+    /// The address is neither mapped nor part of a lazy region
+    ///
+    NotReserved { addr: usize },
+
+    ///
+    /// The page does not allow the access which faulted
+    ///
+    AccessDenied { addr: usize, fault: PageFault },
+
+    ///
+    /// The page could not be backed
+    ///
+    Map { addr: usize, error: MapError },
+}
+
+///
+/// A range of virtual memory which is only backed once it is touched
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LazyRegion {
+    pub virt_addr: usize,
+    pub pages: usize,
+
+    ///
+    /// The `R`, `W`, `X`, `U` and `G` bits its pages are mapped with
+    ///
+    pub flags: u64,
+
+    ///
+    /// The process its frames are allocated for, 0 for kernel regions
+    ///
+    pub pid: u16,
+}
+
+impl LazyRegion {
+    pub fn contains(&self, virt_addr: usize) -> bool {
+        (self.virt_addr..self.virt_addr + self.pages * PAGE_SIZE_B).contains(&virt_addr)
+    }
+}
+
+///
+/// The lazy regions of a page table, sorted by address
+///
+pub struct LazyRegions {
+    regions: Vec<LazyRegion>,
+}
+
+impl LazyRegions {
+    pub const fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    ///
+    /// Reserve `pages` pages at `virt_addr`, to be backed on first use with
+    /// the permissions in `flags` by frames of `pid`
+    ///
+    /// The tables beneath the root covering the range are allocated right
+    /// away, like `Vmalloc::init` does. Address spaces copy the kernel's root
+    /// entries, a fault must not add one they lack. The tables stay in place
+    /// when the region is released, or when reserving it fails
+    ///
+    /// Fails with `AlreadyMapped` when the range overlaps a region which is
+    /// already reserved
+    ///
+    /// # Safety
+    ///
+    /// `page_table` must be the table the region is backed in
+    ///
+    pub unsafe fn reserve(
+        &mut self,
+        page_table: &mut PageTable,
+        frame_table: &mut FrameTable,
+        virt_addr: usize,
+        pages: usize,
+        flags: u64,
+        pid: u16,
+    ) -> Result<(), MapError> {
+        assert!(pages > 0, "lazy regions hold at least one page");
+        assert_eq!(
+            virt_addr & (PAGE_SIZE_B - 1),
+            0,
+            "lazy regions start on a page boundary"
+        );
+        assert_eq!(
+            flags & !Translation::PERMISSIONS,
+            0,
+            "lazy regions only hold permission bits"
+        );
+        assert!(
+            PageTableEntry(flags).is_leaf(),
+            "lazy regions need a leaf permission"
+        );
+        assert!(
+            flags & PageTableEntry::FLAG_U != 0 || pid == 0,
+            "kernel lazy regions belong to pid 0"
+        );
+
+        let end = virt_addr + pages * PAGE_SIZE_B;
+        let index = self
+            .regions
+            .partition_point(|region| region.virt_addr < virt_addr);
+
+        let overlaps_previous = index > 0 && self.regions[index - 1].contains(virt_addr);
+        let overlaps_next = self
+            .regions
+            .get(index)
+            .is_some_and(|next| next.virt_addr < end);
+
+        if overlaps_previous || overlaps_next {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        let first = virt_map::decompose_virt_pageaddr(paging_mode().vpn(virt_addr));
+        let last = virt_map::decompose_virt_pageaddr(paging_mode().vpn(end - PAGE_SIZE_B));
+
+        for index in usize::from(first.root_index())..=usize::from(last.root_index()) {
+            let entry = page_table.entries[index];
+
+            if entry.is_leaf() {
+                return Err(MapError::HugePageConflict);
+            }
+
+            if entry.is_unused() {
+                let table = unsafe { page_table.meta_allocate_page_table(frame_table) }?;
+                page_table.entries[index].set(table.phys_addr as u64, PageTableEntry::FLAG_V);
+            }
+        }
+
+        self.regions.insert(
+            index,
+            LazyRegion {
+                virt_addr,
+                pages,
+                flags,
+                pid,
+            },
+        );
+
+        Ok(())
+    }
+
+    ///
+    /// The region holding `virt_addr`
+    ///
+    pub fn find(&self, virt_addr: usize) -> Option<&LazyRegion> {
+        let index = self
+            .regions
+            .partition_point(|region| region.virt_addr <= virt_addr);
+
+        index
+            .checked_sub(1)
+            .map(|index| &self.regions[index])
+            .filter(|region| region.contains(virt_addr))
+    }
+
+    ///
+    /// Drop the region starting at `virt_addr`, unmapping and freeing every
+    /// page of it which was backed
+    ///
+    /// Returns the region, or `None` if no region starts there. The backed
+    /// pages are added to `tlb`, which is flushed before their frames are
+    /// let go of
+    ///
+    /// # Safety
+    ///
+    /// Nothing may still use the region, on this or any other hart, and
+    /// `page_table` must be the table it was backed in
+    ///
+    pub unsafe fn release(
+        &mut self,
+        page_table: &mut PageTable,
+        frame_table: &mut FrameTable,
+        virt_addr: usize,
+        tlb: &mut TlbFlush,
+    ) -> Option<LazyRegion> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.virt_addr == virt_addr)?;
+        let region = self.regions.remove(index);
+
+        // Faults only ever map 4KiB pages, there is never anything to split
+        let backed: Vec<PageRegion> = unsafe {
+            virt_map::unmap_shared(page_table, frame_table, region.virt_addr, region.pages, tlb)
+        }
+        .expect("lazy region: failed to unmap");
+
+        tlb.flush_freeing(frame_table);
+
+        // The frames may still be shared copy-on-write with a clone
+        for frames in backed {
+            for page in 0..frames.count {
+                frame_table
+                    .frame_unref(frames.address + page * PAGE_SIZE_B)
+                    .expect("lazy region: failed to drop a backing frame");
+            }
+        }
+
+        Some(region)
+    }
+}
+
+impl Default for LazyRegions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///
+/// Resolve a `fault` at `virt_addr`, the value of `stval`
+///
+/// Pages of a lazy region are backed with a zeroed frame on their first
+/// access. Pages which are mapped already only get their `A` and `D` bits
+/// set, when the access is allowed, and copy-on-write pages are broken up
+/// on a store. The pages changed are added to `tlb`, the access can be
+/// retried once it is flushed
+///
+/// # Safety
+///
+/// `page_table` must be the table the hart faulted in, and `regions` its lazy
+/// regions
+///
+pub unsafe fn handle_page_fault(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    regions: &LazyRegions,
+    virt_addr: usize,
+    fault: PageFault,
+    tlb: &mut TlbFlush,
+) -> Result<(), FaultError> {
+    let page = virt_addr & !(PAGE_SIZE_B - 1);
+    let vpn = paging_mode().vpn(page);
+
+    if let Some(translation) = page_table.walk(virt_addr) {
+        if fault == PageFault::Store && translation.entry.is_cow() {
+            return unsafe { copy_on_write(page_table, frame_table, page, tlb) }.map_err(|error| {
+                FaultError::Map {
+                    addr: virt_addr,
+                    error,
+                }
+            });
+        }
+
+        if unsafe { mark_accessed(page_table, virt_addr, fault, tlb) } {
+            return Ok(());
+        }
+
+        return Err(FaultError::AccessDenied {
+            addr: virt_addr,
+            fault,
+        });
+    }
+
+    let region = regions
+        .find(virt_addr)
+        .ok_or(FaultError::NotReserved { addr: virt_addr })?;
+
+    if region.flags & fault.required_flag() == 0 {
+        return Err(FaultError::AccessDenied {
+            addr: virt_addr,
+            fault,
+        });
+    }
+
+    let map_error = |error| FaultError::Map {
+        addr: virt_addr,
+        error,
+    };
+
+    let state = if region.flags & PageTableEntry::FLAG_U != 0 {
+        FrameState::User
+    } else {
+        FrameState::Kernel
+    };
+
+    let frame = frame_table
+        .alloc_front(1, state, region.pid)
+        .ok_or(map_error(MapError::OutOfFrames))?;
+
+    unsafe { frame.zero() };
+
+    let flags = PageTableEntry::FLAG_V | region.flags | fault.accessed_flags();
+
+    if let Err(error) = unsafe {
+        virt_map::map_leaf(
+            page_table,
+            frame_table,
+            vpn,
+            frame.phys_addr,
+            PageSize::Page,
+            flags,
+        )
+    } {
+        frame_table
+            .free_addr(frame.phys_addr, 1, region.pid)
+            .expect("lazy region: failed to free a backing frame");
+
+        return Err(map_error(error));
+    }
+
+    tlb.add(page, 1);
+
+    Ok(())
+}
+
+///
+/// Add `accessed` to `entry` as long as it still maps what `walked` did
+///
+/// No lock is held, another hart may change the entry meanwhile and the
+/// hardware may set `A` or `D` itself. The bits are only added with a
+/// compare-and-swap on an entry which differs from `walked` in those two
+/// at most, anything else is left to fault again when the access is retried
+///
+fn set_accessed(entry: &mut PageTableEntry, walked: PageTableEntry, accessed: u64) {
+    let ad = PageTableEntry::FLAG_A | PageTableEntry::FLAG_D;
+    let bits = unsafe { AtomicU64::from_ptr(&mut entry.0) };
+    let mut current = bits.load(Ordering::Acquire);
+
+    while current & !ad == walked.0 & !ad {
+        match bits.compare_exchange_weak(
+            current,
+            current | accessed,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return,
+            Err(now) => current = now,
+        }
+    }
+}
+
+///
+/// Set the `A` bit, and the `D` bit for a store, of the page mapping
+/// `virt_addr` after a `fault` on hardware which leaves them to software
+///
+/// Returns whether the fault was resolved, which takes a page allowing the
+/// access with the bits missing. Only the leaf is touched, with a
+/// compare-and-swap, so unlike `handle_page_fault` this works without any
+/// lock and with the frame table locked. The page is added to `tlb`
+///
+/// # Safety
+///
+/// `page_table` must be the table the hart faulted in
+///
+pub unsafe fn mark_accessed(
+    page_table: &mut PageTable,
+    virt_addr: usize,
+    fault: PageFault,
+    tlb: &mut TlbFlush,
+) -> bool {
+    let Some(translation) = page_table.walk(virt_addr) else {
+        return false;
+    };
+
+    // Copy-on-write pages lack `W`, a store to them needs a copy instead
+    if translation.permissions & fault.required_flag() == 0 {
+        return false;
+    }
+
+    let page = virt_addr & !(PAGE_SIZE_B - 1);
+    let vpn = paging_mode().vpn(page);
+    let accessed = fault.accessed_flags();
+
+    let walked = translation.entry;
+
+    // With the bits already set the fault came from something else, like
+    // a user page touched by the kernel, retrying would fault forever
+    if walked.0 & accessed == accessed {
+        return false;
+    }
+
+    // The leaves of a NAPOT run are kept alike, any of them may be the
+    // one the TLB caches
+    let pages = if walked.is_napot() {
+        let first = vpn - vpn % NAPOT_PAGES;
+        first..first + NAPOT_PAGES
+    } else {
+        vpn..vpn + 1
+    };
+
+    for page in pages {
+        let Some(entry) = virt_map::entry_at(page_table, page, translation.size) else {
+            break;
+        };
+
+        set_accessed(entry, walked, accessed);
+    }
+
+    tlb.add(page, 1);
+
+    true
+}
+
+///
+/// Give the copy-on-write page at `page` a frame of its own and make it
+/// writable again
+///
+/// The frame is copied while it is shared, and taken over once this is the
+/// last reference to it. A large page is split up first, only the 4KiB page
+/// which was stored to is copied. Other harts may still read the shared
+/// frame until `tlb` is flushed
+///
+unsafe fn copy_on_write(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    page: usize,
+    tlb: &mut TlbFlush,
+) -> Result<(), MapError> {
+    let vpn = paging_mode().vpn(page);
+
+    unsafe { virt_map::split_leaves_around(page_table, frame_table, &(vpn..vpn + 1), tlb) }?;
+
+    let entry = virt_map::entry_at(page_table, vpn, PageSize::Page).expect("split leaf is missing");
+    let shared = entry.phys_addr();
+    let flags = (entry.0 & PageTableEntry::FLAGS & !PageTableEntry::FLAG_COW)
+        | PageTableEntry::FLAG_W
+        | PageTableEntry::FLAG_A
+        | PageTableEntry::FLAG_D;
+
+    if frame_table.ref_count(shared) == Some(1) {
+        entry.set(shared as u64, flags);
+    } else {
+        // The copy belongs to whoever owned the original
+        let pid = frame_table.segment_of(shared).map_or(0, |segment| {
+            unsafe { segment.get_metadata(segment.index_of(shared)) }.pid()
+        });
+
+        let copy = frame_table
+            .alloc_front(1, FrameState::User, pid)
+            .ok_or(MapError::OutOfFrames)?;
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_ptr::<u8>(shared),
+                phys_to_ptr::<u8>(copy.phys_addr),
+                PAGE_SIZE_B,
+            )
+        };
+
+        entry.set(copy.phys_addr as u64, flags);
+
+        frame_table
+            .frame_unref(shared)
+            .expect("copy-on-write: failed to drop the shared frame");
+    }
+
+    tlb.add(page, 1);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Arena, bootstrapped, walk};
+
+    const RAM_BASE: usize = 0x8000_0000;
+
+    #[test]
+    fn lazy_pages_are_backed_on_first_touch() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+
+        let (mut pt, root) = bootstrapped(&mut ft);
+
+        let rw = PageTableEntry::FLAG_R | PageTableEntry::FLAG_W;
+        let heap = 1 << 30;
+
+        let tables = ft.count_frames(0, FrameState::PageTable);
+
+        let mut regions = LazyRegions::new();
+        unsafe {
+            regions.reserve(&mut pt, &mut ft, heap, 4, rw, 0).unwrap();
+            regions
+                .reserve(
+                    &mut pt,
+                    &mut ft,
+                    heap + 8 * PAGE_SIZE_B,
+                    1,
+                    PageTableEntry::FLAG_R,
+                    0,
+                )
+                .unwrap();
+            assert_eq!(
+                regions.reserve(&mut pt, &mut ft, heap + 3 * PAGE_SIZE_B, 2, rw, 0),
+                Err(MapError::AlreadyMapped)
+            );
+        }
+
+        // The table beneath the root is there before anything is backed, so
+        // address spaces copying the root entries share it
+        let root_index = usize::from(virt_map::decompose_virt_pageaddr(heap >> 12).root_index());
+        assert!(pt.entries[root_index].is_valid() && !pt.entries[root_index].is_leaf());
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables + 1);
+
+        let tables = tables + 1;
+        let free = ft.count_frames(0, FrameState::Free);
+        let mut tlb = TlbFlush::local();
+
+        // Nothing is backed until the first fault
+        assert_eq!(pt.translate(heap), None);
+
+        let addr = heap + 2 * PAGE_SIZE_B + 0x10;
+        unsafe { handle_page_fault(&mut pt, &mut ft, &regions, addr, PageFault::Load, &mut tlb) }
+            .unwrap();
+
+        let (phys_addr, flags) = walk(root, addr).unwrap();
+        assert_eq!(flags, PageTableEntry::FLAG_V | rw | PageTableEntry::FLAG_A);
+        assert_eq!(unsafe { *phys_to_ptr::<u64>(phys_addr) }, 0);
+        assert_eq!(ft.count_frames(0, FrameState::Kernel), 1);
+
+        // The first store to the page only marks it dirty
+        unsafe { handle_page_fault(&mut pt, &mut ft, &regions, addr, PageFault::Store, &mut tlb) }
+            .unwrap();
+        assert_eq!(
+            walk(root, addr).unwrap().1 & PageTableEntry::FLAG_D,
+            PageTableEntry::FLAG_D
+        );
+        assert_eq!(ft.count_frames(0, FrameState::Kernel), 1);
+
+        // A fault on a page which is already accessed and dirty isn't resolved
+        assert_eq!(
+            unsafe {
+                handle_page_fault(&mut pt, &mut ft, &regions, addr, PageFault::Store, &mut tlb)
+            },
+            Err(FaultError::AccessDenied {
+                addr,
+                fault: PageFault::Store
+            })
+        );
+
+        // Accesses the region does not allow, or outside of any region, fail
+        let read_only = heap + 8 * PAGE_SIZE_B;
+        assert_eq!(
+            unsafe {
+                handle_page_fault(
+                    &mut pt,
+                    &mut ft,
+                    &regions,
+                    read_only,
+                    PageFault::Store,
+                    &mut tlb,
+                )
+            },
+            Err(FaultError::AccessDenied {
+                addr: read_only,
+                fault: PageFault::Store
+            })
+        );
+        assert_eq!(
+            unsafe {
+                handle_page_fault(
+                    &mut pt,
+                    &mut ft,
+                    &regions,
+                    addr,
+                    PageFault::Instruction,
+                    &mut tlb,
+                )
+            },
+            Err(FaultError::AccessDenied {
+                addr,
+                fault: PageFault::Instruction
+            })
+        );
+        assert_eq!(
+            unsafe {
+                handle_page_fault(
+                    &mut pt,
+                    &mut ft,
+                    &regions,
+                    heap + 5 * PAGE_SIZE_B,
+                    PageFault::Load,
+                    &mut tlb,
+                )
+            },
+            Err(FaultError::NotReserved {
+                addr: heap + 5 * PAGE_SIZE_B
+            })
+        );
+
+        // Faults only ever touch the page which faulted
+        assert_eq!(tlb.pages(), 1);
+        tlb.flush();
+
+        // Releasing the region hands back its frames and tables, but for the
+        // one beneath the root which address spaces may share
+        let region = unsafe { regions.release(&mut pt, &mut ft, heap, &mut tlb) }.unwrap();
+        assert_eq!(region.pages, 4);
+        assert_eq!(regions.find(addr), None);
+        assert_eq!(pt.translate(addr), None);
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+        assert_eq!(ft.count_frames(0, FrameState::Free), free);
+    }
+
+    #[test]
+    fn user_lazy_pages_belong_to_the_region_owner() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+
+        let (mut pt, root) = bootstrapped(&mut ft);
+
+        let user = PageTableEntry::FLAG_R | PageTableEntry::FLAG_W | PageTableEntry::FLAG_U;
+        let stack = 1 << 30;
+
+        let mut regions = LazyRegions::new();
+        unsafe { regions.reserve(&mut pt, &mut ft, stack, 2, user, 7) }.unwrap();
+
+        let mut tlb = TlbFlush::local();
+        unsafe {
+            handle_page_fault(
+                &mut pt,
+                &mut ft,
+                &regions,
+                stack,
+                PageFault::Store,
+                &mut tlb,
+            )
+        }
+        .unwrap();
+        tlb.flush();
+
+        let (phys_addr, _) = walk(root, stack).unwrap();
+        assert_eq!(
+            ft.frames_owned_by(7).collect::<Vec<_>>(),
+            [(phys_addr, FrameState::User)]
+        );
+        assert_eq!(ft.count_frames(0, FrameState::User), 0);
+
+        unsafe { regions.release(&mut pt, &mut ft, stack, &mut tlb) }.unwrap();
+        assert_eq!(ft.frames_owned_by(7).count(), 0);
+    }
+
+    #[test]
+    fn accessed_bits_only_go_on_the_entry_walked() {
+        let rw = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W;
+        let (a, d) = (PageTableEntry::FLAG_A, PageTableEntry::FLAG_D);
+
+        let mut walked = PageTableEntry(0);
+        walked.set(0x8000_1000, rw);
+
+        // The hardware set `A` meanwhile, `D` is still added
+        let mut entry = PageTableEntry(walked.0 | a);
+        set_accessed(&mut entry, walked, a | d);
+        assert_eq!(entry.0, walked.0 | a | d);
+
+        // Entries remapped or unmapped meanwhile are left alone
+        let mut remapped = PageTableEntry(0);
+        remapped.set(0x8000_2000, rw);
+        let before = remapped;
+        set_accessed(&mut remapped, walked, a | d);
+        assert_eq!(remapped, before);
+
+        let mut unmapped = PageTableEntry(0);
+        set_accessed(&mut unmapped, walked, a);
+        assert!(unmapped.is_unused());
+    }
+}
@@ -0,0 +1,214 @@
+//!
+//! Paging modes
+//!
+//! Sv39, Sv48 and Sv57 share the page table entry format and only differ in
+//! how many levels of tables a virtual address goes through. The mode is
+//! picked once during boot, before the kernel page table is built, and
+//! every walk of a page table follows it
+//!
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::PageSize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    ///
+    /// Three levels, 39 bit virtual addresses
+    ///
+    Sv39,
+
+    ///
+    /// Four levels, 48 bit virtual addresses
+    ///
+    Sv48,
+
+    ///
+    /// Five levels, 57 bit virtual addresses
+    ///
+    Sv57,
+}
+
+impl PagingMode {
+    ///
+    /// Every mode, deepest first
+    ///
+    pub const ALL: [PagingMode; 3] = [PagingMode::Sv57, PagingMode::Sv48, PagingMode::Sv39];
+
+    pub const fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    ///
+    /// The value of the `MODE` field of `satp`
+    ///
+    pub const fn satp_mode(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+            PagingMode::Sv57 => 10,
+        }
+    }
+
+    pub fn from_satp_mode(mode: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.satp_mode() == mode)
+    }
+
+    ///
+    /// Parse the `mmu-type` property of a device tree cpu node, such as `riscv,sv48`
+    ///
+    pub fn from_mmu_type(mmu_type: &str) -> Option<Self> {
+        // Properties are stored with their nul terminator
+        match mmu_type.trim_end_matches('\0') {
+            "riscv,sv39" => Some(PagingMode::Sv39),
+            "riscv,sv48" => Some(PagingMode::Sv48),
+            "riscv,sv57" => Some(PagingMode::Sv57),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Number of significant bits in a virtual address
+    ///
+    pub const fn virt_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    ///
+    /// Number of virtual pages in the address space
+    ///
+    pub const fn page_count(self) -> usize {
+        1 << (9 * self.levels())
+    }
+
+    ///
+    /// The size of a leaf in the root table
+    ///
+    pub fn root_size(self) -> PageSize {
+        PageSize::from_level(self.levels() - 1)
+    }
+
+    ///
+    /// The leaf sizes of this mode, from the root table down
+    ///
+    pub fn sizes(self) -> impl Iterator<Item = PageSize> {
+        (0..self.levels()).rev().map(PageSize::from_level)
+    }
+
+    ///
+    /// The virtual page number of `virt_addr`, dropping the sign extension
+    ///
+    pub fn vpn(self, virt_addr: usize) -> usize {
+        (virt_addr >> 12) & (self.page_count() - 1)
+    }
+
+    ///
+    /// Sign extend `virt_addr` from its highest significant bit
+    ///
+    pub fn canonical(self, virt_addr: usize) -> usize {
+        let unused = usize::BITS as usize - self.virt_bits();
+        (((virt_addr << unused) as isize) >> unused) as usize
+    }
+
+    pub fn is_canonical(self, virt_addr: usize) -> bool {
+        self.canonical(virt_addr) == virt_addr
+    }
+
+    ///
+    /// The `satp` value which enables this mode with the root table at `root_phys_addr`
+    ///
+    pub fn satp(self, root_phys_addr: usize, asid: u16) -> usize {
+        (self.satp_mode() << 60) | ((asid as usize) << 44) | (root_phys_addr >> 12)
+    }
+}
+
+#[cfg(not(test))]
+static PAGING_MODE: AtomicU8 = AtomicU8::new(PagingMode::Sv39 as u8);
+
+// Tests run on many threads at once, each with its own page tables
+#[cfg(test)]
+std::thread_local! {
+    static PAGING_MODE: AtomicU8 = const { AtomicU8::new(PagingMode::Sv39 as u8) };
+}
+
+///
+/// The paging mode every page table is walked with, `Sv39` until set
+///
+pub fn paging_mode() -> PagingMode {
+    #[cfg(not(test))]
+    let mode = PAGING_MODE.load(Ordering::Relaxed);
+
+    #[cfg(test)]
+    let mode = PAGING_MODE.with(|mode| mode.load(Ordering::Relaxed));
+
+    match mode {
+        0 => PagingMode::Sv39,
+        1 => PagingMode::Sv48,
+        _ => PagingMode::Sv57,
+    }
+}
+
+///
+/// Set the paging mode every page table is walked with
+///
+/// # Safety
+///
+/// No page table may have been built in another mode, this is meant to be
+/// called once before the kernel page table is bootstrapped
+///
+pub unsafe fn set_paging_mode(mode: PagingMode) {
+    #[cfg(not(test))]
+    PAGING_MODE.store(mode as u8, Ordering::Relaxed);
+
+    #[cfg(test)]
+    PAGING_MODE.with(|m| m.store(mode as u8, Ordering::Relaxed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_sign_extended_per_mode() {
+        let addr = 0x0000_4000_0000_1000;
+
+        assert_eq!(PagingMode::Sv39.canonical(addr), 0x1000);
+        assert!(PagingMode::Sv48.is_canonical(addr));
+        assert!(!PagingMode::Sv39.is_canonical(addr));
+
+        let high = PagingMode::Sv48.canonical(1 << 47);
+        assert_eq!(high, 0xFFFF_8000_0000_0000);
+        assert_eq!(PagingMode::Sv48.vpn(high), 1 << 35);
+        assert_eq!(PagingMode::Sv57.canonical(1 << 47), 1 << 47);
+
+        assert_eq!(
+            PagingMode::Sv48.satp(0x8020_0000, 3),
+            (9 << 60) | (3 << 44) | 0x80200
+        );
+    }
+
+    #[test]
+    fn modes_parse_from_the_device_tree() {
+        assert_eq!(
+            PagingMode::from_mmu_type("riscv,sv48\0"),
+            Some(PagingMode::Sv48)
+        );
+        assert_eq!(PagingMode::from_mmu_type("riscv,sv32"), None);
+        assert_eq!(PagingMode::from_satp_mode(10), Some(PagingMode::Sv57));
+
+        let sizes: std::vec::Vec<_> = PagingMode::Sv48.sizes().collect();
+        assert_eq!(
+            sizes,
+            [
+                PageSize::Tera,
+                PageSize::Giga,
+                PageSize::Mega,
+                PageSize::Page
+            ]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//!
+//! The physmap
+//!
+//! All RAM is mapped linearly at `physmap_base()` in the upper half, so
+//! `phys_to_ptr` can keep reaching frames and page tables once RAM is no
+//! longer identity mapped. The kernel switches over by setting the physical
+//! offset to `physmap_base()` right after enabling paging
+//!
+
+use crate::PAGE_SIZE_B;
+use crate::frame_table::FrameTable;
+
+use super::virt_map::{LinearMapError, PageRegion, virtual_map_linear_with_flags};
+use super::{PageTable, PageTableEntry, paging_mode};
+
+///
+/// The root table index the physmap starts at
+///
+/// It runs up to the end of the root table, leaving a quarter of the
+/// address space for physical addresses
+///
+pub const PHYSMAP_ROOT_INDEX: usize = 384;
+
+///
+/// The virtual address physical address `0` is mapped to
+///
+pub fn physmap_base() -> usize {
+    let mode = paging_mode();
+    mode.canonical(PHYSMAP_ROOT_INDEX << mode.root_size().shift())
+}
+
+///
+/// The first physical address past what the physmap can hold
+///
+pub fn physmap_limit() -> usize {
+    (512 - PHYSMAP_ROOT_INDEX) << paging_mode().root_size().shift()
+}
+
+///
+/// Map the physical memory `phys_addr..phys_addr + size`, rounded out to
+/// whole pages, into the physmap
+///
+/// The memory is readable and writable but never executable, and global
+/// as every address space shares it
+///
+/// # Safety
+///
+/// The range must not hold anything which faults when accessed, like
+/// memory protected by the firmware
+///
+pub unsafe fn map_physical(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    phys_addr: usize,
+    size: usize,
+) -> Result<(), LinearMapError> {
+    let start = phys_addr & !(PAGE_SIZE_B - 1);
+    let end = (phys_addr + size).next_multiple_of(PAGE_SIZE_B);
+
+    assert!(
+        end <= physmap_limit(),
+        "physical memory at {end:#X} is past the end of the physmap"
+    );
+
+    let region = PageRegion {
+        address: start,
+        count: (end - start) / PAGE_SIZE_B,
+    };
+
+    // Kernel pages are never aged, `A` and `D` are set up front so harts
+    // which leave them to software don't fault on them
+    let flags = PageTableEntry::FLAG_V
+        | PageTableEntry::FLAG_R
+        | PageTableEntry::FLAG_W
+        | PageTableEntry::FLAG_G
+        | PageTableEntry::FLAG_A
+        | PageTableEntry::FLAG_D;
+
+    unsafe {
+        virtual_map_linear_with_flags(
+            page_table,
+            frame_table,
+            region,
+            physmap_base() + start,
+            flags,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page_table::{PageSize, PagingMode, pt_virt_start, set_paging_mode};
+    use crate::testing::{Arena, bootstrapped};
+
+    const RAM_BASE: usize = 0x8000_0000;
+
+    #[test]
+    fn ram_is_reachable_through_the_physmap() {
+        for mode in [PagingMode::Sv39, PagingMode::Sv48] {
+            unsafe { set_paging_mode(mode) };
+
+            let arena = Arena::at(RAM_BASE, 64);
+            let mut ft = arena.frame_table();
+
+            let (mut pt, _) = bootstrapped(&mut ft);
+
+            // The simulated RAM, plus a few gigabytes of large pages
+            unsafe { map_physical(&mut pt, &mut ft, RAM_BASE, 64 * PAGE_SIZE_B) }.unwrap();
+            unsafe { map_physical(&mut pt, &mut ft, 4 << 30, (2 << 30) + 0x800) }.unwrap();
+
+            assert!(physmap_base() > pt_virt_start());
+
+            for phys_addr in [RAM_BASE, RAM_BASE + 63 * PAGE_SIZE_B + 8, (6 << 30) + 0x7FF] {
+                let translation = pt.walk(physmap_base() + phys_addr).unwrap();
+
+                assert_eq!(translation.phys_addr, phys_addr);
+                assert!(translation.is_writable() && !translation.is_executable());
+            }
+
+            assert_eq!(
+                pt.walk(physmap_base() + (4 << 30)).unwrap().size,
+                PageSize::Giga
+            );
+            assert_eq!(
+                pt.translate(physmap_base() + RAM_BASE + 64 * PAGE_SIZE_B),
+                None
+            );
+
+            // Overlapping the physmap is refused
+            assert!(unsafe { map_physical(&mut pt, &mut ft, RAM_BASE, 1) }.is_err());
+        }
+    }
+}
@@ -1,7 +1,19 @@
-use crate::{frame_table::FrameTable, page_table::PageTableEntry};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use super::{PageTable, PageTableIndex};
+use crate::{
+    PAGE_SIZE_B,
+    frame_table::{FrameTable, RefCountError},
+    page_table::PageTableEntry,
+    tlb::TlbFlush,
+};
 
+use super::extensions::{MemoryType, NAPOT_PAGES, extensions};
+use super::{
+    MapError, PageSize, PageTable, PageTableIndex, SELF_REF_ROOT_INDEX, Translation, paging_mode,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PageRegion {
     ///
     /// Page-aligned memory address
@@ -31,76 +43,1503 @@ pub unsafe fn virtual_map_linear(
     physical_region: PageRegion,
     virtual_start_addr: usize,
 ) -> Result<(), LinearMapError> {
-    // Number of pages an entry of each level can back
-    let virt_page_num = virtual_start_addr >> 12;
-    let virt_end_page = virt_page_num + physical_region.count;
+    let flags = PageTableEntry::FLAG_V
+        | PageTableEntry::FLAG_R
+        | PageTableEntry::FLAG_W
+        | PageTableEntry::FLAG_X;
 
-    log::debug!(
-        "Making sure that virtual pages {virt_page_num:#X}..{virt_end_page:#X} are mappable"
+    unsafe {
+        virtual_map_linear_with_flags(
+            page_table,
+            frame_table,
+            physical_region,
+            virtual_start_addr,
+            flags,
+        )
+    }
+}
+
+///
+/// Like `virtual_map_linear`, with the leaves getting `flags` instead of
+/// being readable, writable and executable
+///
+/// # Safety
+///
+/// The physical memory must be fine to access with `flags`
+///
+pub unsafe fn virtual_map_linear_with_flags(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    physical_region: PageRegion,
+    virtual_start_addr: usize,
+    flags: u64,
+) -> Result<(), LinearMapError> {
+    assert!(
+        flags & PageTableEntry::FLAG_V != 0 && PageTableEntry(flags).is_leaf(),
+        "linear mappings need valid leaf flags"
+    );
+    assert_eq!(
+        flags & PageTableEntry::FLAG_N,
+        0,
+        "NAPOT leaves are picked by the mapping itself"
     );
 
-    for i in virt_page_num..virt_end_page {
-        let parts = decompose_virt_pageaddr(i);
-        if !page_table.is_free(parts.l1_index, parts.l2_index, parts.l3_index) {
-            return Err(LinearMapError::NotFree);
+    let extensions = extensions();
+
+    // The memory type bits are reserved without Svpbmt
+    let flags = if extensions.svpbmt {
+        flags
+    } else {
+        flags & !PageTableEntry::PBMT_MASK
+    };
+
+    for addr in [physical_region.address, virtual_start_addr] {
+        if addr & (PAGE_SIZE_B - 1) != 0 {
+            return Err(LinearMapError::Map {
+                virt_addr: virtual_start_addr,
+                error: MapError::Misaligned { addr },
+            });
         }
     }
 
+    let mode = paging_mode();
+    let end = mode.vpn(virtual_start_addr) + physical_region.count;
+
+    if end > mode.page_count() {
+        return Err(LinearMapError::Map {
+            virt_addr: virtual_start_addr,
+            error: MapError::IndexOutOfRange { index: end },
+        });
+    }
+
+    let range = page_range(virtual_start_addr, physical_region.count);
+
+    log::debug!(
+        "Making sure that virtual pages {:#X}..{:#X} are mappable",
+        range.start,
+        range.end
+    );
+
+    if !range_is_free(page_table.entries, paging_mode().root_size(), 0, &range) {
+        return Err(LinearMapError::NotFree);
+    }
+
     let first_hardware_page = physical_region.address >> 12;
+    let mut mapped = 0;
 
-    for i in 0..physical_region.count {
-        let hardware_page = first_hardware_page + i;
-        let virtual_page = (virtual_start_addr >> 12) + i;
+    while mapped < physical_region.count {
+        let virtual_page = range.start + mapped;
+        let hardware_page = first_hardware_page + mapped;
+        let remaining = physical_region.count - mapped;
 
-        let parts = decompose_virt_pageaddr(virtual_page);
+        // Use the largest leaf both addresses are aligned to which fits, as
+        // long as no table already sits in its place
+        for size in paging_mode().sizes() {
+            let fits = |pages: usize| {
+                virtual_page.is_multiple_of(pages)
+                    && hardware_page.is_multiple_of(pages)
+                    && remaining >= pages
+            };
 
-        unsafe {
-            page_table.virtually_map(
+            // Below the large pages, 64KiB runs take a single TLB entry
+            let napot = size == PageSize::Page && extensions.svnapot && fits(NAPOT_PAGES);
+            let pages = if napot { NAPOT_PAGES } else { size.pages() };
+
+            if !fits(pages) {
+                continue;
+            }
+
+            let placed = if napot {
+                unsafe {
+                    map_napot(
+                        page_table,
+                        frame_table,
+                        virtual_page,
+                        hardware_page << 12,
+                        flags,
+                    )
+                }
+            } else {
+                unsafe {
+                    map_leaf(
+                        page_table,
+                        frame_table,
+                        virtual_page,
+                        hardware_page << 12,
+                        size,
+                        flags,
+                    )
+                }
+            };
+
+            match placed {
+                Ok(()) => {
+                    mapped += pages;
+                    break;
+                }
+                // A table is in the way of a large page, try a smaller one
+                Err(MapError::AlreadyMapped) if size != PageSize::Page => {}
+                Err(error) => {
+                    // Take back what was mapped so far, the tables above it
+                    // may be shared with other mappings. Nothing has used
+                    // the pages yet, but harts may still have walked to them
+                    // speculatively, in whichever table this is
+                    let mut tlb = TlbFlush::kernel();
+                    let _ = unsafe {
+                        unmap_shared(page_table, frame_table, virtual_start_addr, mapped, &mut tlb)
+                    };
+                    tlb.flush_freeing(frame_table);
+
+                    return Err(LinearMapError::Map {
+                        virt_addr: page_virt_addr(virtual_page),
+                        error,
+                    });
+                }
+            }
+        }
+    }
+
+    log::info!("Wrote all mappings");
+
+    Ok(())
+}
+
+///
+/// Map the device memory of `physical_region` at `virtual_start_addr`,
+/// readable and writable by the kernel only, as `memory_type`
+///
+/// The memory type needs Svpbmt, without it the platform's attributes for
+/// the physical range apply
+///
+/// # Safety
+///
+/// The physical memory must be fine to access as `memory_type`
+///
+pub unsafe fn map_device(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    physical_region: PageRegion,
+    virtual_start_addr: usize,
+    memory_type: MemoryType,
+) -> Result<(), LinearMapError> {
+    let flags = PageTableEntry::FLAG_V
+        | PageTableEntry::FLAG_R
+        | PageTableEntry::FLAG_W
+        | PageTableEntry::FLAG_G
+        | PageTableEntry::FLAG_A
+        | PageTableEntry::FLAG_D
+        | memory_type.flags();
+
+    unsafe {
+        virtual_map_linear_with_flags(
+            page_table,
+            frame_table,
+            physical_region,
+            virtual_start_addr,
+            flags,
+        )
+    }
+}
+
+///
+/// Remove every mapping of the `count` virtual pages starting at `virtual_start_addr`
+///
+/// Pages which aren't mapped are skipped. Every page which was unmapped is
+/// added to `tlb`, along with the intermediate page tables which end up
+/// empty, `TlbFlush::flush_freeing` frees those.
+///
+/// Returns the physical memory which was mapped there, merged into runs of
+/// contiguous frames, so the caller can free it once `tlb` is flushed
+///
+/// Large page leaves which are only partially covered are split up first,
+/// which fails when there is no frame left for the new tables
+///
+/// # Safety
+///
+/// Nothing may still use the unmapped memory, on this or any other hart
+///
+pub unsafe fn unmap(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    virtual_start_addr: usize,
+    count: usize,
+    tlb: &mut TlbFlush,
+) -> Result<Vec<PageRegion>, MapError> {
+    unsafe { unmap_pages(page_table, frame_table, virtual_start_addr, count, false, tlb) }
+}
+
+///
+/// Like `unmap`, but the tables right beneath the root are kept even when
+/// they end up empty
+///
+/// Address spaces copy the kernel's root entries, so a table they point at
+/// must not be freed while the kernel still hands out memory beneath it
+///
+/// # Safety
+///
+/// Nothing may still use the unmapped memory, on this or any other hart
+///
+pub unsafe fn unmap_shared(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    virtual_start_addr: usize,
+    count: usize,
+    tlb: &mut TlbFlush,
+) -> Result<Vec<PageRegion>, MapError> {
+    unsafe { unmap_pages(page_table, frame_table, virtual_start_addr, count, true, tlb) }
+}
+
+unsafe fn unmap_pages(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    virtual_start_addr: usize,
+    count: usize,
+    keep_root_tables: bool,
+    tlb: &mut TlbFlush,
+) -> Result<Vec<PageRegion>, MapError> {
+    let range = checked_page_range(virtual_start_addr, count);
+
+    unsafe { split_leaves_around(page_table, frame_table, &range, tlb) }?;
+
+    let mut unmapped: Vec<PageRegion> = Vec::new();
+    let mut empty_tables = Vec::new();
+
+    unsafe {
+        walk_leaves(
+            page_table,
+            paging_mode().root_size(),
+            0,
+            &range,
+            if keep_root_tables {
+                KeepTables::Root
+            } else {
+                KeepTables::None
+            },
+            &mut empty_tables,
+            &mut |entry, vpn, span| {
+                let region = PageRegion {
+                    address: entry.leaf_phys_addr(vpn),
+                    count: span,
+                };
+
+                match unmapped.last_mut() {
+                    Some(last) if last.address + last.count * PAGE_SIZE_B == region.address => {
+                        last.count += region.count;
+                    }
+                    _ => unmapped.push(region),
+                }
+
+                entry.clear();
+                tlb.add(page_virt_addr(vpn), span);
+            },
+        )
+    };
+
+    // Other harts may still walk through the tables until `tlb` is flushed
+    for table in empty_tables {
+        unsafe { page_table.meta_unlink_page_table(frame_table, table) };
+        tlb.add_table(table);
+    }
+
+    Ok(unmapped)
+}
+
+///
+/// Replace the permissions of every mapped page among the `count` virtual
+/// pages starting at `virtual_start_addr` with `flags`
+///
+/// `flags` holds the new `R`, `W`, `X`, `U` and `G` bits, the physical
+/// address and every other bit of the entries is kept. Pages which aren't
+/// mapped are skipped.
+///
+/// Returns the number of 4KiB pages whose permissions were rewritten, large
+/// pages which are only partially covered are split up first, which fails
+/// when there is no frame left for the new tables. Every rewritten page is
+/// added to `tlb`
+///
+/// # Safety
+///
+/// Nothing may rely on the permissions being taken away until `tlb` is
+/// flushed
+///
+pub unsafe fn protect(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    virtual_start_addr: usize,
+    count: usize,
+    flags: u64,
+    tlb: &mut TlbFlush,
+) -> Result<usize, MapError> {
+    assert_eq!(
+        flags & !Translation::PERMISSIONS,
+        0,
+        "protect: only permission bits can be set"
+    );
+    assert!(
+        flags & PageTableEntry::FLAG_R != 0 || flags & PageTableEntry::FLAG_X != 0,
+        "protect: leaves must stay readable or executable"
+    );
+
+    let range = checked_page_range(virtual_start_addr, count);
+
+    unsafe { split_leaves_around(page_table, frame_table, &range, tlb) }?;
+
+    let mut protected = 0;
+
+    unsafe {
+        walk_leaves(
+            page_table,
+            paging_mode().root_size(),
+            0,
+            &range,
+            KeepTables::All,
+            &mut Vec::new(),
+            &mut |entry, vpn, span| {
+                entry.0 = (entry.0 & !Translation::PERMISSIONS) | flags;
+                tlb.add(page_virt_addr(vpn), span);
+                protected += span;
+            },
+        )
+    };
+
+    Ok(protected)
+}
+
+///
+/// Share every user page among the `count` virtual pages starting at
+/// `virtual_start_addr` of `parent` with `child`, copy-on-write
+///
+/// Both copies of a writable page lose their `W` bit and are marked with
+/// `FLAG_COW`, read-only pages are shared as they are. Every frame takes
+/// another reference, the first store to either copy then gives it a frame
+/// of its own. Pages without `U` are left out of `child`
+///
+/// Returns the number of 4KiB pages shared. On failure the pages shared so
+/// far stay mapped in `child` with their references taken. The parent's
+/// pages which lost their `W` bit are added to `tlb`
+///
+/// # Safety
+///
+/// `child` must have nothing mapped in the range, the parent's pages may
+/// still be written to until `tlb` is flushed
+///
+pub unsafe fn clone_cow(
+    parent: &mut PageTable,
+    child: &mut PageTable,
+    frame_table: &mut FrameTable,
+    virtual_start_addr: usize,
+    count: usize,
+    tlb: &mut TlbFlush,
+) -> Result<usize, CloneError> {
+    let range = checked_page_range(virtual_start_addr, count);
+
+    unsafe { split_leaves_around(parent, frame_table, &range, tlb) }.map_err(|error| {
+        CloneError::Map {
+            virt_addr: virtual_start_addr,
+            error,
+        }
+    })?;
+
+    let mut shared = 0;
+    let mut failed = None;
+
+    unsafe {
+        walk_leaves(
+            parent,
+            paging_mode().root_size(),
+            0,
+            &range,
+            KeepTables::All,
+            &mut Vec::new(),
+            &mut |entry, vpn, span| {
+                if failed.is_some() || entry.0 & PageTableEntry::FLAG_U == 0 {
+                    return;
+                }
+
+                let virt_addr = page_virt_addr(vpn);
+                let phys_addr = entry.leaf_phys_addr(vpn);
+
+                let unref = |frame_table: &mut FrameTable, pages: usize| {
+                    for page in 0..pages {
+                        frame_table
+                            .frame_unref(phys_addr + page * PAGE_SIZE_B)
+                            .expect("clone_cow: failed to drop a reference");
+                    }
+                };
+
+                for page in 0..span {
+                    if let Err(error) = frame_table.frame_ref(phys_addr + page * PAGE_SIZE_B) {
+                        unref(frame_table, page);
+                        failed = Some(CloneError::RefCount { virt_addr, error });
+                        return;
+                    }
+                }
+
+                let mut flags = entry.0 & PageTableEntry::FLAGS;
+
+                if flags & (PageTableEntry::FLAG_W | PageTableEntry::FLAG_COW) != 0 {
+                    flags = (flags & !PageTableEntry::FLAG_W) | PageTableEntry::FLAG_COW;
+                }
+
+                let size = paging_mode()
+                    .sizes()
+                    .find(|size| size.pages() == span)
+                    .unwrap();
+
+                // NAPOT runs are copied entry by entry, PPN encoding included
+                if let Err(error) =
+                    map_leaf(child, frame_table, vpn, entry.phys_addr(), size, flags)
+                {
+                    unref(frame_table, span);
+                    failed = Some(CloneError::Map { virt_addr, error });
+                    return;
+                }
+
+                entry.set(entry.phys_addr() as u64, flags);
+                tlb.add(virt_addr, span);
+                shared += span;
+            },
+        )
+    };
+
+    match failed {
+        Some(error) => Err(error),
+        None => Ok(shared),
+    }
+}
+
+///
+/// Clear the `A` bit of every user page among the `count` virtual pages
+/// starting at `virtual_start_addr`, calling `sample` with the physical
+/// memory behind each leaf and whether it was accessed since its bit was
+/// last cleared
+///
+/// Large leaves only partly in the range are sampled whole, nothing but
+/// the `A` bits of the table is changed
+///
+/// Returns the number of 4KiB pages sampled. The pages which lost their
+/// `A` bit are added to `tlb`, accesses through translations cached before
+/// it is flushed go unseen
+///
+/// # Safety
+///
+/// The page table must not be changed on another hart meanwhile, hardware
+/// setting `A` and `D` is fine
+///
+pub unsafe fn sample_accessed(
+    page_table: &mut PageTable,
+    virtual_start_addr: usize,
+    count: usize,
+    tlb: &mut TlbFlush,
+    sample: &mut impl FnMut(PageRegion, bool),
+) -> usize {
+    let range = checked_page_range(virtual_start_addr, count);
+
+    // No leaf straddles the edge of whole root entries, the leaves outside
+    // of the range are skipped instead of split
+    let span = paging_mode().root_size().pages();
+    let whole = range.start / span * span..range.end.div_ceil(span) * span;
+
+    let mut sampled = 0;
+
+    unsafe {
+        walk_leaves(
+            page_table,
+            paging_mode().root_size(),
+            0,
+            &whole,
+            KeepTables::All,
+            &mut Vec::new(),
+            &mut |entry, vpn, span| {
+                let overlaps = vpn < range.end && range.start < vpn + span;
+
+                if !overlaps || entry.0 & PageTableEntry::FLAG_U == 0 {
+                    return;
+                }
+
+                // Hardware may set `D` meanwhile, which must not be lost
+                let bits = AtomicU64::from_ptr(&mut entry.0);
+                let previous = bits.fetch_and(!PageTableEntry::FLAG_A, Ordering::AcqRel);
+                let accessed = previous & PageTableEntry::FLAG_A != 0;
+
+                let region = PageRegion {
+                    address: entry.leaf_phys_addr(vpn),
+                    count: span,
+                };
+
+                sample(region, accessed);
+
+                if accessed {
+                    tlb.add(page_virt_addr(vpn), span);
+                }
+
+                sampled += span;
+            },
+        )
+    };
+
+    sampled
+}
+
+///
+/// Turn a virtual start address and page count into a range of virtual page numbers
+///
+fn page_range(virtual_start_addr: usize, count: usize) -> core::ops::Range<usize> {
+    let mode = paging_mode();
+
+    let start = mode.vpn(virtual_start_addr);
+    let end = start + count;
+
+    assert!(
+        end <= mode.page_count(),
+        "virtual range runs past the end of the address space"
+    );
+
+    start..end
+}
+
+///
+/// Like `page_range`, but refusing to touch the self-ref section
+///
+fn checked_page_range(virtual_start_addr: usize, count: usize) -> core::ops::Range<usize> {
+    let core::ops::Range { start, end } = page_range(virtual_start_addr, count);
+
+    let span = paging_mode().root_size().pages();
+    let self_ref = SELF_REF_ROOT_INDEX * span..(SELF_REF_ROOT_INDEX + 1) * span;
+    assert!(
+        end <= self_ref.start || start >= self_ref.end,
+        "the page table self-ref section can not be changed"
+    );
+
+    start..end
+}
+
+///
+/// The sign extended virtual address of virtual page `vpn`
+///
+fn page_virt_addr(vpn: usize) -> usize {
+    paging_mode().canonical(vpn << 12)
+}
+
+///
+/// Whether no leaf beneath `entries`, a table of leaves of `size` mapping
+/// from virtual page `base`, overlaps `range`
+///
+fn range_is_free(
+    entries: &[PageTableEntry],
+    size: PageSize,
+    base: usize,
+    range: &core::ops::Range<usize>,
+) -> bool {
+    let span = size.pages();
+    let first = range.start.saturating_sub(base) / span;
+    let last = (range.end - base).div_ceil(span).min(512);
+
+    entries[first..last]
+        .iter()
+        .enumerate()
+        .all(|(index, entry)| {
+            if !entry.is_valid() {
+                return true;
+            }
+
+            let Some(child_size) = size.smaller().filter(|_| !entry.is_leaf()) else {
+                return false;
+            };
+
+            let child = unsafe { PageTable::from_pointer(entry.phys_addr()) };
+            range_is_free(
+                child.entries,
+                child_size,
+                base + (first + index) * span,
+                range,
+            )
+        })
+}
+
+///
+/// The entry on the way to virtual page `vpn` at the level of `size`, if
+/// every table above it exists
+///
+pub(super) fn entry_at(page_table: &mut PageTable, vpn: usize, size: PageSize) -> Option<&mut PageTableEntry> {
+    let mut entries = &mut *page_table.entries;
+
+    for level in paging_mode().sizes() {
+        let index = (vpn >> (level.shift() - 12)) & 0x1FF;
+
+        if level == size {
+            return Some(&mut entries[index]);
+        }
+
+        let entry = entries[index];
+
+        if !entry.is_valid() || entry.is_leaf() {
+            return None;
+        }
+
+        entries = unsafe { PageTable::from_pointer(entry.phys_addr()) }.entries;
+    }
+
+    None
+}
+
+///
+/// Place a leaf of `size` mapping virtual page `vpn` to `phys_addr`,
+/// allocating the tables above it as needed
+///
+/// Fails with `MapError::AlreadyMapped` when the entry is already in use, and
+/// `MapError::HugePageConflict` when a larger leaf covers it
+///
+pub(super) unsafe fn map_leaf(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    vpn: usize,
+    phys_addr: usize,
+    size: PageSize,
+    flags: u64,
+) -> Result<(), MapError> {
+    for level in paging_mode().sizes().take_while(|&level| level != size) {
+        let entry = entry_at(page_table, vpn, level).expect("parent table is missing");
+
+        if entry.is_unused() {
+            let table = unsafe { page_table.meta_allocate_page_table(frame_table) }?;
+
+            entry_at(page_table, vpn, level)
+                .unwrap()
+                .set(table.phys_addr as u64, PageTableEntry::FLAG_V);
+        } else if entry.is_leaf() {
+            return Err(MapError::HugePageConflict);
+        }
+    }
+
+    let entry = entry_at(page_table, vpn, size).unwrap();
+
+    if !entry.is_unused() {
+        return Err(MapError::AlreadyMapped);
+    }
+
+    entry.set(phys_addr as u64, flags);
+
+    Ok(())
+}
+
+///
+/// Place the 16 leaves of a NAPOT run mapping the 64KiB at virtual page `vpn`
+/// to `phys_addr`, both of which are aligned to it
+///
+/// Nothing is left behind when a leaf can not be placed, the run is only
+/// valid as a whole
+///
+unsafe fn map_napot(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    vpn: usize,
+    phys_addr: usize,
+    flags: u64,
+) -> Result<(), MapError> {
+    // The low bits of the PPN encode the size of the run
+    let encoded = phys_addr | (NAPOT_PAGES / 2) << 12;
+
+    for page in 0..NAPOT_PAGES {
+        let placed = unsafe {
+            map_leaf(
+                page_table,
                 frame_table,
-                parts.l1_index,
-                parts.l2_index,
-                parts.l3_index,
-                hardware_page << 12,
-                PageTableEntry::FLAG_V | PageTableEntry::FLAG_R | PageTableEntry::FLAG_W | PageTableEntry::FLAG_X,
+                vpn + page,
+                encoded,
+                PageSize::Page,
+                flags | PageTableEntry::FLAG_N,
             )
         };
+
+        if let Err(error) = placed {
+            // Only the leaves go, the table they share is left in place
+            for placed in vpn..vpn + page {
+                entry_at(page_table, placed, PageSize::Page).unwrap().clear();
+            }
+
+            return Err(error);
+        }
     }
 
-    log::info!("Wrote all mappings");
+    Ok(())
+}
+
+///
+/// Split the large pages which straddle either end of `range`, so every
+/// leaf overlapping it lies completely inside
+///
+/// The split pages are added to `tlb`, the smaller leaves map the same
+/// memory, so stale translations do no harm in the meantime
+///
+pub(super) unsafe fn split_leaves_around(
+    page_table: &mut PageTable,
+    frame_table: &mut FrameTable,
+    range: &core::ops::Range<usize>,
+    tlb: &mut TlbFlush,
+) -> Result<(), MapError> {
+    for vpn in [range.start, range.end] {
+        while let Some(translation) = page_table.walk(page_virt_addr(vpn)) {
+            if vpn.is_multiple_of(translation.size.pages()) {
+                break;
+            }
+
+            // Pages are always aligned, so this is a large page
+            let smaller = translation.size.smaller().unwrap();
+
+            // The new table maps the same memory with 512 smaller leaves
+            let table = unsafe { page_table.meta_allocate_page_table(frame_table) }?;
+            let child = unsafe { PageTable::from_pointer(table.phys_addr) };
+            let flags = translation.entry.0 & PageTableEntry::FLAGS;
+
+            for (index, entry) in child.entries.iter_mut().enumerate() {
+                let phys_addr = translation.entry.phys_addr() + index * smaller.bytes();
+                entry.set(phys_addr as u64, flags);
+            }
+
+            entry_at(page_table, vpn, translation.size)
+                .unwrap()
+                .set(table.phys_addr as u64, PageTableEntry::FLAG_V);
+
+            // Fencing any address of a large page drops all of it
+            tlb.add(page_virt_addr(vpn), 1);
+        }
+
+        // A NAPOT run is only valid as a whole, it goes back to plain pages
+        let in_napot = page_table
+            .walk(page_virt_addr(vpn))
+            .is_some_and(|translation| translation.entry.is_napot());
+
+        if in_napot && !vpn.is_multiple_of(NAPOT_PAGES) {
+            let first = vpn - vpn % NAPOT_PAGES;
+
+            for page in first..first + NAPOT_PAGES {
+                let entry = entry_at(page_table, page, PageSize::Page).unwrap();
+                let flags = entry.0 & PageTableEntry::FLAGS & !PageTableEntry::FLAG_N;
+
+                entry.set(entry.leaf_phys_addr(page) as u64, flags);
+            }
+
+            tlb.add(page_virt_addr(first), NAPOT_PAGES);
+        }
+    }
 
     Ok(())
 }
 
+///
+/// Which of the tables `walk_leaves` finds empty stay in place
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeepTables {
+    ///
+    /// Every empty table is collected
+    ///
+    None,
+
+    ///
+    /// The tables beneath the root are kept, address spaces share them, the
+    /// ones further down are collected
+    ///
+    Root,
+
+    ///
+    /// Every table is kept, for walks which only change leaves
+    ///
+    All,
+}
+
+///
+/// Call `visit` with every leaf entry overlapping `range` beneath `table`,
+/// along with the first virtual page it maps and how many 4KiB pages it spans
+///
+/// `table` holds leaves of `size` and maps from virtual page `base`.
+/// Sub-tables which are left without any entry are cleared from `table`, and
+/// their physical addresses pushed onto `empty_tables`, unless `keep_tables`
+/// keeps them
+///
+unsafe fn walk_leaves(
+    table: &mut PageTable,
+    size: PageSize,
+    base: usize,
+    range: &core::ops::Range<usize>,
+    keep_tables: KeepTables,
+    empty_tables: &mut Vec<usize>,
+    visit: &mut impl FnMut(&mut PageTableEntry, usize, usize),
+) {
+    let span = size.pages();
+    let first = range.start.saturating_sub(base) / span;
+    let last = (range.end - base).div_ceil(span).min(512);
+
+    for index in first..last {
+        let vpn = base + index * span;
+        let entry = &mut table.entries[index];
+
+        if !entry.is_valid() {
+            continue;
+        }
+
+        if entry.is_leaf() {
+            assert!(
+                range.start <= vpn && vpn + span <= range.end,
+                "leaf straddles the edge of the range"
+            );
+
+            visit(entry, vpn, span);
+            continue;
+        }
+
+        let child_size = size
+            .smaller()
+            .expect("page table entry at the last level is not a leaf");
+
+        let mut child = unsafe { PageTable::from_pointer(entry.phys_addr()) };
+
+        let keep_below = match keep_tables {
+            KeepTables::All => KeepTables::All,
+            KeepTables::Root | KeepTables::None => KeepTables::None,
+        };
+
+        unsafe { walk_leaves(&mut child, child_size, vpn, range, keep_below, empty_tables, visit) };
+
+        if keep_tables == KeepTables::None && child.entries.iter().all(|e| e.is_unused()) {
+            empty_tables.push(entry.phys_addr());
+            entry.clear();
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum LinearMapError {
     ///
     /// Some memory is not free to be mapped
     ///
     NotFree,
+
+    ///
+    /// Mapping the page at `virt_addr` failed, nothing of the region is
+    /// left mapped
+    ///
+    Map { virt_addr: usize, error: MapError },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneError {
+    ///
+    /// Sharing the page at `virt_addr` with the child failed
+    ///
+    Map { virt_addr: usize, error: MapError },
+
+    ///
+    /// The frame behind the page at `virt_addr` can't take another reference
+    ///
+    RefCount {
+        virt_addr: usize,
+        error: RefCountError,
+    },
+
+    ///
+    /// The child's root table could not be allocated
+    ///
+    Root { error: MapError },
 }
 
 #[derive(Debug, Clone)]
 pub struct DecomposedVirtualPageAddress {
-    pub l1_index: PageTableIndex,
-    pub l2_index: PageTableIndex,
-    pub l3_index: PageTableIndex,
+    ///
+    /// The index into the table at each level, the last level first
+    ///
+    pub indexes: [PageTableIndex; 5],
+
+    pub levels: usize,
+}
+
+impl DecomposedVirtualPageAddress {
+    ///
+    /// The index into the table holding leaves of `size`
+    ///
+    pub fn index(&self, size: PageSize) -> PageTableIndex {
+        self.indexes[size.level()]
+    }
+
+    pub fn root_index(&self) -> PageTableIndex {
+        self.indexes[self.levels - 1]
+    }
 }
 
 ///
-/// Decompose a page address into its indexes
+/// Decompose a page address into its indexes, for the current paging mode
 ///
 /// NOTE: This specifically operates on 'page addresses'
 ///
 /// this means that the 12-bit inner index should be shifted out
 ///
 pub fn decompose_virt_pageaddr(addr: usize) -> DecomposedVirtualPageAddress {
-    let l3_index = addr & 0x1FF;
-    let l2_index = (addr >> 9) & 0x1FF;
-    let l1_index = (addr >> 18) & 0x1FF;
+    let levels = paging_mode().levels();
 
     DecomposedVirtualPageAddress {
-        l1_index: PageTableIndex::new(l1_index as u16),
-        l2_index: PageTableIndex::new(l2_index as u16),
-        l3_index: PageTableIndex::new(l3_index as u16),
+        indexes: core::array::from_fn(|level| {
+            let index = if level < levels {
+                (addr >> (9 * level)) & 0x1FF
+            } else {
+                0
+            };
+            PageTableIndex::new(index as u16)
+        }),
+        levels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_table::FrameState;
+    use crate::page_table::extensions::{Extensions, set_extensions};
+    use crate::page_table::{PagingMode, pt_virt_start, set_paging_mode};
+    use crate::testing::{Arena, XorShift, bootstrapped, sv39_canonical, walk};
+    use std::vec::Vec;
+
+    const RAM_BASE: usize = 0x8000_0000;
+    const RWX: u64 = PageTableEntry::FLAG_V
+        | PageTableEntry::FLAG_R
+        | PageTableEntry::FLAG_W
+        | PageTableEntry::FLAG_X;
+
+    #[test]
+    fn linear_mappings_translate() {
+        let arena = Arena::at(RAM_BASE, 1024);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+
+        let mut rng = XorShift(0xC0FFEE);
+        let mut mapped: Vec<(usize, usize, usize)> = Vec::new();
+
+        for _ in 0..60 {
+            // Keep to a few gigabytes of the lower half so regions collide now and then
+            let virt_page = rng.below(4 << 18);
+            let count = 1 + rng.below(40);
+            let phys_page = (RAM_BASE >> 12) + rng.below(arena.pages() - count);
+
+            let overlaps = mapped
+                .iter()
+                .any(|&(v, c, _)| virt_page < v + c && v < virt_page + count);
+
+            let result = unsafe {
+                virtual_map_linear(
+                    &mut pt,
+                    &mut ft,
+                    PageRegion {
+                        address: phys_page << 12,
+                        count,
+                    },
+                    virt_page << 12,
+                )
+            };
+
+            assert_eq!(result.is_err(), overlaps);
+
+            if !overlaps {
+                mapped.push((virt_page, count, phys_page));
+            }
+        }
+
+        assert!(mapped.len() > 40);
+
+        for &(virt_page, count, phys_page) in &mapped {
+            for i in 0..count {
+                let virt_addr = sv39_canonical((virt_page + i) << 12) + 0x123;
+                assert_eq!(
+                    walk(root, virt_addr),
+                    Some((((phys_page + i) << 12) + 0x123, RWX))
+                );
+            }
+        }
+
+        assert_eq!(walk(root, (4 << 30) + 0x1000), None);
+    }
+
+    #[test]
+    fn unmapping_frees_empty_tables() {
+        let arena = Arena::at(RAM_BASE, 256);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+        let mut tlb = TlbFlush::local();
+
+        let tables = ft.count_frames(0, FrameState::PageTable);
+
+        // Straddle a 2MiB boundary so two leaf tables are needed
+        let virt = (1 << 30) + (510 << 12);
+        let phys = RAM_BASE + 128 * 4096;
+        let region = PageRegion {
+            address: phys,
+            count: 8,
+        };
+        unsafe { virtual_map_linear(&mut pt, &mut ft, region, virt) }.unwrap();
+
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables + 3);
+
+        // Unmapping the first half keeps the second leaf table alive
+        let unmapped = unsafe { unmap(&mut pt, &mut ft, virt, 4, &mut tlb) }.unwrap();
+        tlb.flush_freeing(&mut ft);
+        assert_eq!(
+            unmapped,
+            std::vec![PageRegion {
+                address: phys,
+                count: 4
+            }]
+        );
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables + 2);
+
+        for i in 0..8 {
+            let expected = (i >= 4).then_some((phys + i * 4096, RWX));
+            assert_eq!(walk(root, virt + i * 4096), expected);
+        }
+
+        // Unmapping the rest, and beyond, returns every table but the root's,
+        // once every hart dropped its cached walks through them
+        let unmapped = unsafe { unmap(&mut pt, &mut ft, virt, 16, &mut tlb) }.unwrap();
+        assert_eq!(tlb.tables().len(), 2);
+        assert!(tlb.flushes_all());
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables + 2);
+        tlb.flush_freeing(&mut ft);
+        assert_eq!(
+            unmapped,
+            std::vec![PageRegion {
+                address: phys + 4 * 4096,
+                count: 4
+            }]
+        );
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+        assert!(pt.entries[1].is_unused());
+
+        // The freed tables left the self-ref window too
+        for table in unsafe { ft.iter_front() } {
+            let window = pt_virt_start() + (table - ft.root_frame_address());
+            let is_table = ft.segment_of(table).is_some_and(|segment| {
+                let meta = unsafe { segment.get_metadata(segment.index_of(table)) };
+                meta.state() == FrameState::PageTable
+            });
+
+            assert_eq!(walk(root, window).is_some(), is_table);
+        }
+
+        // And the range can be mapped again
+        let region = PageRegion {
+            address: phys,
+            count: 8,
+        };
+        unsafe { virtual_map_linear(&mut pt, &mut ft, region, virt) }.unwrap();
+        assert_eq!(walk(root, virt + 7 * 4096), Some((phys + 7 * 4096, RWX)));
+    }
+
+    #[test]
+    fn protect_rewrites_permissions() {
+        let arena = Arena::at(RAM_BASE, 128);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+        let mut tlb = TlbFlush::local();
+
+        let virt = 0x4000_0000;
+        let region = PageRegion {
+            address: RAM_BASE,
+            count: 4,
+        };
+        unsafe { virtual_map_linear(&mut pt, &mut ft, region, virt) }.unwrap();
+
+        // The unmapped pages past the region are skipped
+        let protected =
+            unsafe { protect(&mut pt, &mut ft, virt + 4096, 6, PageTableEntry::FLAG_R, &mut tlb) }.unwrap();
+        assert_eq!(tlb.pages(), 3);
+        tlb.flush();
+        assert_eq!(protected, 3);
+
+        let read_only = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R;
+        assert_eq!(walk(root, virt), Some((RAM_BASE, RWX)));
+        for i in 1..4 {
+            assert_eq!(
+                walk(root, virt + i * 4096),
+                Some((RAM_BASE + i * 4096, read_only))
+            );
+        }
+
+        // Tables without any page are left in place, other roots may share them
+        let mut upper = unsafe { PageTable::from_pointer(pt.entries[1].phys_addr()) };
+        let spare = unsafe { upper.allocate_intermediary(PageTableIndex::new(1), &mut ft) }.unwrap();
+        let tables = ft.count_frames(0, FrameState::PageTable);
+
+        let protected =
+            unsafe { protect(&mut pt, &mut ft, virt + (1 << 21), 512, PageTableEntry::FLAG_R, &mut tlb) }.unwrap();
+        tlb.flush();
+        assert_eq!(protected, 0);
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+        assert_eq!(upper.entries[1].phys_addr(), spare.physical_starting_memory_address);
+    }
+
+    #[test]
+    fn aligned_regions_use_large_pages() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+
+        let tables = ft.count_frames(0, FrameState::PageTable);
+
+        // A gigapage, a megapage and a few pages, the memory itself is never touched
+        let count = (1 << 18) + (1 << 9) + 3;
+        let region = PageRegion {
+            address: 1 << 30,
+            count,
+        };
+        unsafe { virtual_map_linear(&mut pt, &mut ft, region, 1 << 30) }.unwrap();
+
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables + 2);
+
+        let sizes = [
+            (0, PageSize::Giga),
+            (1 << 18, PageSize::Mega),
+            ((1 << 18) + (1 << 9), PageSize::Page),
+        ];
+        for (page, size) in sizes {
+            let virt_addr = (1 << 30) + page * 4096 + 0x10;
+            let translation = pt.walk(virt_addr).unwrap();
+
+            assert_eq!(translation.size, size);
+            assert_eq!(walk(root, virt_addr), Some((virt_addr, RWX)));
+        }
+
+        assert_eq!(walk(root, (1 << 30) + count * 4096), None);
+
+        // Overlapping the large pages is still caught
+        let region = PageRegion {
+            address: RAM_BASE,
+            count: 1,
+        };
+        assert!(
+            unsafe { virtual_map_linear(&mut pt, &mut ft, region, (1 << 30) + 0x5000) }.is_err()
+        );
+
+        // Misaligned against each other, only pages can be used
+        let region = PageRegion {
+            address: (4 << 30) + 4096,
+            count: 1024,
+        };
+        unsafe { virtual_map_linear(&mut pt, &mut ft, region, 4 << 30) }.unwrap();
+        assert_eq!(pt.walk((4 << 30) + (1 << 21)).unwrap().size, PageSize::Page);
+    }
+
+    #[test]
+    fn large_pages_are_split_when_partially_changed() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+        let mut tlb = TlbFlush::local();
+
+        let tables = ft.count_frames(0, FrameState::PageTable);
+
+        let region = PageRegion {
+            address: 1 << 30,
+            count: 1 << 18,
+        };
+        unsafe { virtual_map_linear(&mut pt, &mut ft, region, 1 << 30) }.unwrap();
+
+        // Punch a page out of the middle of the gigapage
+        let hole = (1 << 30) + (3 << 21) + (5 << 12);
+        let unmapped = unsafe { unmap(&mut pt, &mut ft, hole, 1, &mut tlb) }.unwrap();
+        tlb.flush_freeing(&mut ft);
+
+        assert_eq!(
+            unmapped,
+            std::vec![PageRegion {
+                address: hole,
+                count: 1
+            }]
+        );
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables + 2);
+
+        assert_eq!(walk(root, hole), None);
+        for virt_addr in [1 << 30, hole - 4096, hole + 4096, (2 << 30) - 4096] {
+            assert_eq!(walk(root, virt_addr), Some((virt_addr, RWX)));
+        }
+        assert_eq!(pt.walk(1 << 30).unwrap().size, PageSize::Mega);
+        assert_eq!(pt.walk(hole + 4096).unwrap().size, PageSize::Page);
+
+        // Protecting the rest of the 2MiB page only rewrites what's mapped
+        let read_only = PageTableEntry::FLAG_V | PageTableEntry::FLAG_R;
+        let megapage = (1 << 30) + (3 << 21);
+        let protected = unsafe { protect(&mut pt, &mut ft, megapage, 512, PageTableEntry::FLAG_R, &mut tlb) }.unwrap();
+        tlb.flush();
+
+        assert_eq!(protected, 511);
+        assert_eq!(walk(root, megapage), Some((megapage, read_only)));
+        assert_eq!(walk(root, megapage - 4096), Some((megapage - 4096, RWX)));
+
+        // Unmapping everything gives the memory back in one piece, minus the hole
+        let unmapped = unsafe { unmap(&mut pt, &mut ft, 1 << 30, 1 << 18, &mut tlb) }.unwrap();
+        tlb.flush_freeing(&mut ft);
+        assert_eq!(
+            unmapped,
+            std::vec![
+                PageRegion {
+                    address: 1 << 30,
+                    count: (hole >> 12) - (1 << 18)
+                },
+                PageRegion {
+                    address: hole + 4096,
+                    count: (2 << 18) - (hole >> 12) - 1
+                }
+            ]
+        );
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+    }
+
+    #[test]
+    fn large_pages_are_sampled_whole() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+
+        let user = PageTableEntry::FLAG_V
+            | PageTableEntry::FLAG_R
+            | PageTableEntry::FLAG_W
+            | PageTableEntry::FLAG_U;
+        let region = PageRegion {
+            address: 1 << 30,
+            count: 512,
+        };
+        unsafe {
+            virtual_map_linear_with_flags(
+                &mut pt,
+                &mut ft,
+                region.clone(),
+                1 << 30,
+                user | PageTableEntry::FLAG_A,
+            )
+        }
+        .unwrap();
+
+        let tables = ft.count_frames(0, FrameState::PageTable);
+        let mut tlb = TlbFlush::local();
+        let mut leaves = Vec::new();
+
+        // A single page out of the middle of the megapage
+        let page = (1 << 30) + (7 << 12);
+        let sampled = unsafe {
+            sample_accessed(&mut pt, page, 1, &mut tlb, &mut |region, accessed| {
+                leaves.push((region, accessed))
+            })
+        };
+        assert_eq!(tlb.pages(), 512);
+        tlb.flush();
+
+        assert_eq!(sampled, 512);
+        assert_eq!(leaves, [(region, true)]);
+        assert_eq!(pt.walk(page).unwrap().size, PageSize::Mega);
+        assert_eq!(walk(root, page), Some((page, user)));
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+    }
+
+    #[test]
+    fn device_memory_uses_napot_runs_and_memory_types() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+        let (mut pt, root) = bootstrapped(&mut ft);
+        let mut tlb = TlbFlush::local();
+
+        let all = Extensions {
+            svpbmt: true,
+            svnapot: true,
+        };
+        unsafe { set_extensions(all) };
+
+        // Two 64KiB runs and a page past them
+        let device = 0x1000_0000;
+        let count = 2 * NAPOT_PAGES + 1;
+        let region = || PageRegion {
+            address: device,
+            count,
+        };
+        unsafe { map_device(&mut pt, &mut ft, region(), device, MemoryType::Io) }.unwrap();
+
+        let kernel_rw = PageTableEntry::FLAG_V
+            | PageTableEntry::FLAG_R
+            | PageTableEntry::FLAG_W
+            | PageTableEntry::FLAG_G
+            | PageTableEntry::FLAG_A
+            | PageTableEntry::FLAG_D;
+        for page in 0..count {
+            let virt_addr = device + page * 4096;
+            let entry = pt.walk(virt_addr).unwrap().entry;
+
+            assert_eq!(walk(root, virt_addr), Some((virt_addr, kernel_rw)));
+            assert_eq!(entry.is_napot(), page < 2 * NAPOT_PAGES);
+            assert_eq!(entry.0 & PageTableEntry::PBMT_MASK, PageTableEntry::PBMT_IO);
+        }
+
+        // Unmapping part of a run turns the rest of it back into plain pages
+        let hole = device + 3 * 4096;
+        let unmapped = unsafe { unmap(&mut pt, &mut ft, hole, 1, &mut tlb) }.unwrap();
+        tlb.flush_freeing(&mut ft);
+
+        assert_eq!(
+            unmapped,
+            std::vec![PageRegion {
+                address: hole,
+                count: 1
+            }]
+        );
+        assert_eq!(walk(root, hole), None);
+        for page in (0..NAPOT_PAGES).filter(|&page| page != 3) {
+            let virt_addr = device + page * 4096;
+            let entry = pt.walk(virt_addr).unwrap().entry;
+
+            assert!(!entry.is_napot());
+            assert_eq!(entry.0 & PageTableEntry::PBMT_MASK, PageTableEntry::PBMT_IO);
+            assert_eq!(walk(root, virt_addr), Some((virt_addr, kernel_rw)));
+        }
+        assert!(pt.walk(device + NAPOT_PAGES * 4096).unwrap().entry.is_napot());
+
+        // Without the extensions the same mapping uses neither
+        unsafe { set_extensions(Extensions::default()) };
+
+        let plain = 0x2000_0000;
+        unsafe { map_device(&mut pt, &mut ft, region(), plain, MemoryType::Io) }.unwrap();
+
+        let entry = pt.walk(plain).unwrap().entry;
+        assert!(!entry.is_napot());
+        assert_eq!(entry.0 & PageTableEntry::PBMT_MASK, 0);
+        assert_eq!(walk(root, plain + 4096), Some((device + 4096, kernel_rw)));
+    }
+
+    #[test]
+    fn deeper_modes_map_the_whole_address_space() {
+        for mode in [PagingMode::Sv48, PagingMode::Sv57] {
+            unsafe { set_paging_mode(mode) };
+
+            let arena = Arena::at(RAM_BASE, 64);
+            let mut ft = arena.frame_table();
+            let (mut pt, root) = bootstrapped(&mut ft);
+            let mut tlb = TlbFlush::local();
+
+            let tables = ft.count_frames(0, FrameState::PageTable);
+
+            // Just below the top of the lower half, and in the upper half
+            let low = (1 << (mode.virt_bits() - 1)) - (1 << 30);
+            let high = mode.canonical(1 << (mode.virt_bits() - 1)) + (5 << 12);
+
+            for (virt, phys, count) in [(low, 1 << 30, 1 << 18), (high, RAM_BASE, 3)] {
+                let region = PageRegion {
+                    address: phys,
+                    count,
+                };
+                unsafe { virtual_map_linear(&mut pt, &mut ft, region, virt) }.unwrap();
+
+                let last = virt + (count - 1) * 4096;
+                assert_eq!(walk(root, last), Some((phys + (count - 1) * 4096, RWX)));
+                assert_eq!(pt.translate(last + 4096), None);
+            }
+
+            assert_eq!(pt.walk(low).unwrap().size, PageSize::Giga);
+            assert_eq!(pt.walk(high).unwrap().size, PageSize::Page);
+
+            // The window still maps every page table
+            let table = unsafe { pt.meta_allocate_page_table(&mut ft) }.unwrap();
+            let window = pt_virt_start() + (table.phys_addr - ft.root_frame_address());
+            assert_eq!(pt.translate(window), Some(table.phys_addr));
+            unsafe { pt.meta_free_page_table(&mut ft, table.phys_addr) }.unwrap();
+
+            let unmapped = unsafe { unmap(&mut pt, &mut ft, high, 3, &mut tlb) }.unwrap();
+            tlb.flush_freeing(&mut ft);
+            assert_eq!(unmapped[0].address, RAM_BASE);
+            unsafe { unmap(&mut pt, &mut ft, low, 1 << 18, &mut tlb) }.unwrap();
+            tlb.flush_freeing(&mut ft);
+
+            assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+        }
+    }
+
+    #[test]
+    fn mapping_errors_are_typed() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+        let (mut pt, _) = bootstrapped(&mut ft);
+
+        let region = |address, count| PageRegion { address, count };
+        let virt = (1 << 30) + (2 << 20) - 2 * 4096;
+
+        assert!(matches!(
+            unsafe { virtual_map_linear(&mut pt, &mut ft, region(RAM_BASE + 8, 1), virt) },
+            Err(LinearMapError::Map {
+                error: MapError::Misaligned { addr },
+                ..
+            }) if addr == RAM_BASE + 8
+        ));
+        assert!(matches!(
+            unsafe { virtual_map_linear(&mut pt, &mut ft, region(RAM_BASE, 2), (1 << 39) - 4096) },
+            Err(LinearMapError::Map {
+                error: MapError::IndexOutOfRange { .. },
+                ..
+            })
+        ));
+
+        // A page and a gigapage to collide with
+        unsafe { virtual_map_linear(&mut pt, &mut ft, region(RAM_BASE, 1), virt) }.unwrap();
+        unsafe { virtual_map_linear(&mut pt, &mut ft, region(2 << 30, 1 << 18), 2 << 30) }.unwrap();
+
+        assert_eq!(
+            unsafe { pt.virtually_map(&mut ft, virt >> 12, RAM_BASE, RWX) },
+            Err(MapError::AlreadyMapped)
+        );
+        assert_eq!(
+            unsafe { pt.virtually_map(&mut ft, (2 << 30 >> 12) + 1, RAM_BASE, RWX) },
+            Err(MapError::HugePageConflict)
+        );
+        assert_eq!(
+            unsafe { pt.virtually_map(&mut ft, 5, RAM_BASE + 1, RWX) },
+            Err(MapError::Misaligned { addr: RAM_BASE + 1 })
+        );
+
+        // Running out of frames half way takes back the pages already mapped
+        while ft.alloc_front(1, FrameState::Kernel, 0).is_some() {}
+        let tables = ft.count_frames(0, FrameState::PageTable);
+
+        let result = unsafe { virtual_map_linear(&mut pt, &mut ft, region(RAM_BASE, 3), virt + 4096) };
+
+        assert!(matches!(
+            result,
+            Err(LinearMapError::Map {
+                virt_addr,
+                error: MapError::OutOfFrames,
+            }) if virt_addr == virt + 2 * 4096
+        ));
+        assert_eq!(pt.translate(virt + 4096), None);
+        assert_eq!(pt.translate(virt), Some(RAM_BASE));
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+    }
+
+    #[test]
+    #[should_panic(expected = "self-ref section")]
+    fn self_ref_section_can_not_be_unmapped() {
+        let arena = Arena::at(RAM_BASE, 64);
+        let mut ft = arena.frame_table();
+        let (mut pt, _) = bootstrapped(&mut ft);
+        let mut tlb = TlbFlush::local();
+
+        let _ = unsafe { unmap(&mut pt, &mut ft, pt_virt_start(), 1, &mut tlb) };
+    }
+
+    #[test]
+    fn decomposes_page_addresses() {
+        let parts = decompose_virt_pageaddr((3 << 18) | (7 << 9) | 511);
+
+        assert_eq!(parts.root_index().as_addr(), 3);
+        assert_eq!(parts.index(PageSize::Mega).as_addr(), 7);
+        assert_eq!(parts.index(PageSize::Page).as_addr(), 511);
+
+        unsafe { set_paging_mode(PagingMode::Sv57) };
+        let parts = decompose_virt_pageaddr((9 << 36) | (3 << 18) | 511);
+
+        assert_eq!(parts.root_index().as_addr(), 9);
+        assert_eq!(parts.index(PageSize::Tera).as_addr(), 0);
+        assert_eq!(parts.index(PageSize::Giga).as_addr(), 3);
+        assert_eq!(parts.index(PageSize::Page).as_addr(), 511);
     }
 }
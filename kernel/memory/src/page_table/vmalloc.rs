@@ -0,0 +1,383 @@
+//!
+//! The vmalloc region
+//!
+//! Kernel memory which has to look contiguous without being physically
+//! contiguous, like kernel stacks and large buffers, is handed out from a
+//! dedicated stretch of the upper half. Every area is backed by single
+//! frames and followed by unmapped guard pages, so running off its end
+//! faults instead of silently corrupting the next area
+//!
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::PAGE_SIZE_B;
+use crate::frame_table::{FrameState, FrameTable};
+use crate::tlb::TlbFlush;
+
+use super::virt_map::{LinearMapError, PageRegion, unmap_shared, virtual_map_linear_with_flags};
+use super::{MapError, PageTable, PageTableEntry, paging_mode};
+
+///
+/// The root table index the vmalloc region lives under
+///
+/// It takes a single root entry between the self-ref section and the
+/// physmap, whose table is allocated up front so address spaces can share it
+///
+pub const VMALLOC_ROOT_INDEX: usize = 320;
+
+///
+/// Number of unmapped pages following every area
+///
+pub const GUARD_PAGES: usize = 1;
+
+///
+/// The first virtual address of the vmalloc region
+///
+pub fn vmalloc_start() -> usize {
+    let mode = paging_mode();
+    mode.canonical(VMALLOC_ROOT_INDEX << mode.root_size().shift())
+}
+
+///
+/// The first virtual address past the vmalloc region
+///
+pub fn vmalloc_end() -> usize {
+    vmalloc_start() + paging_mode().root_size().bytes()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmallocError {
+    ///
+    /// No free stretch of the region is large enough
+    ///
+    OutOfVirtualSpace { pages: usize },
+
+    ///
+    /// The frame table ran out of frames to back the area with
+    ///
+    OutOfMemory { pages: usize },
+
+    ///
+    /// The address is not the start of an area
+    ///
+    NotAllocated { addr: usize },
+}
+
+///
+/// Hands out areas of the vmalloc region
+///
+pub struct Vmalloc {
+    ///
+    /// Stretches of the region which are not reserved, sorted by address
+    ///
+    free: Vec<PageRegion>,
+
+    ///
+    /// The start of every area in use, with its size in pages
+    ///
+    areas: BTreeMap<usize, usize>,
+}
+
+impl Vmalloc {
+    pub const fn new() -> Self {
+        Self {
+            free: Vec::new(),
+            areas: BTreeMap::new(),
+        }
+    }
+
+    ///
+    /// Take over the vmalloc region of `page_table`
+    ///
+    /// The table beneath `VMALLOC_ROOT_INDEX` is allocated right away, it is
+    /// never freed again. The first pages of the region are a guard as well
+    ///
+    /// # Safety
+    ///
+    /// `page_table` must be the kernel page table, with nothing mapped in the
+    /// vmalloc region, and be used for every later call
+    ///
+    pub unsafe fn init(
+        &mut self,
+        page_table: &mut PageTable,
+        frame_table: &mut FrameTable,
+    ) -> Result<(), MapError> {
+        assert!(
+            self.free.is_empty() && self.areas.is_empty(),
+            "vmalloc is already initialized"
+        );
+        assert!(
+            page_table.entries[VMALLOC_ROOT_INDEX].is_unused(),
+            "the vmalloc region is already in use"
+        );
+
+        let table = unsafe { page_table.meta_allocate_page_table(frame_table) }?;
+        page_table.entries[VMALLOC_ROOT_INDEX].set(table.phys_addr as u64, PageTableEntry::FLAG_V);
+
+        self.free.push(PageRegion {
+            address: vmalloc_start() + GUARD_PAGES * PAGE_SIZE_B,
+            count: paging_mode().root_size().pages() - GUARD_PAGES,
+        });
+
+        Ok(())
+    }
+
+    ///
+    /// Reserve `pages` pages of the region and back them with zeroed frames
+    ///
+    /// The frames are owned by the kernel and need not be contiguous.
+    /// Returns the virtual address of the area
+    ///
+    /// # Safety
+    ///
+    /// `page_table` must be the table the region was initialized with
+    ///
+    pub unsafe fn alloc(
+        &mut self,
+        page_table: &mut PageTable,
+        frame_table: &mut FrameTable,
+        pages: usize,
+    ) -> Result<usize, VmallocError> {
+        assert!(pages > 0, "vmalloc: an area holds at least one page");
+
+        let address = self
+            .reserve(pages + GUARD_PAGES)
+            .ok_or(VmallocError::OutOfVirtualSpace { pages })?;
+
+        // Like the physmap, the pages are accessed and dirty from the start
+        let flags = PageTableEntry::FLAG_V
+            | PageTableEntry::FLAG_R
+            | PageTableEntry::FLAG_W
+            | PageTableEntry::FLAG_G
+            | PageTableEntry::FLAG_A
+            | PageTableEntry::FLAG_D;
+
+        for page in 0..pages {
+            let Some(frame) = frame_table.alloc_front(1, FrameState::Kernel, 0) else {
+                // Hand back everything taken so far
+                unsafe { self.release(page_table, frame_table, address, page, pages) };
+                return Err(VmallocError::OutOfMemory { pages });
+            };
+
+            unsafe { frame.zero() };
+
+            let region = PageRegion {
+                address: frame.phys_addr,
+                count: 1,
+            };
+
+            let mapped = unsafe {
+                virtual_map_linear_with_flags(
+                    page_table,
+                    frame_table,
+                    region,
+                    address + page * PAGE_SIZE_B,
+                    flags,
+                )
+            };
+
+            match mapped {
+                Ok(()) => {}
+                Err(LinearMapError::Map {
+                    error: MapError::OutOfFrames,
+                    ..
+                }) => {
+                    // No frame left for a page table
+                    frame_table
+                        .free_addr(frame.phys_addr, 1, 0)
+                        .expect("vmalloc: failed to free a backing frame");
+                    unsafe { self.release(page_table, frame_table, address, page, pages) };
+                    return Err(VmallocError::OutOfMemory { pages });
+                }
+                Err(error) => panic!("vmalloc: failed to map a reserved page: {error:?}"),
+            }
+        }
+
+        self.areas.insert(address, pages);
+
+        Ok(address)
+    }
+
+    ///
+    /// Unmap the area starting at `address` and free its frames
+    ///
+    /// # Safety
+    ///
+    /// Nothing may still use the area, on this or any other hart. The area is
+    /// flushed from the TLB of every hart running with paging on
+    ///
+    pub unsafe fn free(
+        &mut self,
+        page_table: &mut PageTable,
+        frame_table: &mut FrameTable,
+        address: usize,
+    ) -> Result<(), VmallocError> {
+        let pages = self
+            .areas
+            .remove(&address)
+            .ok_or(VmallocError::NotAllocated { addr: address })?;
+
+        unsafe { self.release(page_table, frame_table, address, pages, pages) };
+
+        Ok(())
+    }
+
+    ///
+    /// Size in pages of the area starting at `address`
+    ///
+    pub fn area_pages(&self, address: usize) -> Option<usize> {
+        self.areas.get(&address).copied()
+    }
+
+    ///
+    /// Unmap and free the first `mapped` pages of the area of `pages` pages
+    /// at `address`, then give it back along with its guard
+    ///
+    unsafe fn release(
+        &mut self,
+        page_table: &mut PageTable,
+        frame_table: &mut FrameTable,
+        address: usize,
+        mapped: usize,
+        pages: usize,
+    ) {
+        // Areas are made of 4KiB pages, there is never anything to split
+        let mut tlb = TlbFlush::kernel();
+        let unmapped = unsafe { unmap_shared(page_table, frame_table, address, mapped, &mut tlb) }
+            .expect("vmalloc: failed to unmap an area");
+
+        // Every hart has to let go of the frames before they are reused
+        tlb.flush_freeing(frame_table);
+
+        for region in unmapped {
+            frame_table
+                .free_addr(region.address, region.count, 0)
+                .expect("vmalloc: failed to free a backing frame");
+        }
+
+        self.unreserve(address, pages + GUARD_PAGES);
+    }
+
+    ///
+    /// Take `pages` pages off the first free stretch large enough
+    ///
+    fn reserve(&mut self, pages: usize) -> Option<usize> {
+        let index = self.free.iter().position(|region| region.count >= pages)?;
+        let region = &mut self.free[index];
+        let address = region.address;
+
+        region.address += pages * PAGE_SIZE_B;
+        region.count -= pages;
+
+        if region.count == 0 {
+            self.free.remove(index);
+        }
+
+        Some(address)
+    }
+
+    ///
+    /// Put `pages` pages at `address` back, merging them with the free
+    /// stretches around them
+    ///
+    fn unreserve(&mut self, address: usize, pages: usize) {
+        let index = self.free.partition_point(|region| region.address < address);
+        let end = address + pages * PAGE_SIZE_B;
+
+        let merges_next = self.free.get(index).is_some_and(|next| next.address == end);
+        let merges_previous = index > 0 && {
+            let previous = &self.free[index - 1];
+            previous.address + previous.count * PAGE_SIZE_B == address
+        };
+
+        match (merges_previous, merges_next) {
+            (true, true) => {
+                let next = self.free.remove(index);
+                self.free[index - 1].count += pages + next.count;
+            }
+            (true, false) => self.free[index - 1].count += pages,
+            (false, true) => {
+                self.free[index].address = address;
+                self.free[index].count += pages;
+            }
+            (false, false) => self.free.insert(
+                index,
+                PageRegion {
+                    address,
+                    count: pages,
+                },
+            ),
+        }
+    }
+}
+
+impl Default for Vmalloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Arena, bootstrapped};
+
+    const RAM_BASE: usize = 0x8000_0000;
+
+    #[test]
+    fn areas_are_backed_and_guarded() {
+        let arena = Arena::at(RAM_BASE, 128);
+        let mut ft = arena.frame_table();
+
+        let (mut pt, _) = bootstrapped(&mut ft);
+
+        let mut vmalloc = Vmalloc::new();
+        unsafe { vmalloc.init(&mut pt, &mut ft) }.unwrap();
+
+        let tables = ft.count_frames(0, FrameState::PageTable);
+        let free = ft.count_frames(0, FrameState::Free);
+
+        let stack = unsafe { vmalloc.alloc(&mut pt, &mut ft, 4) }.unwrap();
+        let buffer = unsafe { vmalloc.alloc(&mut pt, &mut ft, 3) }.unwrap();
+
+        assert_eq!(stack, vmalloc_start() + PAGE_SIZE_B);
+        assert_eq!(ft.count_frames(0, FrameState::Kernel), 7);
+
+        // Guard pages on both sides of every area
+        assert_eq!(pt.translate(stack - PAGE_SIZE_B), None);
+        assert_eq!(pt.translate(stack + 4 * PAGE_SIZE_B), None);
+        assert_eq!(buffer, stack + 5 * PAGE_SIZE_B);
+        assert_eq!(pt.translate(buffer + 3 * PAGE_SIZE_B), None);
+
+        for page in 0..3 {
+            let translation = pt.walk(buffer + page * PAGE_SIZE_B).unwrap();
+            assert!(translation.is_writable() && !translation.is_executable());
+        }
+
+        // Freed space is reused, and the shared root entry stays
+        unsafe { vmalloc.free(&mut pt, &mut ft, stack) }.unwrap();
+        assert_eq!(pt.translate(stack), None);
+        assert_eq!(
+            unsafe { vmalloc.free(&mut pt, &mut ft, stack) },
+            Err(VmallocError::NotAllocated { addr: stack })
+        );
+        assert_eq!(unsafe { vmalloc.alloc(&mut pt, &mut ft, 2) }, Ok(stack));
+
+        unsafe { vmalloc.free(&mut pt, &mut ft, stack) }.unwrap();
+        unsafe { vmalloc.free(&mut pt, &mut ft, buffer) }.unwrap();
+
+        assert!(pt.entries[VMALLOC_ROOT_INDEX].is_valid());
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), tables);
+        assert_eq!(ft.count_frames(0, FrameState::Free), free);
+        assert_eq!(vmalloc.free.len(), 1);
+
+        // Running out of frames gives everything back
+        assert_eq!(
+            unsafe { vmalloc.alloc(&mut pt, &mut ft, 200) },
+            Err(VmallocError::OutOfMemory { pages: 200 })
+        );
+        assert_eq!(ft.count_frames(0, FrameState::Free), free);
+        assert_eq!(vmalloc.free.len(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+//!
+//! Physical memory access
+//!
+//! Frame and page table code works with physical addresses, every time one
+//! is dereferenced it is turned into a pointer through `phys_to_ptr`. Stage0
+//! builds the first page table while paging is off, with an offset of `0`,
+//! and moves the offset to the physmap once paging is on. Host tests point
+//! it at a heap arena standing in for RAM instead
+//!
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(test))]
+static PHYS_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+// Tests run on many threads at once, each with its own simulated RAM
+#[cfg(test)]
+std::thread_local! {
+    static PHYS_OFFSET: AtomicUsize = const { AtomicUsize::new(0) };
+}
+
+///
+/// The offset added to a physical address to get a pointer to it
+///
+pub fn phys_offset() -> usize {
+    #[cfg(not(test))]
+    return PHYS_OFFSET.load(Ordering::Relaxed);
+
+    #[cfg(test)]
+    return PHYS_OFFSET.with(|offset| offset.load(Ordering::Relaxed));
+}
+
+///
+/// Set the offset added to a physical address to get a pointer to it
+///
+/// # Safety
+///
+/// Every physical address handed to this crate afterwards must be
+/// accessible at `addr + offset`, including frame metadata and page tables
+/// which were created before the change
+///
+pub unsafe fn set_phys_offset(offset: usize) {
+    #[cfg(not(test))]
+    PHYS_OFFSET.store(offset, Ordering::Relaxed);
+
+    #[cfg(test)]
+    PHYS_OFFSET.with(|o| o.store(offset, Ordering::Relaxed));
+}
+
+///
+/// Get a pointer through which the physical address `phys_addr` can be accessed
+///
+pub fn phys_to_ptr<T>(phys_addr: usize) -> *mut T {
+    phys_addr.wrapping_add(phys_offset()) as *mut T
+}
+
+///
+/// Get the physical address behind a pointer from `phys_to_ptr`
+///
+pub fn ptr_to_phys<T>(ptr: *const T) -> usize {
+    (ptr as usize).wrapping_sub(phys_offset())
+}
@@ -0,0 +1,108 @@
+//!
+//! Minimal locking for state shared between harts
+//!
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+///
+/// The id of the hart running this, boot code keeps it in `tp`
+///
+fn current_hart() -> usize {
+    #[cfg(target_arch = "riscv64")]
+    {
+        let hart: usize;
+        unsafe { core::arch::asm!("mv {}, tp", out(reg) hart) };
+        hart
+    }
+
+    #[cfg(not(target_arch = "riscv64"))]
+    0
+}
+
+///
+/// A busy-waiting mutual exclusion lock
+///
+/// Nothing here masks interrupts, so a lock must never be taken from a trap
+/// handler which may have interrupted its holder on the same hart. Such a
+/// handler can check `held_by_current_hart` first
+///
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+
+    ///
+    /// The id of the hart holding the lock plus one, 0 while it is free
+    ///
+    owner: AtomicUsize,
+
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+
+        self.owner.store(current_hart() + 1, Ordering::Relaxed);
+
+        Some(SpinLockGuard { lock: self })
+    }
+
+    ///
+    /// Whether the hart running this holds the lock, taking it again would
+    /// spin forever
+    ///
+    pub fn held_by_current_hart(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == current_hart() + 1
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.store(0, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
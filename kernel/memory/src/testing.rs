@@ -0,0 +1,158 @@
+//!
+//! Helpers for running the crate on the host
+//!
+
+use crate::frame_table::{FrameSegment, FrameState, FrameTable};
+use crate::page_table::{PageTable, PageTableEntry, bootstrap_pt, paging_mode, pt_virt_start};
+use crate::phys::{phys_offset, phys_to_ptr, set_phys_offset};
+
+///
+/// A page aligned heap arena standing in for physical memory
+///
+/// By default the arena is identity mapped, so its host address doubles as
+/// its physical address. `Arena::at` instead places it at a chosen physical
+/// address through the translation hook in `crate::phys`, which only one
+/// arena per test thread may do at a time.
+///
+pub struct Arena {
+    ptr: *mut u8,
+    layout: std::alloc::Layout,
+    base: usize,
+}
+
+impl Arena {
+    pub fn new(pages: usize) -> Self {
+        let layout = std::alloc::Layout::from_size_align(pages * 4096, 4096).unwrap();
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        assert!(!ptr.is_null());
+        Self {
+            ptr,
+            layout,
+            base: ptr as usize,
+        }
+    }
+
+    ///
+    /// Simulate `pages` pages of RAM at the physical address `base`
+    ///
+    pub fn at(base: usize, pages: usize) -> Self {
+        assert_eq!(base % 4096, 0, "simulated RAM must be page aligned");
+        assert_eq!(phys_offset(), 0, "only one simulated RAM per test thread");
+
+        let mut arena = Self::new(pages);
+        arena.base = base;
+        unsafe { set_phys_offset(arena.ptr as usize - base) };
+        arena
+    }
+
+    ///
+    /// Physical address of the first page of the arena
+    ///
+    pub fn addr(&self) -> usize {
+        self.base
+    }
+
+    pub fn pages(&self) -> usize {
+        self.layout.size() / 4096
+    }
+
+    ///
+    /// Build a frame segment over `pages` pages, starting `offset` pages into the arena
+    ///
+    pub fn segment(&self, offset: usize, pages: usize) -> FrameSegment {
+        assert!((offset + pages) * 4096 <= self.layout.size());
+        unsafe { FrameSegment::initialize(self.addr() + offset * 4096, pages * 4096) }.unwrap()
+    }
+
+    ///
+    /// Build a frame table with a single segment over the whole arena
+    ///
+    pub fn frame_table(&self) -> FrameTable {
+        FrameTable {
+            segments: std::vec![self.segment(0, self.pages())],
+        }
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        if self.base != self.ptr as usize {
+            unsafe { set_phys_offset(0) };
+        }
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+///
+/// Allocate a zeroed root table from `ft` and give it the self-ref section
+///
+/// Returns the table along with its physical address
+///
+pub fn bootstrapped(ft: &mut FrameTable) -> (PageTable, usize) {
+    let root = ft.alloc_front(1, FrameState::PageTable, 0).unwrap();
+    unsafe { root.zero() };
+
+    let mut pt = PageTable {
+        entries: unsafe { root.as_slice() },
+    };
+    let offsets = unsafe { bootstrap_pt(&mut pt, ft) };
+
+    assert_eq!(offsets.virtual_root_null_offset, pt_virt_start());
+    assert_eq!(offsets.physical_root_null_offset, ft.root_frame_address());
+
+    (pt, root.phys_addr)
+}
+
+///
+/// Walk a page table rooted at `root` in software, in the current paging mode
+///
+/// Returns the physical address `virt_addr` maps to and the flags of the leaf entry
+///
+pub fn walk(root: usize, virt_addr: usize) -> Option<(usize, u64)> {
+    let mut table = root;
+
+    for level in (0..paging_mode().levels()).rev() {
+        let index = (virt_addr >> (12 + 9 * level)) & 0x1FF;
+        let entry = unsafe { *phys_to_ptr::<PageTableEntry>(table + index * 8) };
+
+        if !entry.is_valid() {
+            return None;
+        }
+
+        if entry.is_leaf() {
+            let page_mask = (1 << (12 + 9 * level)) - 1;
+            let flags = entry.0 & 0x3FF;
+            let phys_addr = entry.leaf_phys_addr(paging_mode().vpn(virt_addr));
+            return Some((phys_addr + (virt_addr & page_mask), flags));
+        }
+
+        table = entry.phys_addr();
+    }
+
+    None
+}
+
+///
+/// Sign extend a 39 bit virtual address
+///
+pub fn sv39_canonical(virt_addr: usize) -> usize {
+    ((virt_addr << 25) as isize >> 25) as usize
+}
+
+///
+/// Small deterministic generator for randomized tests
+///
+pub struct XorShift(pub u64);
+
+impl XorShift {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
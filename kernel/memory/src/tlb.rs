@@ -0,0 +1,451 @@
+//!
+//! TLB shootdowns
+//!
+//! A page table change only reaches the TLB of the hart making it once that
+//! hart runs `sfence.vma`. Every other hart which may hold a stale
+//! translation is asked to fence as well, through the SBI RFENCE extension.
+//!
+//! Page table changes collect the pages they touch in a `TlbFlush`, which is
+//! sent out once the change is complete. Past `FLUSH_ALL_THRESHOLD` pages, or
+//! `MAX_RANGES` separate ranges, the ranges are dropped and the whole address
+//! space flushed instead, one fence per page would cost more than refilling
+//! the TLB
+//!
+//! Harts may also cache the non-leaf entries they walked through, which a
+//! fence of single pages need not drop. Page tables taken out of a table are
+//! held in the `TlbFlush` and only freed by `flush_freeing`, after a full
+//! flush
+//!
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::PAGE_SIZE_B;
+use crate::frame_table::FrameTable;
+
+///
+/// Number of pages past which a flush covers the whole address space
+///
+pub const FLUSH_ALL_THRESHOLD: usize = 64;
+
+///
+/// Number of separate ranges a flush keeps track of
+///
+pub const MAX_RANGES: usize = 8;
+
+///
+/// A set of harts, by hart ID
+///
+/// Only hart IDs below 64 can be held
+///
+pub struct HartSet(AtomicU64);
+
+impl HartSet {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn insert(&self, hart: usize) {
+        assert!(hart < 64, "HartSet: hart ID {hart} is out of range");
+        self.0.fetch_or(1 << hart, Ordering::AcqRel);
+    }
+
+    pub fn remove(&self, hart: usize) {
+        assert!(hart < 64, "HartSet: hart ID {hart} is out of range");
+        self.0.fetch_and(!(1 << hart), Ordering::AcqRel);
+    }
+
+    pub fn contains(&self, hart: usize) -> bool {
+        hart < 64 && self.bits() & (1 << hart) != 0
+    }
+
+    ///
+    /// One bit per hart in the set, hart 0 being the lowest
+    ///
+    pub fn bits(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl Default for HartSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///
+/// The translations a flush applies to
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushScope {
+    ///
+    /// Translations of every ASID, global mappings included
+    ///
+    Global,
+
+    ///
+    /// Non-global translations tagged with the ASID
+    ///
+    Asid(u16),
+}
+
+///
+/// A batch of pages whose translations changed, to be flushed from the TLB
+/// of this hart and `harts`
+///
+/// The batch has to be sent out with `flush` before it is dropped, and
+/// before any frame it unmapped is handed out again. Batches holding page
+/// tables are sent out with `flush_freeing` instead
+///
+#[derive(Debug)]
+#[must_use = "a TlbFlush does nothing until it is flushed"]
+pub struct TlbFlush {
+    scope: FlushScope,
+
+    ///
+    /// Harts which may cache the translations, one bit per hart ID
+    ///
+    harts: u64,
+
+    ///
+    /// The virtual address ranges covered so far, none of them overlapping
+    /// or adjacent
+    ///
+    ranges: [(usize, usize); MAX_RANGES],
+    len: usize,
+
+    ///
+    /// Number of pages added, overlaps left out
+    ///
+    pages: usize,
+
+    ///
+    /// The ranges outgrew `MAX_RANGES` and were dropped
+    ///
+    overflowed: bool,
+
+    ///
+    /// Page tables which were taken out, to be freed once no hart can have
+    /// them cached
+    ///
+    tables: Vec<usize>,
+}
+
+impl TlbFlush {
+    pub const fn new(scope: FlushScope, harts: u64) -> Self {
+        Self {
+            scope,
+            harts,
+            ranges: [(0, 0); MAX_RANGES],
+            len: 0,
+            pages: 0,
+            overflowed: false,
+            tables: Vec::new(),
+        }
+    }
+
+    ///
+    /// A flush of the kernel's translations, on every hart which runs with
+    /// paging on
+    ///
+    pub fn kernel() -> Self {
+        Self::new(FlushScope::Global, crate::KERNEL_HARTS.bits())
+    }
+
+    ///
+    /// A flush of this hart's TLB only, for translations no other hart can
+    /// have seen
+    ///
+    pub const fn local() -> Self {
+        Self::new(FlushScope::Global, 0)
+    }
+
+    pub fn scope(&self) -> FlushScope {
+        self.scope
+    }
+
+    pub fn harts(&self) -> u64 {
+        self.harts
+    }
+
+    ///
+    /// Add the `pages` pages starting at `virt_addr` to the batch
+    ///
+    pub fn add(&mut self, virt_addr: usize, pages: usize) {
+        if pages == 0 {
+            return;
+        }
+
+        if self.overflowed {
+            self.pages += pages;
+            return;
+        }
+
+        let mut start = virt_addr;
+        let mut end = virt_addr + pages * PAGE_SIZE_B;
+
+        // Fold every range the new one touches into it
+        let mut index = 0;
+
+        while index < self.len {
+            let (range_start, range_end) = self.ranges[index];
+
+            if range_start <= end && start <= range_end {
+                start = start.min(range_start);
+                end = end.max(range_end);
+
+                self.pages -= (range_end - range_start) / PAGE_SIZE_B;
+                self.len -= 1;
+                self.ranges[index] = self.ranges[self.len];
+            } else {
+                index += 1;
+            }
+        }
+
+        self.pages += (end - start) / PAGE_SIZE_B;
+
+        if self.len == MAX_RANGES {
+            self.overflowed = true;
+            self.len = 0;
+        } else {
+            self.ranges[self.len] = (start, end);
+            self.len += 1;
+        }
+    }
+
+    ///
+    /// Hold on to the page table at `phys_addr`, which no entry points at
+    /// anymore, until the batch is flushed
+    ///
+    /// The whole address space is flushed then, fences of single pages need
+    /// not drop the entries cached from the table
+    ///
+    pub fn add_table(&mut self, phys_addr: usize) {
+        self.tables.push(phys_addr);
+    }
+
+    ///
+    /// The page tables held until the flush
+    ///
+    pub fn tables(&self) -> &[usize] {
+        &self.tables
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages == 0 && self.tables.is_empty()
+    }
+
+    ///
+    /// Number of pages the flush covers, the gaps between the ranges left out
+    ///
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    ///
+    /// The ranges the flush covers, none when it covers the whole address space
+    ///
+    pub fn ranges(&self) -> &[(usize, usize)] {
+        if self.flushes_all() {
+            &[]
+        } else {
+            &self.ranges[..self.len]
+        }
+    }
+
+    ///
+    /// Whether the flush covers the whole address space rather than ranges
+    ///
+    pub fn flushes_all(&self) -> bool {
+        self.overflowed || self.pages > FLUSH_ALL_THRESHOLD || !self.tables.is_empty()
+    }
+
+    ///
+    /// Flush the batch from this hart's TLB and every other hart's, then
+    /// start over with an empty one
+    ///
+    /// The other harts are fenced through the SBI, which also covers this
+    /// hart if it is among them
+    ///
+    pub fn flush(&mut self) {
+        assert!(
+            self.tables.is_empty(),
+            "TlbFlush: page tables are held, use flush_freeing"
+        );
+
+        self.fence();
+    }
+
+    ///
+    /// Flush the batch like `flush`, then free the page tables it held to
+    /// `frame_table`
+    ///
+    pub fn flush_freeing(&mut self, frame_table: &mut FrameTable) {
+        self.fence();
+
+        for table in self.tables.drain(..) {
+            frame_table
+                .free_addr(table, 1, 0)
+                .expect("TlbFlush: failed to free a page table");
+        }
+    }
+
+    fn fence(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+
+        if self.flushes_all() {
+            match self.scope {
+                FlushScope::Global => crate::page_table::sfence_vma_all(),
+                FlushScope::Asid(asid) => crate::page_table::sfence_vma_asid(asid),
+            }
+        } else {
+            for &(start, end) in self.ranges() {
+                for page in (start..end).step_by(PAGE_SIZE_B) {
+                    crate::page_table::sfence_vma(page);
+                }
+            }
+        }
+
+        if self.harts != 0 {
+            self.shootdown();
+        }
+
+        self.len = 0;
+        self.pages = 0;
+        self.overflowed = false;
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn shootdown(&self) {
+        let mask = (0..64)
+            .filter(|hart| self.harts & (1 << hart) != 0)
+            .fold(sbi::HartMask::new(0), |mask, hart| mask.with(hart));
+
+        // A size of all ones flushes everything
+        let everything = [(0, usize::MAX)];
+        let ranges = if self.flushes_all() {
+            &everything[..]
+        } else {
+            self.ranges()
+        };
+
+        for &(start, end) in ranges {
+            let size = end - start;
+
+            let result = match self.scope {
+                FlushScope::Global => sbi::rfence::remote_sfence_vma(mask, start, size),
+                FlushScope::Asid(asid) => {
+                    sbi::rfence::remote_sfence_vma_asid(mask, start, size, asid as usize)
+                }
+            };
+
+            if let Err(error) = result {
+                panic!(
+                    "TLB shootdown of {start:#X}..{end:#X} on harts {:#b} failed: {error:?}",
+                    self.harts
+                );
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "riscv64"))]
+    fn shootdown(&self) {}
+}
+
+impl Drop for TlbFlush {
+    fn drop(&mut self) {
+        // Don't turn a failing test into an abort
+        #[cfg(test)]
+        if std::thread::panicking() {
+            return;
+        }
+
+        debug_assert!(
+            self.is_empty(),
+            "TlbFlush of {} pages and {} tables dropped without being flushed",
+            self.pages,
+            self.tables.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_table::FrameState;
+    use crate::testing::Arena;
+
+    #[test]
+    fn batches_grow_into_full_flushes() {
+        let harts = HartSet::new();
+        harts.insert(0);
+        harts.insert(3);
+        assert!(harts.contains(3) && !harts.contains(1));
+        harts.remove(0);
+        assert_eq!(harts.bits(), 0b1000);
+
+        let mut tlb = TlbFlush::new(FlushScope::Asid(5), harts.bits());
+        assert!(tlb.is_empty());
+
+        // Pages far apart are kept in ranges of their own, without the gap
+        tlb.add(0x4000, 2);
+        tlb.add(0x1000, 1);
+        assert_eq!(tlb.ranges(), [(0x4000, 0x6000), (0x1000, 0x2000)]);
+        assert_eq!(tlb.pages(), 3);
+        assert!(!tlb.flushes_all());
+
+        // Ranges touching or overlapping are merged
+        tlb.add(0x2000, 3);
+        assert_eq!(tlb.ranges(), [(0x1000, 0x6000)]);
+        assert_eq!(tlb.pages(), 5);
+
+        tlb.add(0x1000, FLUSH_ALL_THRESHOLD + 1);
+        assert!(tlb.flushes_all());
+        assert!(tlb.ranges().is_empty());
+
+        tlb.flush();
+        assert!(tlb.is_empty());
+        assert_eq!(tlb.scope(), FlushScope::Asid(5));
+        assert_eq!(tlb.harts(), 0b1000);
+
+        // Too many ranges flush everything, however few pages they hold
+        for range in 0..MAX_RANGES {
+            tlb.add(range * 0x10_0000, 1);
+        }
+        assert!(!tlb.flushes_all());
+        tlb.add(0x1000_0000, 1);
+        assert!(tlb.flushes_all());
+        assert_eq!(tlb.pages(), MAX_RANGES + 1);
+
+        tlb.flush();
+    }
+
+    #[test]
+    fn page_tables_are_freed_after_a_full_flush() {
+        let arena = Arena::new(16);
+        let mut ft = arena.frame_table();
+
+        let table = ft.alloc_front(1, FrameState::PageTable, 0).unwrap();
+        let mut tlb = TlbFlush::local();
+        tlb.add(0x1000, 1);
+        tlb.add_table(table.phys_addr);
+
+        // However few pages changed, the cached walks go too
+        assert!(tlb.flushes_all());
+        assert_eq!(tlb.tables(), [table.phys_addr]);
+
+        tlb.flush_freeing(&mut ft);
+        assert!(tlb.is_empty());
+        assert_eq!(ft.count_frames(0, FrameState::PageTable), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "use flush_freeing")]
+    fn page_tables_need_to_be_freed() {
+        let mut tlb = TlbFlush::local();
+        tlb.add_table(0x8000_0000);
+        tlb.flush();
+    }
+}
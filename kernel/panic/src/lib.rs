@@ -2,7 +2,28 @@
 
 
 
+use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use chopin_memory::page_table::{dump, PageTable};
+
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars(){
+            sbi::legacy::console_putchar(c as u8);
+        }
+        Ok(())
+    }
+}
+
+///
+/// Set once a panic started dumping the page table, so a panic while
+/// walking it doesn't try again
+///
+static DUMPING: AtomicBool = AtomicBool::new(false);
 
 
 
@@ -16,13 +37,17 @@ pub fn chopin_panic_handle(info : &PanicInfo) -> !{
         sbi::legacy::console_putchar(c as u8);
     }
 
-    if let Some(message) = info.message().as_str(){
-        for c in message.chars(){
-            sbi::legacy::console_putchar(c as u8);
-        }
-    }
+    // Formatted messages, like the errors stage0 reports, only show up through Display
+    let _ = write!(Console, "{}", info.message());
 
     sbi::legacy::console_putchar(b'\n');
+
+    if let Some(root) = dump::active_root() {
+        if !DUMPING.swap(true, Ordering::Relaxed) {
+            let _ = writeln!(Console, "Active page table at {root:#X}:");
+            let _ = unsafe { PageTable::from_pointer(root) }.dump(&mut Console, true);
+        }
+    }
    
     loop {
 
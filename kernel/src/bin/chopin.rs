@@ -2,12 +2,15 @@
 #![no_main]
 
 use chopin_kernel as _;
-use chopin_kernel_stage0 as _;
+use chopin_kernel_stage0::BootHandoff;
 
 
 
 #[no_mangle]
-extern "C" fn CHOPIN_kern_start() {
+extern "C" fn CHOPIN_kern_start(handoff: &BootHandoff) -> ! {
+
+    // Stage0 is done, nothing may call back into it from here on
+    unsafe { chopin_kernel::boot_memory::reclaim(handoff) };
 
     unsafe {
         // uart_print(c"Hello, World!".as_ptr() as *const _);
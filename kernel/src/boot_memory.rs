@@ -0,0 +1,76 @@
+//!
+//! Reclaiming memory which was only needed to boot
+//!
+
+use chopin_kernel_stage0::{BootHandoff, MemoryRegion};
+use chopin_memory::frame_table::{FrameState, FrameTable};
+use chopin_memory::page_table::virt_map::unmap_shared;
+use chopin_memory::tlb::TlbFlush;
+use chopin_memory::{KERNEL_FRAME_TABLE, KERNEL_PAGE_TABLE};
+
+///
+/// Hand the memory stage0 left behind back to the frame table
+///
+/// The `.init` section and the device tree blob are released, the early heap
+/// is kept: allocations made while booting live on in it, and it stays the
+/// kernel's global allocator
+///
+/// # Safety
+///
+/// Nothing may refer to stage0 code or data, or to the device tree, afterwards
+///
+pub unsafe fn reclaim(handoff: &BootHandoff) {
+    let mut ft = KERNEL_FRAME_TABLE.lock();
+
+    unsafe { unmap_init(&mut ft, &handoff.init) };
+
+    let init = release(&mut ft, &handoff.init);
+    let dtb = release(&mut ft, &handoff.device_tree);
+
+    log::info!("Reclaimed {init} frames of stage0 code and data, {dtb} frames of device tree");
+}
+
+///
+/// Take stage0's identity mapping of the `.init` section down, on every hart
+///
+/// Its frames are mapped executable and global, once they are reused they
+/// must not be reachable through it
+///
+unsafe fn unmap_init(ft: &mut FrameTable, init: &MemoryRegion) {
+    // Only the pages the section covers completely are released
+    let Some((start, end)) = whole_pages(init) else {
+        return;
+    };
+
+    let pt = unsafe { (*core::ptr::addr_of_mut!(KERNEL_PAGE_TABLE)).assume_init_mut() };
+    let mut tlb = TlbFlush::kernel();
+
+    unsafe { unmap_shared(pt, ft, start, (end - start) / 4096, &mut tlb) }
+        .expect("Failed to unmap the .init section");
+
+    tlb.flush_freeing(ft);
+}
+
+fn release(ft: &mut FrameTable, region: &MemoryRegion) -> usize {
+    if ft.segment_of(region.addr).is_some() {
+        return ft.release_region(region.addr, region.size, FrameState::Kernel);
+    }
+
+    // Memory outside of every segment is new to the frame table, which only
+    // takes whole pages
+    let Some((start, end)) = whole_pages(region) else {
+        return 0;
+    };
+
+    unsafe { ft.add_segment(start, end - start) }.unwrap_or(0)
+}
+
+///
+/// The range of pages lying completely inside `region`, if there are any
+///
+fn whole_pages(region: &MemoryRegion) -> Option<(usize, usize)> {
+    let start = region.addr.next_multiple_of(4096);
+    let end = (region.addr + region.size) & !(4096 - 1);
+
+    (start < end).then_some((start, end))
+}
@@ -6,4 +6,5 @@ use chopin_kpanic as _;
 
 
 
+pub mod boot_memory;
 pub mod trap;
@@ -1,3 +1,41 @@
+use chopin_memory::page_table::dump::active_root;
+use chopin_memory::page_table::fault::{handle_page_fault, mark_accessed, PageFault};
+use chopin_memory::page_table::PageTable;
+use chopin_memory::tlb::TlbFlush;
+use chopin_memory::{KERNEL_FRAME_TABLE, KERNEL_LAZY_REGIONS};
+
+extern "C" {
+    ///
+    /// Restore the registers saved in `frame` and return from the trap
+    ///
+    fn CHOPIN_kern_trap_return(frame: usize) -> !;
+}
+
+///
+/// Take a lock from a trap, waiting for other harts to let go of it
+///
+/// A trap taken while its own hart holds the lock would spin forever, this
+/// turns it into a panic
+///
+fn lock_from_trap<G>(
+    what: &str,
+    stval: usize,
+    held_here: impl Fn() -> bool,
+    try_lock: impl Fn() -> Option<G>,
+) -> G {
+    loop {
+        if let Some(guard) = try_lock() {
+            return guard;
+        }
+
+        if held_here() {
+            panic!("Page fault at {stval:#X} with the {what} locked, lazily reserved memory was touched while holding it");
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
 
 #[no_mangle]
 extern "C" fn CHOPIN_kern_trap_handle_unimplemented(){
@@ -23,3 +61,65 @@ extern "C" fn CHOPIN_kern_trap_handle_instruction_access_fault(){
 extern "C" fn CHOPIN_kern_trap_handle_ecall(){
 
 }
+
+///
+/// Instruction, load and store page faults
+///
+/// Faults on lazily reserved kernel memory are resolved and the access runs
+/// again, everything else is fatal. Lazily reserved memory must not be
+/// touched while the frame table or the lazy regions are locked, doing so
+/// panics instead of deadlocking
+///
+/// Harts which leave the `A` and `D` bits to software fault on the first
+/// access and the first store to a page, these are resolved before taking
+/// any lock, so they may hit while the frame table is held
+///
+/// `frame` holds the registers saved on entry, the trap returns through it
+///
+#[no_mangle]
+extern "C" fn CHOPIN_kern_trap_handle_page_fault(scause: usize, frame: usize){
+    let stval: usize;
+    unsafe { core::arch::asm!("csrr {}, stval", out(reg) stval) };
+
+    let fault = PageFault::from_scause(scause).expect("Page fault handler called for another trap");
+    let root = active_root().expect("Page fault with paging off");
+
+    let mut pt = unsafe { PageTable::from_pointer(root) };
+
+    // Only the `A` and `D` bits are added, other harts holding the entry
+    // without them fault and add them on their own
+    let mut local = TlbFlush::local();
+
+    if unsafe { mark_accessed(&mut pt, stval, fault, &mut local) } {
+        local.flush();
+        unsafe { CHOPIN_kern_trap_return(frame) }
+    }
+
+    // The faulting table may be any address space, so flush everywhere
+    let mut tlb = TlbFlush::kernel();
+
+    let result = {
+        let mut ft = lock_from_trap(
+            "frame table",
+            stval,
+            || KERNEL_FRAME_TABLE.held_by_current_hart(),
+            || KERNEL_FRAME_TABLE.try_lock(),
+        );
+        let regions = lock_from_trap(
+            "lazy regions",
+            stval,
+            || KERNEL_LAZY_REGIONS.held_by_current_hart(),
+            || KERNEL_LAZY_REGIONS.try_lock(),
+        );
+
+        let result = unsafe { handle_page_fault(&mut pt, &mut ft, &regions, stval, fault, &mut tlb) };
+        tlb.flush();
+
+        result
+    };
+
+    match result {
+        Ok(()) => unsafe { CHOPIN_kern_trap_return(frame) },
+        Err(err) => panic!("Unhandled {fault:?} page fault at {stval:#X}: {err:?}"),
+    }
+}
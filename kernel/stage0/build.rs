@@ -8,7 +8,14 @@ use std::path::PathBuf;
 use std::env;
 
 fn main() {
-    cc::Build::new()
+    let mut build = cc::Build::new();
+
+    // Secondary harts only have somewhere to go when stress testing
+    if env::var_os("CARGO_FEATURE_FRAME_STRESS").is_some() {
+        build.define("CHOPIN_FRAME_STRESS", None);
+    }
+
+    build
         .file("src/boot.S")
         .target("riscv64imac-unknown-none-elf")
         .flag("-march=rv64imac_zicsr") // Specify RISC-V ISA
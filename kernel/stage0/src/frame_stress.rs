@@ -0,0 +1,161 @@
+//!
+//! Boot-time stress test of the global frame table
+//!
+//! Built with the `frame-stress` feature, this starts every other hart
+//! through SBI and has all of them allocate and free kernel pages through
+//! their per-hart caches at once. The QEMU runner already passes `-smp 4`,
+//! so `cargo run --features frame-stress` exercises the lock.
+//!
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use chopin_memory::frame_table::{FrameState, MemoryAllocation};
+use chopin_memory::KERNEL_FRAME_TABLE;
+
+use crate::PAGE_SIZE_BYTES;
+
+const ROUNDS: u64 = 20_000;
+const HELD_PAGES: usize = 64;
+const STACK_PAGES: usize = 4;
+
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    fn CHOPIN_kern_stage0_secondary_entry();
+}
+
+///
+/// Run the stress test on the boot hart and every hart in `harts`
+///
+#[link_section = ".init.text"]
+pub fn run(boot_hart: usize, harts: impl Iterator<Item = usize>) {
+    let free_before = free_frames();
+    let mut stacks = alloc::vec::Vec::new();
+
+    for hart in harts.filter(|&hart| hart != boot_hart) {
+        let stack = KERNEL_FRAME_TABLE
+            .lock()
+            .alloc_back(STACK_PAGES, FrameState::Kernel, 0)
+            .expect("frame stress: no memory for a hart stack");
+
+        let stack_top = stack.phys_addr + STACK_PAGES * PAGE_SIZE_BYTES;
+
+        // Count the hart before it can possibly finish
+        STARTED.fetch_add(1, Ordering::AcqRel);
+
+        let started = sbi::hart_state_management::hart_start(
+            hart,
+            CHOPIN_kern_stage0_secondary_entry as *const () as usize,
+            stack_top,
+        );
+
+        match started {
+            Ok(()) => stacks.push(stack),
+            Err(e) => {
+                STARTED.fetch_sub(1, Ordering::AcqRel);
+                log::warn!("frame stress: failed to start hart {hart}: {e:?}");
+                KERNEL_FRAME_TABLE.lock().free(stack).unwrap();
+            }
+        }
+    }
+
+    log::info!(
+        "frame stress: running on {} harts",
+        STARTED.load(Ordering::Acquire) + 1
+    );
+
+    hammer(boot_hart);
+
+    while FINISHED.load(Ordering::Acquire) < STARTED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+
+    for stack in stacks {
+        KERNEL_FRAME_TABLE.lock().free(stack).unwrap();
+    }
+
+    let drained = KERNEL_FRAME_TABLE.drain_all();
+    let free_after = free_frames();
+
+    assert_eq!(free_before, free_after, "frame stress: frames leaked");
+
+    log::info!("frame stress: passed, drained {drained} cached frames");
+}
+
+#[link_section = ".init.text"]
+fn free_frames() -> usize {
+    KERNEL_FRAME_TABLE
+        .lock()
+        .stats()
+        .totals
+        .get(FrameState::Free)
+}
+
+#[no_mangle]
+#[link_section = ".init.text"]
+extern "C" fn CHOPIN_kern_stage0_secondary(hart_id: usize) -> ! {
+    hammer(hart_id);
+
+    FINISHED.fetch_add(1, Ordering::AcqRel);
+
+    let _ = sbi::hart_state_management::hart_stop();
+
+    loop {
+        riscv::asm::wfi();
+    }
+}
+
+///
+/// Allocate and free pages at random, checking nobody else wrote to them
+///
+#[link_section = ".init.text"]
+fn hammer(hart: usize) {
+    // No heap use here, the early allocator is not safe to share between harts
+    let mut held = [(0usize, 0u64); HELD_PAGES];
+    let mut len = 0;
+    let mut rng = 0x9E37_79B9_7F4A_7C15u64 ^ hart as u64;
+
+    let page = |phys_addr| MemoryAllocation {
+        phys_addr,
+        page_count: 1,
+        pid: 0,
+        state: FrameState::Kernel,
+    };
+
+    for round in 0..ROUNDS {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+
+        if len < HELD_PAGES && (rng & 1 == 0 || len == 0) {
+            let allocation = KERNEL_FRAME_TABLE
+                .alloc_page(hart)
+                .expect("frame stress: out of memory");
+            let tag = ((hart as u64) << 32) | round;
+
+            unsafe { allocation.as_slice::<u64>() }.fill(tag);
+            held[len] = (allocation.phys_addr, tag);
+            len += 1;
+        } else {
+            let victim = (rng >> 1) as usize % len;
+            let (phys_addr, tag) = held[victim];
+
+            len -= 1;
+            held[victim] = held[len];
+
+            let allocation = page(phys_addr);
+            let contents = unsafe { allocation.as_slice::<u64>() };
+            assert!(
+                contents.iter().all(|&word| word == tag),
+                "frame stress: page {phys_addr:#X} was handed out twice"
+            );
+
+            KERNEL_FRAME_TABLE.free_page(hart, allocation).unwrap();
+        }
+    }
+
+    for &(phys_addr, _) in &held[..len] {
+        KERNEL_FRAME_TABLE.free_page(hart, page(phys_addr)).unwrap();
+    }
+}
@@ -6,20 +6,27 @@ use alloc::vec::Vec;
 use chopin_kalloc::AllocatorVariant;
 use chopin_kalloc::EarlyKernelAllocator;
 use chopin_kalloc::ALLOCATOR;
+use chopin_memory::frame_table::{FrameMetadataEntry, FrameSegment, FrameState};
+use chopin_memory::page_table::PageTableEntry;
 
 extern crate alloc;
 
+#[cfg(feature = "frame-stress")]
+mod frame_stress;
+
 extern "C" {
     static CHOPIN_kernel_memory_end: u8;
     // static stack_top : usize;
     fn CHOPIN_kern_stage0_kcore_init(hart_id: usize, value: usize) -> !;
 }
 
+#[link_section = ".init.text"]
 fn print(s: &str) {
     for c in s.chars() {
         sbi::legacy::console_putchar(c as u8);
     }
 }
+#[link_section = ".init.text"]
 fn println(s: &str) {
     for c in s.chars() {
         sbi::legacy::console_putchar(c as u8);
@@ -27,12 +34,14 @@ fn println(s: &str) {
     sbi::legacy::console_putchar(b'\n');
 }
 
+#[link_section = ".init.text"]
 fn print_nibble(val: u8) {
     let table = b"0123456789ABCDEF";
     let c = table[val as usize];
     sbi::legacy::console_putchar(c);
 }
 
+#[link_section = ".init.text"]
 fn print_u32(v: u32) {
     print("0x");
     let mut first = true;
@@ -53,6 +62,7 @@ fn print_u32(v: u32) {
     }
 }
 
+#[link_section = ".init.text"]
 fn print_u64(v: u64) {
     print("0x");
     let mut first = true;
@@ -73,9 +83,21 @@ fn print_u64(v: u64) {
 }
 extern "C" {
     static _start: u8;
+    static CHOPIN_text_start: u8;
+    static CHOPIN_text_end: u8;
+    static CHOPIN_init_start: u8;
+    static CHOPIN_init_data_start: u8;
+    static CHOPIN_init_end: u8;
+    static CHOPIN_rodata_start: u8;
+    static CHOPIN_rodata_end: u8;
+    static CHOPIN_data_start: u8;
+    static CHOPIN_stack_start: u8;
+    static CHOPIN_stack_end: u8;
+    fn CHOPIN_kern_start(handoff: &BootHandoff) -> !;
 }
 
 #[no_mangle]
+#[link_section = ".init.text"]
 extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
     let start_address = unsafe { &_start as *const u8 as usize as u64 };
     let end_address = unsafe { &CHOPIN_kernel_memory_end as *const u8 as usize as u64 };
@@ -93,12 +115,16 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
         size: end_address as usize - start_address as usize,
     };
 
+    // The second u32 in the header contains the total size
+    let dtb_region = MemoryRegion {
+        addr: device_tree as usize,
+        size: u32::from_be_bytes(unsafe { *(device_tree.add(4) as *const [u8; 4]) }) as usize,
+    };
+
     print("Init Running on HART");
     print_u32(hart_id);
     println("");
 
-    // The second u32 in the header contains the total size
-
     let device_tree =
         unsafe { hermit_dtb::Dtb::from_raw(device_tree) }.expect("Failed to load DTB");
 
@@ -176,18 +202,15 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
     print_u64(heap_start_address);
     println("");
 
-    let early_alloc = unsafe {
-        EarlyKernelAllocator::new(
-            heap_start_address as usize + 32,
-            heap_start_address as usize + 32 + 64_000,
-        )
-    }; // 64K HEAP
-
     let early_heap_region = MemoryRegion {
         addr: heap_start_address as usize + 32,
         size: 64_000,
     };
 
+    let early_alloc = unsafe {
+        EarlyKernelAllocator::new(early_heap_region.addr, early_heap_region.end())
+    }; // 64K HEAP
+
     unsafe {
         ALLOCATOR.allocator = AllocatorVariant::Early(early_alloc);
     }
@@ -229,6 +252,7 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
         hart_id: u32,
         status: &'a str,
         mmu: Option<&'a str>,
+        extensions: chopin_memory::page_table::extensions::Extensions,
     }
 
     let mut harts = Vec::with_capacity(10);
@@ -244,6 +268,18 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
             .get_property(&cpu_path, "mmu-type")
             .map(|t| core::str::from_utf8(t).unwrap_or("?"));
 
+        // Newer device trees list extensions one by one, older ones only
+        // have the ISA string
+        use chopin_memory::page_table::extensions::Extensions;
+        let extensions = match device_tree.get_property(&cpu_path, "riscv,isa-extensions") {
+            Some(list) => Extensions::from_isa_extensions(list),
+            None => device_tree
+                .get_property(&cpu_path, "riscv,isa")
+                .and_then(|isa| core::str::from_utf8(isa).ok())
+                .map(Extensions::from_isa)
+                .unwrap_or_default(),
+        };
+
         let status_str = core::str::from_utf8(status).unwrap();
 
         let hart_number = u32::from_be_bytes(reg.try_into().unwrap());
@@ -252,6 +288,7 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
             hart_id: hart_number,
             status: status_str,
             mmu: mmu_type,
+            extensions,
         });
         // log::info!("Pushed");
     });
@@ -388,20 +425,27 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
         }
     });
 
+    let mut reservations = Vec::new();
+
     device_tree
         .enum_subnodes("/reserved-memory")
         .for_each(|sn| {
             let path = format!("/reserved-memory/{sn}");
-            log::info!(">: {sn}");
+            let no_map = device_tree.get_property(&path, "no-map").is_some();
+            log::info!(">: {sn} (no-map: {no_map})");
             if let Some(reg) = device_tree.get_property(&path, "reg") {
                 let addrs = root_cellsize.interpret_reg(reg);
                 // log::info!(">: Got mem addrs");
                 for addr in addrs.iter() {
                     // log::info!(">: :: At {:#X} [{:#X} bytes]", addr.0, addr.1);
 
-                    mmap.cut_region(MemoryRegion {
-                        addr: addr.0 as usize,
-                        size: addr.1 as usize,
+                    reservations.push(ReservedRegion {
+                        region: MemoryRegion {
+                            addr: addr.0 as usize,
+                            size: addr.1 as usize,
+                        },
+                        state: FrameState::Reserved,
+                        no_map,
                     });
                 }
             }
@@ -411,9 +455,14 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
             // });
         });
 
-    // Save the kernel and heap
-    mmap.cut_region(kernel_region);
-    mmap.cut_region(early_heap_region);
+    // Save the kernel, heap and device tree
+    for region in [kernel_region, early_heap_region.clone(), dtb_region.clone()] {
+        reservations.push(ReservedRegion {
+            region,
+            state: FrameState::Kernel,
+            no_map: false,
+        });
+    }
 
     // device_tree.enum_subnodes("/").for_each(|sn| {
     //     log::info!("SN: {sn}");
@@ -450,23 +499,103 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
     };
 
     for region in mmap.regions {
-        let segment =
-            chopin_memory::frame_table::FrameSegment::initialize(region.addr, region.size);
+        let first_page_addr = align_up(region.addr, PAGE_SIZE_BYTES);
+        let end_page_addr = region.end() & !(PAGE_SIZE_BYTES - 1);
 
-        match segment {
-            Some(s) => {
-                log::info!("Initialized frame segment at {region:?}");
-                ft.segments.push(s);
+        if end_page_addr <= first_page_addr {
+            log::warn!("Memory region too small to create segment: {region:?}");
+            continue;
+        }
+
+        let page_count = (end_page_addr - first_page_addr) / PAGE_SIZE_BYTES;
+
+        // The segment covers the whole memory device, its bookkeeping goes
+        // wherever nothing has been reserved
+        let mut unreserved = MemoryMap {
+            regions: alloc::vec![region.clone()],
+        };
+
+        for reservation in &reservations {
+            unreserved.cut_region(reservation.region.clone());
+        }
+
+        let bookkeeping = unreserved
+            .bite_last_aligned(FrameSegment::bookkeeping_size(page_count), PAGE_SIZE_BYTES);
+
+        match bookkeeping {
+            Some(bookkeeping) => {
+                let segment = unsafe {
+                    FrameSegment::initialize_at(first_page_addr, page_count, bookkeeping.addr)
+                };
+
+                log::info!(
+                    "Initialized frame segment at {region:?}, bookkeeping at {bookkeeping:?}"
+                );
+                ft.segments.push(segment);
             }
             None => {
-                log::warn!("Memory region too small to create segment: {region:?}");
+                log::warn!("No room for frame segment bookkeeping in {region:?}");
             }
         }
-        // ft.segmens.push(segment);
+    }
+
+    for reservation in &reservations {
+        let flags = if reservation.no_map {
+            FrameMetadataEntry::FLAG_NO_MAP
+        } else {
+            0
+        };
+
+        let count = ft.reserve_region(
+            reservation.region.addr,
+            reservation.region.size,
+            reservation.state,
+            flags,
+        );
+
+        log::info!("Reserved {count} frames for {reservation:?}");
     }
 
     log::info!("Constructed frame table");
 
+    chopin_memory::KERNEL_FRAME_TABLE.init(ft);
+
+    #[cfg(feature = "frame-stress")]
+    frame_stress::run(
+        hart_id as usize,
+        harts
+            .iter()
+            .filter(|hart| hart.status.starts_with("okay") && hart.mmu.is_some())
+            .map(|hart| hart.hart_id as usize),
+    );
+
+    // Only the boot hart runs from here on, it keeps the table for the rest of stage0
+    let mut ft = chopin_memory::KERNEL_FRAME_TABLE.lock();
+
+    // The page table layout depends on the mode, so pick it before building one
+    let mmu_type = harts
+        .iter()
+        .find(|hart| hart.hart_id == hart_id)
+        .and_then(|hart| hart.mmu);
+    let paging_mode = select_paging_mode(&mut ft, mmu_type);
+
+    log::info!("Paging mode: {paging_mode:?} (device tree: {mmu_type:?})");
+
+    unsafe { chopin_memory::page_table::set_paging_mode(paging_mode) };
+
+    // Every hart shares the kernel's leaves, so only what all of them
+    // implement can be used in them
+    let extensions = harts
+        .iter()
+        .filter(|hart| hart.status.starts_with("okay") && hart.mmu.is_some())
+        .map(|hart| hart.extensions)
+        .reduce(|all, extensions| all.intersection(extensions))
+        .unwrap_or_default();
+
+    log::info!("Page table extensions: {extensions:?}");
+
+    unsafe { chopin_memory::page_table::extensions::set_extensions(extensions) };
+
     let root_page_table = ft
         .alloc_front(1, chopin_memory::frame_table::FrameState::PageTable, 0)
         .unwrap();
@@ -478,34 +607,130 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
     let entries =
         unsafe { root_page_table.as_slice::<chopin_memory::page_table::PageTableEntry>() };
 
+    // Page tables are reached through the physmap, so the kernel table
+    // goes without the self-ref section
     let mut pt = chopin_memory::page_table::PageTable{
         entries
     };
 
 
-    unsafe { chopin_memory::page_table::bootstrap_pt(&mut pt, &mut ft) };
+    // Identity map the kernel image section by section, so that code is
+    // never writable and data is never executable, along with the EKH
+    // (early kernel heap). The kernel is mapped in every address space.
+    // Its pages are accessed and dirty from the start, the trap handler
+    // can't take a fault on its own code and stack
+    let heap_end = early_heap_region.end();
+    let kernel_sections = unsafe {
+        [
+            ("text", &CHOPIN_text_start as *const u8 as usize, &CHOPIN_text_end as *const u8 as usize, PageTableEntry::FLAG_R | PageTableEntry::FLAG_X),
+            ("init text", &CHOPIN_init_start as *const u8 as usize, &CHOPIN_init_data_start as *const u8 as usize, PageTableEntry::FLAG_R | PageTableEntry::FLAG_X),
+            ("init data", &CHOPIN_init_data_start as *const u8 as usize, &CHOPIN_init_end as *const u8 as usize, PageTableEntry::FLAG_R | PageTableEntry::FLAG_W),
+            ("rodata", &CHOPIN_rodata_start as *const u8 as usize, &CHOPIN_rodata_end as *const u8 as usize, PageTableEntry::FLAG_R),
+            // .data, .bss, the stack and the heap follow each other
+            ("data", &CHOPIN_data_start as *const u8 as usize, align_up(heap_end, 4096), PageTableEntry::FLAG_R | PageTableEntry::FLAG_W),
+        ]
+    };
 
+    let sp: usize;
+    unsafe {
+        core::arch::asm!("mv {}, sp", out(reg) sp);
+    }
 
-    // Identity map kernel memory into virtual memory 
-    // also identity map the EKH (early kernel heap)
+    log::info!("Stack PTR: {sp:#X} ({:#X} to {:#X})", unsafe { &CHOPIN_stack_start as *const u8 as usize }, unsafe { &CHOPIN_stack_end as *const u8 as usize });
 
+    for (name, start, end, permissions) in kernel_sections {
+        if start == end {
+            continue;
+        }
 
-    let heap_end = heap_start_address + 64_000;
-    let idmap_start = start_address as usize;
-    let idmap_end = align_up(heap_end as usize, 4096);
-    let idmap_page_count = idmap_end - idmap_start >> 12;
+        log::info!("Identity mapping {name}: {start:#X} to {end:#X} ({} pages)", (end - start) >> 12);
+        let mapped = unsafe{chopin_memory::page_table::virt_map::virtual_map_linear_with_flags(&mut pt, &mut ft, chopin_memory::page_table::virt_map::PageRegion{
+            address: start,
+            count: (end - start) >> 12
+        }, start, PageTableEntry::FLAG_V | PageTableEntry::FLAG_G | PageTableEntry::FLAG_A | PageTableEntry::FLAG_D | permissions)};
 
-    log::info!("Identity mapping: {:#X?} to {:#X} ({} pages)", start_address, idmap_end, idmap_page_count);
-    let sp: usize;
-    unsafe {
-        core::arch::asm!("mv {}, sp", out(reg) sp);
+        if let Err(err) = mapped {
+            panic!("Failed to identity map kernel {name}: {err:?}");
+        }
     }
 
-    log::info!("Stack PTR: {sp:#X}");
-    unsafe{chopin_memory::page_table::virt_map::virtual_map_linear(&mut pt, &mut ft, chopin_memory::page_table::virt_map::PageRegion{
-        address: idmap_start,
-        count: idmap_page_count
-    }, idmap_start)};
+    // Frames and page tables are reached through the physmap once paging is
+    // on, it covers every segment along with its bookkeeping, and the device
+    // tree which the kernel proper may add as a segment later. Memory the
+    // device tree marks no-map stays out of it
+    let mut physical = MemoryMap {
+        regions: ft
+            .segments
+            .iter()
+            .map(|segment| {
+                let start = segment.frame_metadata_start_addr.min(segment.first_page_addr);
+                let end = segment.first_page_addr + segment.page_count * PAGE_SIZE_BYTES;
+
+                MemoryRegion {
+                    addr: start,
+                    size: end - start,
+                }
+            })
+            .collect(),
+    };
+
+    if ft.segment_of(dtb_region.addr).is_none() {
+        physical.regions.push(dtb_region.clone());
+    }
+
+    for reservation in reservations.iter().filter(|reservation| reservation.no_map) {
+        physical.cut_region(reservation.region.clone());
+    }
+
+    // Kernel code and read-only data stay out too, a writable alias would
+    // undo mapping them read-only. The init sections are left in, their
+    // frames are handed back to the frame table once the kernel is up
+    let read_only = unsafe {
+        [
+            (&CHOPIN_text_start as *const u8 as usize, &CHOPIN_text_end as *const u8 as usize),
+            (&CHOPIN_rodata_start as *const u8 as usize, &CHOPIN_rodata_end as *const u8 as usize),
+        ]
+    };
+
+    for (start, end) in read_only {
+        physical.cut_region(MemoryRegion {
+            addr: start,
+            size: end - start,
+        });
+    }
+
+    for region in physical.regions {
+        let mapped = unsafe {
+            chopin_memory::page_table::physmap::map_physical(&mut pt, &mut ft, region.addr, region.size)
+        };
+
+        if let Err(err) = mapped {
+            panic!("Failed to map {:#X} ({:#X} bytes) into the physmap: {err:?}", region.addr, region.size);
+        }
+    }
+
+    log::info!(
+        "Physmap at {:#X}",
+        chopin_memory::page_table::physmap::physmap_base()
+    );
+
+    // The vmalloc root entry has to exist before any address space copies
+    // the kernel's root entries
+    if let Err(err) = unsafe { chopin_memory::KERNEL_VMALLOC.lock().init(&mut pt, &mut ft) } {
+        panic!("Failed to set up the vmalloc region: {err:?}");
+    }
+
+    log::info!(
+        "Vmalloc region at {:#X} to {:#X}",
+        chopin_memory::page_table::vmalloc::vmalloc_start(),
+        chopin_memory::page_table::vmalloc::vmalloc_end()
+    );
+
+    // Everything the hart will translate with once satp is written
+    log::info!("Kernel page table at {:#X}:", root_page_table.phys_addr);
+    let usage = pt.for_each_range(|range| log::info!("  {range}"));
+    log::info!("  {usage}");
+
     let satp_value = construct_satp(pt.entries.as_ptr() as usize);
 
     unsafe {
@@ -515,21 +740,166 @@ extern "C" fn CHOPIN_kern_stage0(hart_id: u32, device_tree: *const u8) -> ! {
         log::info!("Set SATP");
         core::arch::asm!("fence.i");
     }
-   
+
+    // RAM past the kernel image is only mapped in the physmap from here on
+    unsafe {
+        chopin_memory::phys::set_phys_offset(chopin_memory::page_table::physmap::physmap_base())
+    };
+
+    // This hart caches kernel translations from here on, and takes part in
+    // every TLB shootdown
+    chopin_memory::KERNEL_HARTS.insert(hart_id as usize);
+
+    let asid_bits = unsafe { probe_asid_bits(satp_value) };
+    chopin_memory::KERNEL_ASIDS.set_bits(asid_bits);
+
+    log::info!("ASID bits: {asid_bits}");
+
+    // Address spaces made by the kernel proper share its entries
+    let pt = unsafe { chopin_memory::page_table::PageTable::from_pointer(root_page_table.phys_addr) };
+    unsafe { chopin_memory::KERNEL_PAGE_TABLE = core::mem::MaybeUninit::new(pt) };
+
+    ft.stats().log();
 
     log::info!("Finished INIT");
-    loop {}
+
+    let handoff = BootHandoff {
+        init: MemoryRegion {
+            addr: unsafe { &CHOPIN_init_start as *const u8 as usize },
+            size: unsafe {
+                &CHOPIN_init_end as *const u8 as usize - &CHOPIN_init_start as *const u8 as usize
+            },
+        },
+        device_tree: dtb_region,
+    };
+
+    drop(ft);
 
     // Allocate stack space for every hart we have
+
+    unsafe { CHOPIN_kern_start(&handoff) }
 }
 
+///
+/// Boot-time memory stage0 hands over to the kernel proper
+///
+/// All of it is still tracked as `Kernel` frames, the kernel releases
+/// each region once nothing refers to it anymore. The early heap is not
+/// handed over, it backs the kernel allocator for good
+///
+#[repr(C)]
+pub struct BootHandoff {
+    ///
+    /// The `.init` section, holding stage0 only code and data
+    ///
+    pub init: MemoryRegion,
+
+    ///
+    /// The flattened device tree blob passed in by the firmware
+    ///
+    pub device_tree: MemoryRegion,
+}
+
+#[link_section = ".init.text"]
 pub fn construct_satp(root_page_table_phys_addr: usize) -> usize {
-    const MODE_SV39: usize = 8;
-    const ASID: usize = 0;
+    const ASID: u16 = 0;
+
+    chopin_memory::page_table::paging_mode().satp(root_page_table_phys_addr, ASID)
+}
+
+///
+/// Pick the deepest paging mode the boot hart supports
+///
+/// The device tree `mmu-type` names the deepest mode, every candidate is
+/// still tried by writing it to `satp`, which ignores modes the hart does
+/// not implement. Falls back to Sv39
+///
+#[link_section = ".init.text"]
+fn select_paging_mode(
+    ft: &mut chopin_memory::frame_table::FrameTable,
+    mmu_type: Option<&str>,
+) -> chopin_memory::page_table::PagingMode {
+    use chopin_memory::page_table::{PageTableEntry, PagingMode};
+
+    let deepest = mmu_type
+        .and_then(PagingMode::from_mmu_type)
+        .unwrap_or(PagingMode::Sv57);
+
+    let probe = ft
+        .alloc_back(1, FrameState::PageTable, 0)
+        .expect("Failed to allocate the paging mode probe table");
+    let entries = unsafe { probe.as_slice::<PageTableEntry>() };
+
+    // Paging is on for a few instructions, which are identity mapped by a
+    // single leaf in the root table
+    let here = probe_satp as *const () as usize;
+    let flags = PageTableEntry::FLAG_V
+        | PageTableEntry::FLAG_R
+        | PageTableEntry::FLAG_W
+        | PageTableEntry::FLAG_X
+        | PageTableEntry::FLAG_A
+        | PageTableEntry::FLAG_D;
+
+    let mode = PagingMode::ALL
+        .into_iter()
+        .filter(|mode| mode.levels() <= deepest.levels())
+        .find(|mode| {
+            let size = mode.root_size();
+
+            entries.fill(PageTableEntry(0));
+            entries[(here >> size.shift()) & 0x1FF].set((here & !(size.bytes() - 1)) as u64, flags);
+
+            unsafe { probe_satp(mode.satp(probe.phys_addr, 0)) }
+        })
+        .unwrap_or(PagingMode::Sv39);
+
+    ft.free(probe).expect("Failed to free the paging mode probe table");
+
+    mode
+}
+
+///
+/// Count the ASID bits of `satp` which can be set, while running with `satp_value`
+///
+#[link_section = ".init.text"]
+unsafe fn probe_asid_bits(satp_value: usize) -> u32 {
+    let read: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "csrw satp, {probe}",
+            "csrr {read}, satp",
+            "csrw satp, {satp}",
+            "sfence.vma zero, zero",
+            probe = in(reg) satp_value | (0xFFFF << 44),
+            satp = in(reg) satp_value,
+            read = out(reg) read,
+        );
+    }
+
+    ((read >> 44) & 0xFFFF).count_ones()
+}
+
+///
+/// Write `value` to `satp` and read it back, turning paging off again
+///
+#[link_section = ".init.text"]
+unsafe fn probe_satp(value: usize) -> bool {
+    let read: usize;
 
-    let ppn = root_page_table_phys_addr >> 12; // divide by 4K
+    unsafe {
+        core::arch::asm!(
+            "csrw satp, {value}",
+            "sfence.vma zero, zero",
+            "csrr {read}, satp",
+            "csrw satp, zero",
+            "sfence.vma zero, zero",
+            value = in(reg) value,
+            read = out(reg) read,
+        );
+    }
 
-    (MODE_SV39 << 60) | (ASID << 44) | ppn
+    read == value
 }
 #[inline(always)]
 pub unsafe fn set_satp(value: usize) {
@@ -541,6 +911,7 @@ pub struct CompatibleEntry<'a> {
 }
 
 impl<'a> CompatibleEntry<'a> {
+    #[link_section = ".init.text"]
     pub fn parse_many(value: &'a str) -> Vec<Self> {
         let mut rem = value;
 
@@ -567,6 +938,7 @@ impl<'a> CompatibleEntry<'a> {
     /// this will consume an entry and return the rest of the stream, if
     /// more than one are detected
     ///
+    #[link_section = ".init.text"]
     pub fn parse(value: &'a str) -> (Self, Option<&'a str>) {
         if let Some(null_index) = value.find('\0') {
             let (relevant, remainder) = if null_index == value.len() - 1 {
@@ -611,6 +983,7 @@ pub struct DTBAddressConfig {
 }
 
 impl DTBAddressConfig {
+    #[link_section = ".init.text"]
     pub fn interpret_reg(&self, data: &[u8]) -> Vec<(u128, u128)> {
         let split_size = (self.address_cells as usize + self.size_cells as usize) * 4;
 
@@ -627,6 +1000,7 @@ impl DTBAddressConfig {
 ///
 /// Big-Endian slice to u128
 ///
+#[link_section = ".init.text"]
 fn numbify(slice: &[u8]) -> u128 {
     let mut v = 0u128;
 
@@ -639,6 +1013,7 @@ fn numbify(slice: &[u8]) -> u128 {
 }
 
 #[derive(Clone)]
+#[repr(C)]
 pub struct MemoryRegion {
     pub addr: usize,
     pub size: usize,
@@ -695,6 +1070,24 @@ impl MemoryRegion {
     }
 }
 
+///
+/// A region of memory which must not be handed out as free frames
+///
+#[derive(Debug, Clone)]
+pub struct ReservedRegion {
+    pub region: MemoryRegion,
+
+    ///
+    /// How the frames are tracked in the frame table
+    ///
+    pub state: FrameState,
+
+    ///
+    /// The region must never be mapped
+    ///
+    pub no_map: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct MemoryMap {
     /// Memory Regions — non-overlapping, sorted by start address
@@ -702,6 +1095,7 @@ pub struct MemoryMap {
 }
 
 impl MemoryMap {
+    #[link_section = ".init.text"]
     pub fn add_region(&mut self, mut r: MemoryRegion) {
         if self.regions.is_empty() {
             self.regions.push(r);
@@ -743,6 +1137,7 @@ impl MemoryMap {
     ///
     /// Bite out the first memory region of that size
     ///
+    #[link_section = ".init.text"]
     pub fn bite_first(&mut self, size_bytes: usize) -> Option<MemoryRegion> {
         for r in &mut self.regions {
             if r.size >= size_bytes {
@@ -765,6 +1160,7 @@ impl MemoryMap {
     ///
     /// Bite out the first memory region of the given size and alignment
     ///
+    #[link_section = ".init.text"]
     pub fn bite_first_aligned(&mut self, size_bytes: usize, align: usize) -> Option<MemoryRegion> {
         for i in 0..self.regions.len() {
             let region = &mut self.regions[i];
@@ -797,6 +1193,41 @@ impl MemoryMap {
         None
     }
 
+    ///
+    /// Bite out the last memory region of the given size and alignment
+    ///
+    #[link_section = ".init.text"]
+    pub fn bite_last_aligned(&mut self, size_bytes: usize, align: usize) -> Option<MemoryRegion> {
+        for i in (0..self.regions.len()).rev() {
+            let region = &mut self.regions[i];
+
+            if region.end() < size_bytes {
+                continue;
+            }
+
+            // Find the highest aligned start which still fits in this region
+            let aligned_start = (region.end() - size_bytes) & !(align - 1);
+
+            if aligned_start >= region.addr {
+                // Everything from the aligned start onwards is taken
+                region.size = aligned_start - region.addr;
+
+                // If we took all of it, remove the region
+                if region.size == 0 {
+                    self.regions.remove(i);
+                }
+
+                return Some(MemoryRegion {
+                    addr: aligned_start,
+                    size: size_bytes,
+                });
+            }
+        }
+
+        None
+    }
+
+    #[link_section = ".init.text"]
     pub fn cut_region(&mut self, cut: MemoryRegion) {
         let mut new_regions = Vec::new();
 